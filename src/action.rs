@@ -0,0 +1,425 @@
+use std::{fmt, iter::Peekable, str::CharIndices};
+
+/// An action as written by the model or advertised by a tool, e.g. `tweet[earthquake in Delhi]`,
+/// `summary(topic)` or `question(query="capital of India", topic=India)`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Action {
+    pub name: String,
+    pub args: Vec<Argument>,
+    /// Everything between the opening and the closing delimiter, trimmed.
+    pub raw_args: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Argument {
+    pub name: Option<String>,
+    pub value: String,
+    pub quoted: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, thiserror::Error)]
+#[error("{kind} at position {position}")]
+pub struct ActionParseError {
+    pub position: usize,
+    pub kind: ActionParseErrorKind,
+}
+
+#[derive(Debug, Clone, PartialEq, thiserror::Error)]
+pub enum ActionParseErrorKind {
+    #[error("expected an action name")]
+    ExpectedName,
+    #[error("expected '[' or '(' after the action name")]
+    ExpectedArguments,
+    #[error("unclosed '{0}'")]
+    Unclosed(char),
+    #[error("expected '{expected}' but found '{found}'")]
+    MismatchedDelimiter { expected: char, found: char },
+    #[error("unterminated string")]
+    UnterminatedString,
+    #[error("unexpected '{0}' after quoted argument")]
+    UnexpectedAfterString(char),
+    #[error("unexpected trailing characters")]
+    TrailingCharacters,
+    #[error("empty argument")]
+    EmptyArgument,
+    #[error("duplicate parameter {0}")]
+    DuplicateParameter(String),
+    #[error("parameters cannot be quoted")]
+    QuotedParameter,
+}
+
+/// The signature of a command advertised by a tool, e.g. `tweet(topic)`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CommandSignature {
+    pub name: String,
+    pub params: Vec<String>,
+}
+
+impl Action {
+    /// Parses a single action. Both `name[args]` and `name(args)` are accepted, arguments are
+    /// separated by top level commas and can be quoted or named (`name=value`). Brackets nested
+    /// inside an argument are kept as part of it, and closing brackets that were never opened are
+    /// kept as text, e.g. in `tweet[smile :)]`.
+    pub fn parse(text: &str) -> Result<Action, ActionParseError> {
+        Self::parse_with(text, |_| None)
+    }
+
+    /// Like [`Action::parse`], but `params` returns the parameters declared for the action's name,
+    /// if they are known. `name=` then only starts a named argument if `name` is one of them, so
+    /// that free text like `calculate[x = 2+3]` is kept as it is.
+    pub fn parse_with(text: &str, params: impl FnOnce(&str) -> Option<Vec<String>>) -> Result<Action, ActionParseError> {
+        Parser::new(text).parse(params)
+    }
+
+    /// Returns the first positional argument, or the whole argument list if the action was not
+    /// written with separate arguments.
+    pub fn first_arg(&self) -> &str {
+        match self.args.first() {
+            Some(arg) if self.args.len() == 1 => &arg.value,
+            _ => &self.raw_args,
+        }
+    }
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}[{}]", self.name, self.raw_args)
+    }
+}
+
+impl CommandSignature {
+    /// Parses and validates a command signature such as `calculate[mathematical expression]`.
+    /// Parameters must be unquoted, unnamed and unique.
+    pub fn parse(text: &str) -> Result<CommandSignature, ActionParseError> {
+        let mut parser = Parser::new(text);
+        let action = parser.parse(|_| None)?;
+
+        let mut params: Vec<String> = Vec::new();
+        for (arg, &position) in action.args.into_iter().zip(&parser.arg_positions) {
+            let error = |kind| ActionParseError { position, kind };
+            if arg.quoted || arg.name.is_some() {
+                return Err(error(ActionParseErrorKind::QuotedParameter));
+            }
+            if params.contains(&arg.value) {
+                return Err(error(ActionParseErrorKind::DuplicateParameter(arg.value)));
+            }
+            params.push(arg.value);
+        }
+
+        Ok(CommandSignature { name: action.name, params })
+    }
+}
+
+struct Parser<'a> {
+    text: &'a str,
+    chars: Peekable<CharIndices<'a>>,
+    /// The parameters declared for the action, if known. See [`Action::parse_with`].
+    params: Option<Vec<String>>,
+    /// Where each parsed argument starts.
+    arg_positions: Vec<usize>,
+}
+
+impl<'a> Parser<'a> {
+    fn new(text: &'a str) -> Self {
+        Parser {
+            text,
+            chars: text.char_indices().peekable(),
+            params: None,
+            arg_positions: Vec::new(),
+        }
+    }
+
+    /// Parses an action that makes up all of the text but surrounding whitespace.
+    fn parse(&mut self, params: impl FnOnce(&str) -> Option<Vec<String>>) -> Result<Action, ActionParseError> {
+        let action = self.action(params)?;
+        self.skip_whitespace();
+
+        match self.chars.peek() {
+            None => Ok(action),
+            Some(&(position, _)) => Err(ActionParseError {
+                position,
+                kind: ActionParseErrorKind::TrailingCharacters,
+            }),
+        }
+    }
+
+    fn position(&mut self) -> usize {
+        self.chars.peek().map(|&(i, _)| i).unwrap_or(self.text.len())
+    }
+
+    fn skip_whitespace(&mut self) {
+        while matches!(self.chars.peek(), Some((_, c)) if c.is_whitespace()) {
+            self.chars.next();
+        }
+    }
+
+    fn error<T>(&mut self, kind: ActionParseErrorKind) -> Result<T, ActionParseError> {
+        Err(ActionParseError {
+            position: self.position(),
+            kind,
+        })
+    }
+
+    fn ident(&mut self) -> Option<String> {
+        let mut ident = String::new();
+        while let Some(&(_, c)) = self.chars.peek() {
+            if c.is_alphanumeric() || c == '_' || c == '-' {
+                ident.push(c);
+                self.chars.next();
+            } else {
+                break;
+            }
+        }
+
+        (!ident.is_empty()).then_some(ident)
+    }
+
+    fn action(&mut self, params: impl FnOnce(&str) -> Option<Vec<String>>) -> Result<Action, ActionParseError> {
+        self.skip_whitespace();
+        let name = match self.ident() {
+            Some(name) => name,
+            None => return self.error(ActionParseErrorKind::ExpectedName),
+        };
+        self.params = params(&name);
+
+        self.skip_whitespace();
+        let close = match self.chars.peek() {
+            Some((_, '[')) => ']',
+            Some((_, '(')) => ')',
+            _ => return self.error(ActionParseErrorKind::ExpectedArguments),
+        };
+        self.chars.next();
+
+        let start = self.position();
+        let args = self.args(close)?;
+        let end = self.position();
+        self.chars.next();
+
+        Ok(Action {
+            name,
+            args,
+            raw_args: self.text[start..end].trim().to_string(),
+        })
+    }
+
+    /// Parses the argument list up to, but not including, the `close` delimiter.
+    fn args(&mut self, close: char) -> Result<Vec<Argument>, ActionParseError> {
+        let mut args = Vec::new();
+
+        self.skip_whitespace();
+        if matches!(self.chars.peek(), Some(&(_, c)) if c == close) {
+            return Ok(args);
+        }
+
+        loop {
+            args.push(self.arg(close)?);
+
+            match self.chars.peek() {
+                Some(&(_, ',')) => {
+                    self.chars.next();
+                }
+                Some(&(_, c)) if c == close => return Ok(args),
+                Some(&(_, c)) => return self.error(ActionParseErrorKind::UnexpectedAfterString(c)),
+                None => return self.error(ActionParseErrorKind::Unclosed(opening(close))),
+            }
+        }
+    }
+
+    fn arg(&mut self, close: char) -> Result<Argument, ActionParseError> {
+        self.skip_whitespace();
+        let start = self.position();
+        self.arg_positions.push(start);
+
+        let name = self.named_arg_prefix();
+
+        self.skip_whitespace();
+        let (value, quoted) = match self.chars.peek() {
+            Some(&(_, quote @ ('"' | '\''))) => {
+                self.chars.next();
+                let value = self.quoted(quote)?;
+                self.skip_whitespace();
+                (value, true)
+            }
+            _ => (self.bare(close)?, false),
+        };
+
+        if value.is_empty() && !quoted {
+            return self.error(ActionParseErrorKind::EmptyArgument);
+        }
+
+        Ok(Argument { name, value, quoted })
+    }
+
+    /// Consumes `name =` if the argument starts with one and `name` is a declared parameter, or
+    /// the parameters are unknown. Leaves the input untouched otherwise.
+    fn named_arg_prefix(&mut self) -> Option<String> {
+        let checkpoint = self.chars.clone();
+
+        if let Some(name) = self.ident() {
+            self.skip_whitespace();
+            let declared = match &self.params {
+                Some(params) => params.contains(&name),
+                None => true,
+            };
+            if let (true, Some(&(_, '='))) = (declared, self.chars.peek()) {
+                self.chars.next();
+                if !matches!(self.chars.peek(), Some(&(_, '='))) {
+                    return Some(name);
+                }
+            }
+        }
+
+        self.chars = checkpoint;
+        None
+    }
+
+    fn quoted(&mut self, quote: char) -> Result<String, ActionParseError> {
+        let mut value = String::new();
+        loop {
+            match self.chars.next() {
+                Some((_, '\\')) => match self.chars.next() {
+                    Some((_, c)) => value.push(c),
+                    None => return self.error(ActionParseErrorKind::UnterminatedString),
+                },
+                Some((_, c)) if c == quote => return Ok(value),
+                Some((_, c)) => value.push(c),
+                None => return self.error(ActionParseErrorKind::UnterminatedString),
+            }
+        }
+    }
+
+    /// Reads an unquoted argument up to the next top level comma or the `close` delimiter.
+    fn bare(&mut self, close: char) -> Result<String, ActionParseError> {
+        let mut value = String::new();
+        let mut nesting: Vec<(usize, char)> = Vec::new();
+
+        loop {
+            let (position, c) = match self.chars.peek() {
+                Some(&next) => next,
+                None => {
+                    let (position, open) = nesting.pop().unwrap_or((self.text.len(), opening(close)));
+                    return Err(ActionParseError {
+                        position,
+                        kind: ActionParseErrorKind::Unclosed(open),
+                    });
+                }
+            };
+
+            match c {
+                '(' | '[' | '{' => nesting.push((position, c)),
+                ')' | ']' | '}' => match nesting.pop() {
+                    Some((_, open)) if closing(open) == c => {}
+                    Some((_, open)) => {
+                        return self.error(ActionParseErrorKind::MismatchedDelimiter {
+                            expected: closing(open),
+                            found: c,
+                        })
+                    }
+                    None if c == close => break,
+                    // a closing bracket that was never opened is free text, like in `:)`
+                    None => {}
+                },
+                ',' if nesting.is_empty() => break,
+                _ => {}
+            }
+
+            value.push(c);
+            self.chars.next();
+        }
+
+        Ok(value.trim().to_string())
+    }
+}
+
+fn opening(close: char) -> char {
+    match close {
+        ')' => '(',
+        ']' => '[',
+        _ => '{',
+    }
+}
+
+fn closing(open: char) -> char {
+    match open {
+        '(' => ')',
+        '[' => ']',
+        _ => '}',
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Action, ActionParseErrorKind, CommandSignature};
+
+    #[test]
+    pub fn test_parse_action() {
+        let action = Action::parse("tweet[earthquake in Delhi]").unwrap();
+        assert_eq!(action.name, "tweet");
+        assert_eq!(action.first_arg(), "earthquake in Delhi");
+
+        let action = Action::parse("summary(Github's new feature release)").unwrap();
+        assert_eq!(action.name, "summary");
+        assert_eq!(action.first_arg(), "Github's new feature release");
+
+        let action = Action::parse("calculate[pow(2, 3) * (1 + 2)]").unwrap();
+        assert_eq!(action.args.len(), 1);
+        assert_eq!(action.first_arg(), "pow(2, 3) * (1 + 2)");
+
+        let action = Action::parse(r#"question(query="capital, of \"India\"", topic=India)"#).unwrap();
+        assert_eq!(action.args.len(), 2);
+        assert_eq!(action.args[0].name.as_deref(), Some("query"));
+        assert_eq!(action.args[0].value, "capital, of \"India\"");
+        assert_eq!(action.args[1].name.as_deref(), Some("topic"));
+        assert_eq!(action.args[1].value, "India");
+
+        let action = Action::parse("calculate[x == 2]").unwrap();
+        assert_eq!(action.args[0].name, None);
+
+        let action = Action::parse("tweet[smile :)]").unwrap();
+        assert_eq!(action.first_arg(), "smile :)");
+
+        let params = |_: &str| Some(vec!["expression".to_string()]);
+        let action = Action::parse_with("calculate[x = 2+3]", params).unwrap();
+        assert_eq!(action.args[0].name, None, "x is not a parameter of calculate");
+        assert_eq!(action.first_arg(), "x = 2+3");
+        let action = Action::parse_with("calculate[expression = 2+3]", params).unwrap();
+        assert_eq!(action.args[0].name.as_deref(), Some("expression"));
+    }
+
+    #[test]
+    pub fn test_parse_action_errors() {
+        let err = Action::parse("tweet earthquake").unwrap_err();
+        assert_eq!(err.kind, ActionParseErrorKind::ExpectedArguments);
+        assert_eq!(err.position, 6);
+
+        let err = Action::parse("calculate[(1 + 2]").unwrap_err();
+        assert_eq!(
+            err.kind,
+            ActionParseErrorKind::MismatchedDelimiter { expected: ')', found: ']' }
+        );
+        assert_eq!(err.position, 16);
+
+        let err = Action::parse("tweet(\"earthquake)").unwrap_err();
+        assert_eq!(err.kind, ActionParseErrorKind::UnterminatedString);
+
+        let err = Action::parse("tweet[earthquake").unwrap_err();
+        assert_eq!(err.kind, ActionParseErrorKind::Unclosed('['));
+
+        let err = Action::parse("tweet[earthquake] in Delhi").unwrap_err();
+        assert_eq!(err.kind, ActionParseErrorKind::TrailingCharacters);
+    }
+
+    #[test]
+    pub fn test_parse_signature() {
+        let sig = CommandSignature::parse("calculate[mathematical expression]").unwrap();
+        assert_eq!(sig.name, "calculate");
+        assert_eq!(sig.params, vec!["mathematical expression"]);
+
+        let err = CommandSignature::parse("tweet(topic, topic)").unwrap_err();
+        assert_eq!(err.kind, ActionParseErrorKind::DuplicateParameter("topic".to_string()));
+        assert_eq!(err.position, 13);
+        let err = CommandSignature::parse("tweet(\"topic\")").unwrap_err();
+        assert_eq!(err.position, 6);
+        assert!(CommandSignature::parse("tweet").is_err());
+    }
+}
@@ -1,40 +1,83 @@
-use tonic::{transport::Channel, Request, Response, Status};
+use std::{sync::Arc, time::Duration};
+
+use tonic::{
+    transport::{Channel, Endpoint},
+    Request, Response, Status,
+};
 
 use crate::{
     common::TaskExecutor,
+    error::MeeseeksError,
     meeseeks_proto::{
-        self, master_agent_client::MasterAgentClient, AgentConnectRequest, TaskRequest,
-        TaskResponse,
+        self, master_agent_client::MasterAgentClient, AgentConnectRequest,
+        AgentDisconnectRequest, CommandSchema, HeartbeatRequest, TaskRequest, TaskResponse,
     },
+    schema,
 };
 
 use crate::error::Result;
 
+/// Exponential backoff used while (re)connecting to the master.
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    pub initial: Duration,
+    pub max: Duration,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Backoff {
+            initial: Duration::from_millis(500),
+            max: Duration::from_secs(30),
+        }
+    }
+}
+
+impl Backoff {
+    /// Delay before retry number `attempt`, starting at 0.
+    pub fn delay(&self, attempt: u32) -> Duration {
+        self.initial
+            .checked_mul(2u32.saturating_pow(attempt))
+            .map_or(self.max, |delay| delay.min(self.max))
+    }
+}
+
 pub struct Agent<Executor: TaskExecutor> {
     name: String,
+    tool: String,
     description: String,
     addr: String,
     master_addr: Option<String>,
     client: Option<MasterAgentClient<Channel>>,
-    executor: Executor,
+    master_instance_id: Option<String>,
+    executor: Arc<Executor>,
     commands: Vec<String>,
-    examples: String,
+    schemas: Vec<CommandSchema>,
 }
 
 impl<T: TaskExecutor> Agent<T> {
-    pub fn new(name: String, description: String, addr: String, executor: T, commands: Vec<String>, examples: String) -> Self {
+    pub fn new(name: String, description: String, addr: String, executor: T, commands: Vec<String>) -> Self {
+        let schemas = executor.schemas();
         Agent {
+            tool: name.clone(),
             name,
             description,
             addr,
-            executor,
+            executor: Arc::new(executor),
             master_addr: None,
             client: None,
+            master_instance_id: None,
             commands,
-            examples,
+            schemas,
         }
     }
 
+    /// Sets the pool of replicas this agent joins on the master. Defaults to the agent's name.
+    pub fn with_tool(mut self, tool: String) -> Self {
+        self.tool = tool;
+        self
+    }
+
     pub async fn connect_to_master(&mut self, master_addr: String) -> Result<()> {
         self.master_addr = Some(master_addr.clone());
 
@@ -42,24 +85,147 @@ impl<T: TaskExecutor> Agent<T> {
 
         let mut client = MasterAgentClient::connect(master_addr.clone()).await?;
         let res = client
-            .connect_to_master(AgentConnectRequest {
-                name: self.name.clone(),
-                description: self.description.clone(),
-                from: self.addr.to_string(),
-                examples: self.examples.clone(),
-                commands: self.commands.clone(),
-            })
-            .await?;
+            .connect_to_master(self.connect_request())
+            .await?
+            .into_inner();
 
         tracing::info!(
             "master returned response status: {}",
-            res.into_inner().status
+            res.status
         );
         self.client = Some(client);
+        self.master_instance_id = Some(res.instance_id);
         tracing::info!("connected to master: {}", master_addr);
 
         Ok(())
     }
+
+    /// Like `connect_to_master`, but keeps retrying with `backoff` until the master is reachable,
+    /// so agents can be started before the master.
+    pub async fn connect_to_master_with_backoff(&mut self, master_addr: String, backoff: &Backoff) -> Result<()> {
+        self.master_addr = Some(master_addr.clone());
+
+        let channel = Endpoint::from_shared(master_addr.clone())?.connect_lazy();
+        let mut client = MasterAgentClient::new(channel);
+        let instance_id = register(&mut client, &self.connect_request(), backoff).await;
+
+        self.client = Some(client);
+        self.master_instance_id = Some(instance_id);
+        tracing::info!("connected to master: {}", master_addr);
+
+        Ok(())
+    }
+
+    /// Removes this agent from the master so that it stops routing tasks to it. Does nothing if the
+    /// agent is not connected to a master.
+    pub async fn disconnect_from_master(&self) -> Result<()> {
+        let mut client = match self.client.clone() {
+            Some(client) => client,
+            None => return Ok(()),
+        };
+
+        client
+            .disconnect_from_master(AgentDisconnectRequest {
+                name: self.name.clone(),
+                from: self.addr.to_string(),
+            })
+            .await?;
+        tracing::info!("disconnected from master");
+
+        Ok(())
+    }
+
+    /// Spawns a task that sends a heartbeat to the master every `interval` and keeps the agent
+    /// registered. The agent registers again, retrying with `backoff`, if the master cannot be
+    /// reached, no longer knows this agent (e.g. because it was evicted) or has restarted since the
+    /// last registration, and when the executor's examples changed. Does nothing if the agent is
+    /// not connected to a master.
+    pub fn spawn_supervisor(&self, interval: Duration, backoff: Backoff) -> Option<tokio::task::JoinHandle<()>>
+    where
+        T: Send + Sync + 'static,
+    {
+        let mut client = self.client.clone()?;
+        let mut instance_id = self.master_instance_id.clone().unwrap_or_default();
+        let mut registration = self.connect_request();
+        let executor = self.executor.clone();
+
+        Some(tokio::spawn(async move {
+            let mut interval = tokio::time::interval(interval);
+            loop {
+                interval.tick().await;
+
+                let examples = executor.examples();
+                if examples != registration.examples {
+                    tracing::info!("examples changed. registering again");
+                    registration.examples = examples;
+                    instance_id = register(&mut client, &registration, &backoff).await;
+                    interval.reset();
+                    continue;
+                }
+
+                let heartbeat = HeartbeatRequest {
+                    name: registration.name.clone(),
+                    from: registration.from.clone(),
+                };
+                match client.heartbeat(heartbeat).await {
+                    Ok(res) => {
+                        let res = res.into_inner();
+                        if !res.registered {
+                            tracing::warn!("master does not know this agent anymore. registering again");
+                        } else if res.instance_id != instance_id {
+                            tracing::warn!("master has restarted. registering again");
+                        } else {
+                            tracing::trace!("sent heartbeat to master");
+                            continue;
+                        }
+                    }
+                    Err(e) => {
+                        tracing::warn!("failed to send heartbeat to master: {}. reconnecting", e);
+                    }
+                }
+
+                instance_id = register(&mut client, &registration, &backoff).await;
+                interval.reset();
+            }
+        }))
+    }
+
+    fn connect_request(&self) -> AgentConnectRequest {
+        AgentConnectRequest {
+            name: self.name.clone(),
+            description: self.description.clone(),
+            from: self.addr.to_string(),
+            examples: self.executor.examples(),
+            commands: self.commands.clone(),
+            schemas: self.schemas.clone(),
+            tool: self.tool.clone(),
+        }
+    }
+}
+
+/// Registers with the master, retrying with `backoff` until it succeeds. Returns the instance id of
+/// the master.
+async fn register(
+    client: &mut MasterAgentClient<Channel>,
+    registration: &AgentConnectRequest,
+    backoff: &Backoff,
+) -> String {
+    let mut attempt = 0;
+    loop {
+        match client.connect_to_master(registration.clone()).await {
+            Ok(res) => {
+                let res = res.into_inner();
+                tracing::info!("registered with master instance {}", res.instance_id);
+                return res.instance_id;
+            }
+            Err(e) => {
+                let delay = backoff.delay(attempt);
+                tracing::warn!("failed to register with master: {}. retrying in {:?}", e, delay);
+                tokio::time::sleep(delay).await;
+                attempt += 1;
+            }
+        }
+    }
 }
 
 #[tonic::async_trait]
@@ -70,7 +236,10 @@ impl<Executor: TaskExecutor + Send + Sync + 'static> meeseeks_proto::agent_serve
         &self,
         request: Request<TaskRequest>,
     ) -> std::result::Result<Response<TaskResponse>, Status> {
-        let req = request.into_inner();
+        // tasks that do not fit the advertised schemas are rejected with Unimplemented or
+        // InvalidArgument, so that the master corrects them instead of retrying them
+        let req = schema::validate(request.into_inner(), &self.schemas)
+            .map_err(|e| Status::from(MeeseeksError::from(e)))?;
 
         tracing::debug!("executing task: {:?}", req);
         let res = self.executor.exec(req).await;
@@ -78,3 +247,23 @@ impl<Executor: TaskExecutor + Send + Sync + 'static> meeseeks_proto::agent_serve
         Ok(Response::new(res))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::Backoff;
+
+    #[test]
+    pub fn test_backoff() {
+        let backoff = Backoff {
+            initial: Duration::from_millis(100),
+            max: Duration::from_secs(1),
+        };
+
+        assert_eq!(backoff.delay(0), Duration::from_millis(100));
+        assert_eq!(backoff.delay(3), Duration::from_millis(800));
+        assert_eq!(backoff.delay(4), Duration::from_secs(1));
+        assert_eq!(backoff.delay(100), Duration::from_secs(1));
+    }
+}
@@ -1,9 +1,11 @@
-use std::{net::SocketAddr};
+use std::{net::SocketAddr, path::PathBuf, sync::Arc, time::Duration};
 
 use clap::Parser;
 use meeseeks::{
-    agent::Agent,
+    action::CommandSignature,
+    agent::{Agent, Backoff},
     meeseeks_proto::agent_server,
+    template::Templates,
     tool::{Calculator, Tool, Tweetu, Wiki}, common::TaskExecutor,
 };
 use tonic::transport::Server;
@@ -23,35 +25,97 @@ pub struct AgentCli {
     addr: String,
     #[arg(short, long)]
     master: String,
+    /// Seconds between heartbeats sent to the master
+    #[arg(long = "heartbeat-interval", default_value_t = 5)]
+    heartbeat_interval: u64,
+    /// Maximum number of seconds to wait between attempts to reach the master
+    #[arg(long = "max-backoff", default_value_t = 30)]
+    max_backoff: u64,
+    /// Directory with prompt templates replacing the builtin ones, e.g. `examples/wiki.txt`.
+    /// Changed files are reloaded while the agent runs
+    #[arg(long = "templates")]
+    templates: Option<PathBuf>,
 }
 
+/// How often the template directory is checked for changes.
+const TEMPLATE_POLL_INTERVAL: Duration = Duration::from_secs(2);
+
 impl AgentCli {
     pub async fn run() -> color_eyre::Result<()> {
         let args = AgentCli::parse();
 
-        let tool = tool_from_name(&args.tool)?;
+        let templates = Arc::new(match &args.templates {
+            Some(dir) => Templates::load(dir)?,
+            None => Templates::builtin(),
+        });
+        let watcher = templates.watch(TEMPLATE_POLL_INTERVAL);
+
+        let tool = tool_from_name(&args.tool, templates)?;
+        for command in tool.commands() {
+            CommandSignature::parse(command).map_err(|e| {
+                color_eyre::eyre::eyre!("tool {} has an invalid command signature {:?}: {}", args.tool, command, e)
+            })?;
+        }
 
         let agent_addr = args.addr;
         let commands = tool.commands().iter().map(|x| x.to_string()).collect();
-        let examples = tool.examples().into();
-        let mut agent = Agent::new(args.name, args.description, agent_addr, tool, commands, examples);
+        let mut agent = Agent::new(args.name, args.description, agent_addr, tool, commands).with_tool(args.tool);
 
-        agent.connect_to_master(args.master).await?;
+        let backoff = Backoff {
+            max: Duration::from_secs(args.max_backoff),
+            ..Default::default()
+        };
+        agent.connect_to_master_with_backoff(args.master, &backoff).await?;
+        let supervisor = agent.spawn_supervisor(Duration::from_secs(args.heartbeat_interval), backoff);
 
+        let agent = Arc::new(agent);
         Server::builder()
-            .add_service(agent_server::AgentServer::new(agent))
-            .serve(args.listen)
+            .add_service(agent_server::AgentServer::from_arc(agent.clone()))
+            .serve_with_shutdown(args.listen, shutdown_signal())
             .await?;
 
+        if let Some(supervisor) = supervisor {
+            supervisor.abort();
+        }
+        if let Some(watcher) = watcher {
+            watcher.abort();
+        }
+        agent.disconnect_from_master().await?;
+
         Ok(())
     }
 }
 
-fn tool_from_name(name: &str) -> Result<Tool, color_eyre::eyre::Error> {
+/// Resolves when the process receives SIGINT or SIGTERM.
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("failed to install SIGINT handler");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("failed to install SIGTERM handler")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+    tracing::info!("shutting down");
+}
+
+fn tool_from_name(name: &str, templates: Arc<Templates>) -> Result<Tool, color_eyre::eyre::Error> {
     match name {
-        "tweetu" => Ok(Tool::Tweetu(Tweetu::new())),
-        "calculator" => Ok(Tool::Calculator(Calculator)),
-        "wiki" => Ok(Tool::Wiki(Wiki::new())),
+        "tweetu" => Ok(Tool::Tweetu(Tweetu::new().with_templates(templates))),
+        "calculator" => Ok(Tool::Calculator(Calculator::default().with_templates(templates))),
+        "wiki" => Ok(Tool::Wiki(Wiki::new().with_templates(templates))),
         _ => Err(color_eyre::eyre::Error::msg(format!(
             "no tool named: {}",
             name
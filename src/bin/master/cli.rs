@@ -1,18 +1,32 @@
+use color_eyre::eyre::eyre;
 use meeseeks::{
-    common::ConnectedAgent,
-    llama_parser::LlamaParser,
-    master::MasterAgent,
-    meeseeks_proto::{master_agent_server, Status, TaskRequest},
+    llama_parser::{KvMemory, LlamaParser, ModelConfig},
+    breaker::BreakerConfig,
+    canonical::{CanonicalizeError, Canonicalizer},
+    bm25::Bm25Matcher,
+    common::{AgentMatch, AgentMatcher, ConnectedAgent, MatcherKind, TaskParser},
+    embedding::{Embedder, EmbeddingMatcher, HashingEmbedder},
+    error::{MatchError, ParseError},
+    master::{DispatchConfig, Fallback, HealthConfig, MasterAgent, MatchConfig},
+    meeseeks_proto::{master_agent_server, TaskRequest},
+    parser::{http::HttpParser, rules::RuleParser, ParserKind},
+    prompt_cache::PrefixCache,
+    registry::Balancing,
+    registry_store::RegistryStore,
+    routing::RoutingRules,
+    task_graph::{NodeResult, NodeStatus, TaskGraph, TaskNode},
+    template::Templates,
     tooldb::ToolDB,
 };
+use llm::LoadProgress;
 use reqwest::Url;
 use std::{
-    collections::VecDeque,
     io::Write,
     net::SocketAddr,
     path::PathBuf,
     process::exit,
     sync::Arc,
+    time::Duration,
 };
 
 use tonic::transport::Server;
@@ -28,18 +42,273 @@ pub struct MasterCli {
     listen: SocketAddr,
     #[arg(long)]
     addr: String,
+    /// GGML model used by the llama parser
     #[arg(short = 'm', long = "model-path")]
-    llama_model_path: PathBuf,
+    llama_model_path: Option<PathBuf>,
+    /// How inputs are matched to agents: tooldb, embedding or bm25. Defaults to tooldb if
+    /// --tooldb-url is set and to embedding otherwise
+    #[arg(long = "matcher")]
+    matcher: Option<MatcherKind>,
+    /// ToolDB service used by the tooldb matcher
     #[arg(long = "tooldb-url")]
-    tooldb_url: Url,
+    tooldb_url: Option<Url>,
+    /// File where the connected agents are persisted, so that they are known again after a restart
+    #[arg(long = "registry")]
+    registry: Option<PathBuf>,
+    /// File where the embeddings of the agents are persisted, so that they survive restarts
+    #[arg(long = "embedding-store")]
+    embedding_store: Option<PathBuf>,
+    /// Number of best matching agents whose commands are offered to the parser for an input
+    #[arg(long = "match-candidates", default_value_t = 3)]
+    match_candidates: usize,
+    /// TOML file with routing rules that send inputs to agents or block them before the matcher is
+    /// asked
+    #[arg(long = "routing-rules")]
+    routing_rules: Option<PathBuf>,
+    /// Agents whose match score (0 to 1) for an input is lower are not offered to the parser
+    #[arg(long = "min-match-score", default_value_t = 0.0)]
+    min_match_score: f32,
+    /// What happens to inputs no agent applies to: rephrase (ask the user to rephrase them), answer
+    /// (answer them with the parser's model) or agent:NAME (offer them to the named agent)
+    #[arg(long = "fallback", default_value = "rephrase")]
+    fallback: Fallback,
+    /// TOML file with the architecture and sampling parameters of the model. Flags take precedence
+    #[arg(long = "model-config")]
+    model_config: Option<PathBuf>,
+    /// Architecture of the model: bloom, gpt2, gptj, gptneox or llama
+    #[arg(long = "model-architecture")]
+    model_architecture: Option<String>,
+    /// Number of tokens of context the model is loaded with
+    #[arg(long = "context-size")]
+    context_size: Option<usize>,
+    /// Number of threads used for inference
+    #[arg(long = "threads")]
+    threads: Option<usize>,
+    /// Precision of the model's key/value memory: f16 or f32
+    #[arg(long = "kv-memory")]
+    kv_memory: Option<KvMemory>,
+    #[arg(long = "temperature")]
+    temperature: Option<f32>,
+    #[arg(long = "top-k")]
+    top_k: Option<usize>,
+    #[arg(long = "top-p")]
+    top_p: Option<f32>,
+    #[arg(long = "repeat-penalty")]
+    repeat_penalty: Option<f32>,
+    /// Maximum number of tokens generated for a single prompt
+    #[arg(long = "max-tokens")]
+    max_tokens: Option<usize>,
+    /// Sequence at which generation stops. Can be repeated
+    #[arg(long = "stop")]
+    stop_sequences: Vec<String>,
+    /// Parser that turns inputs into tasks: llama, rules or http
+    #[arg(long = "parser", default_value = "llama")]
+    parser: ParserKind,
+    /// TOML file with the rules of the rules parser
+    #[arg(long = "rules")]
+    rules: Option<PathBuf>,
+    /// OpenAI compatible completion endpoint used by the http parser. The API key is read from
+    /// OPENAI_API_KEY if set
+    #[arg(long = "parser-url")]
+    parser_url: Option<Url>,
+    /// Model requested from the completion endpoint of the http parser
+    #[arg(long = "parser-model", default_value = "text-davinci-003")]
+    parser_model: String,
+    /// Maximum number of actions the ReAct loop may run for a single question
+    #[arg(long = "max-steps", default_value_t = 5)]
+    max_steps: usize,
+    /// Parse every input line with its own inference instead of planning the whole batch at once
+    #[arg(long = "per-line")]
+    per_line: bool,
+    /// Seconds without a heartbeat after which an agent stops receiving tasks
+    #[arg(long = "unhealthy-after", default_value_t = 15)]
+    unhealthy_after: u64,
+    /// Seconds without a heartbeat after which an agent is removed
+    #[arg(long = "evict-after", default_value_t = 60)]
+    evict_after: u64,
+    /// How tasks are spread over the replicas of a tool: round-robin or least-outstanding
+    #[arg(long = "balancing", default_value = "round-robin")]
+    balancing: Balancing,
+    /// Seconds to wait for an agent to complete a task
+    #[arg(long = "timeout", default_value_t = 30)]
+    timeout: u64,
+    /// Timeout for the tasks of one agent, as NAME=SECONDS. Can be repeated
+    #[arg(long = "agent-timeout", value_parser = parse_timeout)]
+    agent_timeouts: Vec<(String, Duration)>,
+    /// Timeout for one command, as NAME=SECONDS. Takes precedence over agent timeouts. Can be repeated
+    #[arg(long = "command-timeout", value_parser = parse_timeout)]
+    command_timeouts: Vec<(String, Duration)>,
+    /// Number of times a task that timed out or failed with a retryable error is sent
+    #[arg(long = "max-attempts", default_value_t = 3)]
+    max_attempts: u32,
+    /// Consecutive failures after which an agent stops receiving tasks
+    #[arg(long = "breaker-threshold", default_value_t = 5)]
+    breaker_threshold: u32,
+    /// Seconds before an agent that stopped receiving tasks is tried again
+    #[arg(long = "breaker-cooldown", default_value_t = 30)]
+    breaker_cooldown: u64,
+    /// How often the model is asked to correct a task that an agent rejected
+    #[arg(long = "max-corrections", default_value_t = 2)]
+    max_corrections: usize,
+    /// Instructions that match an advertised command with a lower confidence (0 to 1) are rejected
+    #[arg(long = "min-command-confidence", default_value_t = 0.75)]
+    min_command_confidence: f64,
+    /// Number of evaluated prompt prefixes kept in memory. 0 disables the cache
+    #[arg(long = "prompt-cache-size", default_value_t = 2)]
+    prompt_cache_size: usize,
+    /// Directory where evaluated prompt prefixes are persisted, so that they survive restarts
+    #[arg(long = "prompt-cache-dir")]
+    prompt_cache_dir: Option<PathBuf>,
+    /// Directory with prompt templates replacing the builtin ones, e.g. `parse.txt`. Changed files
+    /// are reloaded while the master runs
+    #[arg(long = "templates")]
+    templates: Option<PathBuf>,
+}
+
+/// How often the template directory is checked for changes.
+const TEMPLATE_POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+fn report_load_progress(progress: LoadProgress) {
+    match progress {
+        LoadProgress::HyperparametersLoaded => tracing::debug!("loaded hyperparameters"),
+        LoadProgress::ContextSize { bytes } => {
+            tracing::debug!("allocated {:.2} MB of context", bytes as f64 / (1024.0 * 1024.0))
+        }
+        LoadProgress::TensorLoaded { current_tensor, tensor_count } => {
+            print!("\rLoaded tensor {}/{}", current_tensor + 1, tensor_count);
+            let _ = std::io::stdout().flush();
+        }
+        LoadProgress::Loaded { file_size, tensor_count } => println!(
+            "\rLoaded {} tensors ({:.2} MB)",
+            tensor_count,
+            file_size as f64 / (1024.0 * 1024.0)
+        ),
+    }
+}
+
+fn parse_timeout(s: &str) -> Result<(String, Duration), String> {
+    let (name, secs) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected NAME=SECONDS, got {}", s))?;
+    let secs: u64 = secs.trim().parse().map_err(|e| format!("invalid number of seconds {}: {}", secs, e))?;
+    Ok((name.trim().to_string(), Duration::from_secs(secs)))
 }
 
 impl MasterCli {
     pub async fn run() -> color_eyre::Result<()> {
         let args = MasterCli::parse();
 
-        let tooldb = ToolDB::new(args.tooldb_url)?;
-        let master = Arc::new(MasterAgent::new(args.name, args.listen, tooldb));
+        let templates = Arc::new(match &args.templates {
+            Some(dir) => Templates::load(dir)?,
+            None => Templates::builtin(),
+        });
+        templates.watch(TEMPLATE_POLL_INTERVAL);
+
+        let (parser, llama): (Arc<dyn TaskParser>, Option<Arc<LlamaParser>>) = match args.parser {
+            ParserKind::Llama => {
+                let model_path = args
+                    .llama_model_path
+                    .ok_or_else(|| eyre!("--model-path is required by the llama parser"))?;
+                let mut config = match &args.model_config {
+                    Some(path) => ModelConfig::from_file(path)?,
+                    None => ModelConfig::default(),
+                };
+                if let Some(architecture) = args.model_architecture {
+                    config.architecture = architecture;
+                }
+                config.context_size = args.context_size.unwrap_or(config.context_size);
+                config.threads = args.threads.unwrap_or(config.threads);
+                config.kv_memory = args.kv_memory.unwrap_or(config.kv_memory);
+                config.temperature = args.temperature.unwrap_or(config.temperature);
+                config.top_k = args.top_k.unwrap_or(config.top_k);
+                config.top_p = args.top_p.unwrap_or(config.top_p);
+                config.repeat_penalty = args.repeat_penalty.unwrap_or(config.repeat_penalty);
+                config.max_tokens = args.max_tokens.unwrap_or(config.max_tokens);
+                config.stop_sequences.extend(args.stop_sequences);
+
+                println!("Loading {} model from {}", config.architecture, model_path.display());
+                let llama = LlamaParser::init(&model_path, &config, report_load_progress)
+                    .expect("failed to initialize llama parser")
+                    .with_prefix_cache(PrefixCache::new(args.prompt_cache_size, args.prompt_cache_dir))
+                    .with_templates(templates.clone());
+
+                let llama = Arc::new(llama);
+                (llama.clone(), Some(llama))
+            }
+            ParserKind::Rules => {
+                let rules = args.rules.ok_or_else(|| eyre!("--rules is required by the rules parser"))?;
+                (Arc::new(RuleParser::from_file(&rules)?), None)
+            }
+            ParserKind::Http => {
+                let url = args.parser_url.ok_or_else(|| eyre!("--parser-url is required by the http parser"))?;
+                let mut parser = HttpParser::new(url, args.parser_model).with_templates(templates.clone());
+                if let Ok(api_key) = std::env::var("OPENAI_API_KEY") {
+                    parser = parser.with_api_key(api_key);
+                }
+                (Arc::new(parser), None)
+            }
+        };
+
+        let matcher_kind = match (args.matcher, &args.tooldb_url) {
+            (Some(kind), _) => kind,
+            (None, Some(_)) => MatcherKind::ToolDb,
+            (None, None) => MatcherKind::Embedding,
+        };
+        let matcher: Box<dyn AgentMatcher + Send + Sync> = match matcher_kind {
+            MatcherKind::ToolDb => {
+                let url = args.tooldb_url.ok_or_else(|| eyre!("--tooldb-url is required by the tooldb matcher"))?;
+                Box::new(ToolDB::new(url)?)
+            }
+            MatcherKind::Embedding => {
+                let embedder: Arc<dyn Embedder> = match &llama {
+                    Some(llama) => llama.clone(),
+                    None => Arc::new(HashingEmbedder::default()),
+                };
+                let mut matcher = EmbeddingMatcher::new(embedder);
+                if let Some(path) = args.embedding_store {
+                    matcher = matcher.with_store(path);
+                }
+                Box::new(matcher)
+            }
+            MatcherKind::Bm25 => Box::new(Bm25Matcher::default()),
+        };
+
+        let routing_rules = match &args.routing_rules {
+            Some(path) => RoutingRules::from_file(path)?,
+            None => RoutingRules::default(),
+        };
+        let health = HealthConfig {
+            unhealthy_after: Duration::from_secs(args.unhealthy_after),
+            evict_after: Duration::from_secs(args.evict_after),
+            ..Default::default()
+        };
+        let dispatch = DispatchConfig {
+            timeout: Duration::from_secs(args.timeout),
+            agent_timeouts: args.agent_timeouts.into_iter().collect(),
+            command_timeouts: args.command_timeouts.into_iter().collect(),
+            max_attempts: args.max_attempts.max(1),
+            breaker: BreakerConfig {
+                failure_threshold: args.breaker_threshold.max(1),
+                cooldown: Duration::from_secs(args.breaker_cooldown),
+            },
+            ..Default::default()
+        };
+        let mut master = MasterAgent::new(args.name, args.listen, matcher)
+            .with_health_config(health)
+            .with_dispatch_config(dispatch)
+            .with_match_config(MatchConfig {
+                candidates: args.match_candidates.max(1),
+                min_score: args.min_match_score,
+            })
+            .with_routing_rules(routing_rules)
+            .with_canonicalizer(Canonicalizer::new(args.min_command_confidence))
+            .with_balancing(args.balancing);
+        if let Some(path) = args.registry {
+            master = master.with_registry_store(RegistryStore::new(path));
+        }
+        let master = Arc::new(master);
+        master.restore().await;
+        master.spawn_health_monitor();
         let master_c = master.clone();
 
         let _join = tokio::spawn(async move {
@@ -56,21 +325,18 @@ impl MasterCli {
             }
         });
 
-        let mut sp =
-            spinners::Spinner::new(spinners::Spinners::Dots9, "Loading llama model".to_string());
-        let parser =
-            LlamaParser::init(&args.llama_model_path).expect("failed to initialize llama parser");
-        sp.stop();
-        println!("");
 
         let mut line = String::new();
-        let mut input_tasks = VecDeque::new();
-        let mut tasks = VecDeque::new();
+        let mut input_tasks = Vec::new();
 
         loop {
             println!("--- Command --- (enter help for a list of commands) ");
             print!("> ");
             std::io::stdout().flush()?;
+            std::io::stdin().read_line(&mut line)?;
+            if let Some(llama) = &llama {
+                llama.sync_agents(master.agents_generation());
+            }
             match line.trim() {
                 "input" => {
                     println!("--- Input --- ");
@@ -78,33 +344,114 @@ impl MasterCli {
                     std::io::stdout().flush()?;
 
                     let stdin = std::io::stdin();
-                    let _len = 0;
+                    line.clear();
                     loop {
                         let _len = stdin.read_line(&mut line);
                         if line.trim().is_empty() {
                             break;
                         }
-                        input_tasks.push_back(line.trim().to_string());
+                        input_tasks.push(line.trim().to_string());
                         print!("> ");
                         std::io::stdout().flush()?;
                         line.clear();
                     }
-                    infer_tasks(master.clone(), &mut input_tasks, &mut tasks, &parser).await?;
-
-                    let mut results = Vec::new();
+                    let graph = infer_tasks(
+                        master.clone(),
+                        &mut input_tasks,
+                        parser.as_ref(),
+                        &args.fallback,
+                        !args.per_line,
+                    )
+                    .await;
 
-                    send_tasks(master.clone(), &mut tasks, &mut results).await;
+                    let results = send_tasks(master.clone(), graph, parser.as_ref(), args.max_corrections).await;
 
                     println!("--- Results ---");
-                    for (input, res) in results {
-                        println!("input: {input}\nresult: {res}");
+                    for res in results {
+                        println!("input: {}\nresult: {}", res.input, res.status);
+                        for (i, attempt) in res.attempts.iter().enumerate() {
+                            println!(
+                                "  attempt {}: task: {:?}, agent: {}, result: {}",
+                                i + 1,
+                                attempt.task,
+                                attempt.agent,
+                                attempt.status
+                            );
+                        }
+                    }
+                }
+                "ask" => {
+                    println!("--- Question --- ");
+                    print!("> ");
+                    std::io::stdout().flush()?;
+
+                    line.clear();
+                    std::io::stdin().read_line(&mut line)?;
+                    let input = line.trim().to_string();
+                    if input.is_empty() {
+                        line.clear();
+                        continue;
+                    }
+
+                    let llama = match &llama {
+                        Some(llama) => llama,
+                        None => {
+                            println!("answering questions requires the llama parser");
+                            line.clear();
+                            continue;
+                        }
+                    };
+
+                    let agents: Vec<ConnectedAgent> = match master.match_agents(&input).await {
+                        Ok(candidates) => candidates.into_iter().map(|candidate| candidate.agent).collect(),
+                        Err(e) => {
+                            println!("failed to answer question: {e}");
+                            line.clear();
+                            continue;
+                        }
+                    };
+                    match llama.run(&master, &input, &agents, args.max_steps).await {
+                        Ok(outcome) => {
+                            println!("--- Steps ---");
+                            for (i, step) in outcome.steps.iter().enumerate() {
+                                println!(
+                                    "{}. thought: {}\n   action: {}, agent: {}\n   observation: {}",
+                                    i + 1,
+                                    step.thought,
+                                    step.action,
+                                    step.agent,
+                                    step.observation
+                                );
+                            }
+                            println!("--- Answer ---");
+                            match outcome.answer {
+                                Some(answer) => println!("{answer}"),
+                                None => println!("(no answer. the model gave up or ran out of steps)"),
+                            }
+                        }
+                        Err(e) => {
+                            println!("failed to answer question: {e}");
+                        }
                     }
                 }
                 "agents" => {
-                    let agents = master.list_agents();
                     println!("--- Agents ---");
-                    for agent in agents {
-                        println!("- {}({})", agent.name(), agent.description());
+                    for pool in master.list_pools() {
+                        let description = pool.representative().map(|agent| agent.description()).unwrap_or_default();
+                        println!("- {}({})", pool.name(), description);
+                        for agent in pool.replicas() {
+                            let status = match agent.is_healthy() {
+                                true => "healthy".to_string(),
+                                false => format!("unhealthy, last seen {}s ago", agent.last_seen().elapsed().as_secs()),
+                            };
+                            println!(
+                                "    - {} at {} [{}, {} in flight]",
+                                agent.name(),
+                                agent.addr(),
+                                status,
+                                agent.outstanding()
+                            );
+                        }
                     }
                 }
                 "exit" => {
@@ -114,6 +461,7 @@ impl MasterCli {
                     println!("--- Help ---");
                     println!("Available commands: ");
                     println!("\t- input: Enter a list of tasks");
+                    println!("\t- ask: Answer a question that may need several tools");
                     println!("\t- agents: List connected agents");
                     println!("\t- help: Prints this message");
                     println!("\t- exit: Exits the process")
@@ -126,84 +474,309 @@ impl MasterCli {
 }
 
 async fn infer_tasks(
-    master: Arc<MasterAgent<ToolDB>>,
-    input_tasks: &mut VecDeque<String>,
-    tasks: &mut VecDeque<(String, TaskRequest, Option<ConnectedAgent>)>,
-    parser: &LlamaParser,
-) -> color_eyre::Result<()> {
-    while let Some(input) = input_tasks.pop_front() {
-        match master.match_agent(&input).await {
-            Ok(agent) => match parser.parse(&input, &[agent.clone()]) {
-                Ok(task) => tasks.push_back((input, task, Some(agent))),
-                Err(e) => tasks.push_back((
+    master: Arc<MasterAgent<Box<dyn AgentMatcher + Send + Sync>>>,
+    input_tasks: &mut Vec<String>,
+    parser: &dyn TaskParser,
+    fallback: &Fallback,
+    batch: bool,
+) -> TaskGraph {
+    // routing rules and the matcher decide which agents each input may go to, before any parser
+    // sees it
+    let mut matched = Vec::with_capacity(input_tasks.len());
+    for input in input_tasks.iter() {
+        matched.push(master.match_agents(input).await);
+    }
+
+    let mut planned = vec![Vec::new(); input_tasks.len()];
+    let routed: Vec<usize> = (0..input_tasks.len()).filter(|&i| matched[i].is_ok()).collect();
+    if batch && routed.len() > 1 {
+        let inputs: Vec<String> = routed.iter().map(|&i| input_tasks[i].clone()).collect();
+        let mut agents: Vec<ConnectedAgent> = Vec::new();
+        for candidate in matched.iter().flatten().flatten() {
+            if !agents.iter().any(|agent| agent.pool() == candidate.agent.pool()) {
+                agents.push(candidate.agent.clone());
+            }
+        }
+        match parser.plan(&inputs, &agents).await {
+            Ok(tasks) => {
+                for (i, tasks) in routed.into_iter().zip(tasks) {
+                    planned[i] = tasks;
+                }
+            }
+            Err(e) => tracing::warn!("failed to plan tasks, parsing them one by one: {}", e),
+        }
+    }
+
+    let mut input_nodes = Vec::new();
+    for ((input, matched), tasks) in input_tasks.drain(..).zip(matched).zip(planned) {
+        input_nodes.push(Vec::new());
+        let nodes: &mut Vec<TaskNode> = input_nodes.last_mut().unwrap();
+        let candidates = match matched {
+            Ok(candidates) => candidates,
+            Err(e @ MatchError::NoMatch(_)) => {
+                nodes.extend(apply_fallback(&master, parser, fallback, input, e.to_string()).await);
+                continue;
+            }
+            Err(e @ MatchError::Blocked { .. }) => {
+                nodes.push(TaskNode::failed(input, e.to_string()));
+                continue;
+            }
+            Err(e) => {
+                nodes.push(TaskNode::failed(
                     input,
-                    TaskRequest {
-                        instruction: "fail".to_string(),
-                        args: vec![e.to_string()],
-                    },
-                    None,
-                )),
-            },
-            Err(e) => tasks.push_back((
-                input,
-                TaskRequest {
-                    instruction: "fail".to_string(),
-                    args: vec![e.to_string()],
-                },
-                None,
-            )),
+                    format!("failed to find a matching agent: {}", e),
+                ));
+                continue;
+            }
+        };
+        let routing: Vec<AgentMatch> = candidates
+            .iter()
+            .map(|candidate| AgentMatch {
+                name: candidate.agent.pool().to_string(),
+                score: candidate.score,
+            })
+            .collect();
+        let rule = candidates.iter().find_map(|candidate| candidate.rule.clone());
+        let agents: Vec<_> = candidates.into_iter().map(|candidate| candidate.agent).collect();
+
+        if !tasks.is_empty() {
+            match canonicalize_all(&master, tasks, &agents) {
+                Ok(tasks) => {
+                    for (task, agent) in tasks {
+                        nodes.push(TaskNode::new(input.clone(), task, agent).with_routing(routing.clone(), rule.clone()));
+                    }
+                    continue;
+                }
+                Err(e) => tracing::warn!("discarding planned tasks for {:?}: {}", input, e),
+            }
+        }
+
+        let parsed = match parser.parse(&input, &agents).await {
+            Ok(tasks) if !tasks.is_empty() => canonicalize_all(&master, tasks, &agents).map_err(|e| e.to_string()),
+            Ok(_) | Err(ParseError::NoTool) => {
+                let reason = "none of the matched agents can handle the input".to_string();
+                let fallback_nodes = apply_fallback(&master, parser, fallback, input, reason).await;
+                nodes.extend(fallback_nodes.into_iter().map(|node| match node.agent {
+                    Some(_) => node,
+                    None => node.with_routing(routing.clone(), rule.clone()),
+                }));
+                continue;
+            }
+            Err(e) => Err(e.to_string()),
+        };
+        match parsed {
+            Ok(tasks) => {
+                for (task, agent) in tasks {
+                    nodes.push(TaskNode::new(input.clone(), task, agent).with_routing(routing.clone(), rule.clone()));
+                }
+            }
+            Err(e) => nodes.push(
+                TaskNode::failed(input, format!("failed to parse given input into a task: {}", e)).with_routing(routing, rule),
+            ),
         }
     }
 
+    let graph = TaskGraph::from_inputs(input_nodes);
+
     println!("--- Tasks ---");
-    for (i, (input, task, agent)) in tasks.iter().enumerate() {
-        if task.instruction == "fail" {
-            println!("{}. input: {} task: (skipping task. failed to parse given input into a task), agent: none", i+1, input);
-        } else if agent.is_none() {
-            println!(
-                "{}. input: {} task: (skipping task. failed to find a matching agent) agent: none",
-                i + 1,
-                input
-            );
-        } else {
-            println!(
-                "{}. input: {} task: {:?}, agent: {}",
+    for (i, node) in graph.nodes().iter().enumerate() {
+        let deps = graph.dependencies(i);
+        let line = match (&node.task, &node.agent, &node.error) {
+            _ if node.settled.is_some() => {
+                let outcome = match &node.settled {
+                    Some(NodeStatus::Answered(_)) => "no tool applies. answering directly",
+                    _ => "no tool applies",
+                };
+                format!("{}. input: {} task: ({}), agent: none", i + 1, node.input, outcome)
+            }
+            (_, _, Some(err)) => {
+                format!("{}. input: {} task: (skipping task. {}), agent: none", i + 1, node.input, err)
+            }
+            (Some(task), Some(agent), None) if deps.is_empty() => {
+                format!("{}. input: {} task: {:?}, agent: {}", i + 1, node.input, task, agent)
+            }
+            (Some(task), Some(agent), None) => format!(
+                "{}. input: {} task: {:?}, agent: {}, after: {:?}",
                 i + 1,
-                input,
+                node.input,
                 task,
-                agent.as_ref().unwrap().name()
-            );
+                agent,
+                deps.iter().map(|dep| dep + 1).collect::<Vec<_>>()
+            ),
+            _ => format!("{}. input: {} task: (skipping task. failed to parse given input into a task), agent: none", i + 1, node.input),
+        };
+        match (node.routing.is_empty(), &node.rule) {
+            (true, _) => println!("{}", line),
+            (false, None) => println!("{}\n   matched: {}", line, format_routing(&node.routing)),
+            (false, Some(rule)) => println!("{}\n   matched: {} by rule {}", line, format_routing(&node.routing), rule),
+        }
+    }
+
+    graph
+}
+
+/// Handles an input no agent applies to according to `fallback`. `reason` says why no agent
+/// applies.
+async fn apply_fallback(
+    master: &MasterAgent<Box<dyn AgentMatcher + Send + Sync>>,
+    parser: &dyn TaskParser,
+    fallback: &Fallback,
+    input: String,
+    reason: String,
+) -> Vec<TaskNode> {
+    tracing::info!("no tool applies to input {:?} ({}). falling back to {:?}", input, reason, fallback);
+    match fallback {
+        Fallback::Rephrase => vec![TaskNode::no_tool(input, format!("{}. please rephrase it", reason))],
+        Fallback::Answer => match parser.answer(&input).await {
+            Ok(answer) => vec![TaskNode::answered(input, answer)],
+            Err(e) => vec![TaskNode::failed(input, format!("{}. failed to answer it directly: {}", reason, e))],
+        },
+        Fallback::Agent(pool) => {
+            let agent = match master.fallback_agent(pool) {
+                Ok(candidate) => candidate.agent,
+                Err(e) => return vec![TaskNode::failed(input, format!("{}. {}", reason, e))],
+            };
+            let agents = [agent];
+            match parser.parse(&input, &agents).await {
+                Ok(tasks) if !tasks.is_empty() => match canonicalize_all(master, tasks, &agents) {
+                    Ok(tasks) => tasks
+                        .into_iter()
+                        .map(|(task, agent)| TaskNode::new(input.clone(), task, agent))
+                        .collect(),
+                    Err(e) => vec![TaskNode::failed(input, format!("failed to parse given input into a task: {}", e))],
+                },
+                Ok(_) | Err(ParseError::NoTool) => vec![TaskNode::no_tool(
+                    input,
+                    format!("{} and neither can the fallback agent {}. please rephrase it", reason, pool),
+                )],
+                Err(e) => vec![TaskNode::failed(input, format!("failed to parse given input into a task: {}", e))],
+            }
         }
     }
+}
+
+/// Lists the matched pools with their scores, e.g. `calculator (0.82), wiki (0.41)`.
+fn format_routing(routing: &[AgentMatch]) -> String {
+    routing
+        .iter()
+        .map(|m| format!("{} ({:.2})", m.name, m.score))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
 
-    Ok(())
+/// Canonicalizes parsed tasks against the agents the parser was offered and pairs each with the
+/// pool it goes to.
+fn canonicalize_all(
+    master: &MasterAgent<Box<dyn AgentMatcher + Send + Sync>>,
+    tasks: Vec<TaskRequest>,
+    agents: &[ConnectedAgent],
+) -> Result<Vec<(TaskRequest, String)>, CanonicalizeError> {
+    tasks
+        .into_iter()
+        .map(|task| master.canonicalize(task, agents).map(|(task, agent)| (task, agent.pool().to_string())))
+        .collect()
 }
 
 async fn send_tasks(
-    master: Arc<MasterAgent<ToolDB>>,
-    tasks: &mut VecDeque<(String, TaskRequest, Option<ConnectedAgent>)>,
-    results: &mut Vec<(String, String)>,
-) {
-    while let Some((input, task, agent)) = tasks.pop_back() {
-        match task.instruction.as_str() {
-            "fail" => results.push((input, "failed to complete task".to_string())),
-            _ => match agent {
-                Some(agent) => {
-                    let res = master.send_task_to_agent(agent.name(), task).await;
-                    match res {
-                        Ok(result) => match result.status() {
-                            Status::Success => results.push((input, result.response)),
-                            Status::Failure => results.push((input, result.response)),
-                        },
-                        Err(_) => {
-                            results.push((input, "failed to send task to agent".to_string()));
-                        }
+    master: Arc<MasterAgent<Box<dyn AgentMatcher + Send + Sync>>>,
+    graph: TaskGraph,
+    parser: &dyn TaskParser,
+    max_corrections: usize,
+) -> Vec<NodeResult> {
+    let master = &master;
+    graph
+        .execute_with_corrections(
+            |agent, task| async move { master.send_task_to_agent(&agent, task).await },
+            |input, task, status| async move {
+                let agents: Vec<ConnectedAgent> = match master.match_agents(&input).await {
+                    Ok(candidates) => candidates.into_iter().map(|candidate| candidate.agent).collect(),
+                    Err(e) => {
+                        tracing::warn!("failed to correct task: {}", e);
+                        return None;
+                    }
+                };
+                let corrected = match parser.correct(&input, &agents, &task, &status.to_string()).await {
+                    Ok(Some(corrected)) => corrected,
+                    Ok(None) => {
+                        tracing::warn!("parser has no correction for {:?}", task);
+                        return None;
+                    }
+                    Err(e) => {
+                        tracing::warn!("failed to correct task: {}", e);
+                        return None;
+                    }
+                };
+                match master.canonicalize(corrected, &agents) {
+                    Ok((task, agent)) => Some((task, agent.pool().to_string())),
+                    Err(e) => {
+                        tracing::warn!("failed to correct task: {}", e);
+                        None
                     }
-                }
-                None => {
-                    results.push((input, "failed to find agent to complete task".to_string()));
                 }
             },
-        }
+            max_corrections,
+        )
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::SocketAddr, sync::Arc};
+
+    use meeseeks::{
+        bm25::Bm25Matcher,
+        common::AgentMatcher,
+        master::{Fallback, MasterAgent},
+        meeseeks_proto::{master_agent_server::MasterAgent as _, AgentConnectRequest},
+        parser::rules::RuleParser,
+        routing::RoutingRules,
+    };
+    use tonic::Request;
+
+    use super::infer_tasks;
+
+    #[tokio::test]
+    pub async fn test_blocked_inputs_are_not_planned() {
+        let rules = RoutingRules::from_toml(
+            r#"
+[[rules]]
+name = "credentials"
+keyword = "password"
+block = "inputs about credentials are not allowed"
+"#,
+        )
+        .unwrap();
+        let matcher: Box<dyn AgentMatcher + Send + Sync> = Box::new(Bm25Matcher::default());
+        let master = Arc::new(
+            MasterAgent::new("master".to_string(), SocketAddr::from(([127, 0, 0, 1], 0)), matcher)
+                .with_routing_rules(rules),
+        );
+        master
+            .connect_to_master(Request::new(AgentConnectRequest {
+                name: "calculator".to_string(),
+                from: "http://127.0.0.1:9001".to_string(),
+                description: "evaluates mathematical expressions".to_string(),
+                commands: vec!["calculate(expression)".to_string()],
+                ..Default::default()
+            }))
+            .await
+            .unwrap();
+        let parser = RuleParser::from_toml("[[rules]]\npattern = '^calculate (?P<expression>.+)$'\ncommand = \"calculate\"\n").unwrap();
+
+        let mut inputs = vec![
+            "calculate 1 + 1".to_string(),
+            "calculate the length of my password".to_string(),
+        ];
+        let graph = infer_tasks(master, &mut inputs, &parser, &Fallback::Rephrase, true).await;
+
+        let nodes = graph.nodes();
+        assert_eq!(nodes.len(), 2);
+        assert_eq!(nodes[0].task.as_ref().unwrap().instruction, "calculate");
+        assert_eq!(nodes[0].agent.as_deref(), Some("calculator"));
+        assert!(nodes[1].task.is_none(), "blocked inputs are never planned");
+        assert_eq!(
+            nodes[1].error.as_deref(),
+            Some("blocked by routing rule credentials: inputs about credentials are not allowed")
+        );
     }
 }
@@ -0,0 +1,231 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::RwLock,
+};
+
+use crate::common::{AgentMatch, AgentMatcher, ConnectedAgent};
+
+#[derive(Debug, Default)]
+struct Document {
+    terms: HashMap<String, usize>,
+    len: usize,
+}
+
+#[derive(Debug, Default)]
+struct Index {
+    /// One document per pool.
+    documents: HashMap<String, Document>,
+    /// Number of documents each term occurs in.
+    document_frequency: HashMap<String, usize>,
+}
+
+impl Index {
+    fn insert(&mut self, pool: String, document: Document) {
+        self.remove(&pool);
+        for term in document.terms.keys() {
+            *self.document_frequency.entry(term.clone()).or_default() += 1;
+        }
+        self.documents.insert(pool, document);
+    }
+
+    fn remove(&mut self, pool: &str) {
+        let document = match self.documents.remove(pool) {
+            Some(document) => document,
+            None => return,
+        };
+        for term in document.terms.keys() {
+            if let Some(n) = self.document_frequency.get_mut(term) {
+                *n -= 1;
+                if *n == 0 {
+                    self.document_frequency.remove(term);
+                }
+            }
+        }
+    }
+
+    fn idf(&self, term: &str) -> f32 {
+        let n = self.documents.len() as f32;
+        let df = self.document_frequency.get(term).copied().unwrap_or_default() as f32;
+        ((n - df + 0.5) / (df + 0.5) + 1.0).ln()
+    }
+}
+
+/// Matches inputs to agents with BM25 over the words of each pool's name, description, commands
+/// and examples. Needs no model or service and always ranks the same way for the same agents, which
+/// makes it a good fit for small sets of tools and for tests.
+///
+/// Scores are normalized to 0..1 by the score a document containing every word of the input would
+/// reach, so they can be compared to the master's minimum match score.
+#[derive(Debug)]
+pub struct Bm25Matcher {
+    k1: f32,
+    b: f32,
+    index: RwLock<Index>,
+}
+
+impl Default for Bm25Matcher {
+    fn default() -> Self {
+        Self::new(1.2, 0.75)
+    }
+}
+
+impl Bm25Matcher {
+    /// `k1` controls how quickly repeated words stop adding to the score, `b` how much longer
+    /// documents are penalized.
+    pub fn new(k1: f32, b: f32) -> Self {
+        Bm25Matcher {
+            k1,
+            b,
+            index: RwLock::new(Index::default()),
+        }
+    }
+
+    fn document(agent: &ConnectedAgent) -> Document {
+        let mut document = Document::default();
+        let texts = [agent.pool.as_str(), agent.name.as_str(), agent.description.as_str(), agent.examples.as_str()];
+        for term in texts
+            .into_iter()
+            .chain(agent.commands.iter().map(String::as_str))
+            .flat_map(tokenize)
+        {
+            *document.terms.entry(term).or_default() += 1;
+            document.len += 1;
+        }
+        document
+    }
+
+    fn score(&self, index: &Index, query: &BTreeMap<String, usize>, document: &Document) -> f32 {
+        let avg_len = index.documents.values().map(|d| d.len).sum::<usize>() as f32 / index.documents.len().max(1) as f32;
+        let norm = self.k1 * (1.0 - self.b + self.b * document.len as f32 / avg_len.max(1.0));
+
+        let mut score = 0.0;
+        let mut best = 0.0;
+        for (term, count) in query {
+            let weight = index.idf(term) * *count as f32;
+            best += weight * (self.k1 + 1.0);
+            if let Some(&tf) = document.terms.get(term) {
+                let tf = tf as f32;
+                score += weight * tf * (self.k1 + 1.0) / (tf + norm);
+            }
+        }
+
+        match best > 0.0 {
+            true => (score / best).min(1.0),
+            false => 0.0,
+        }
+    }
+}
+
+/// Lowercase words of `text`.
+fn tokenize(text: &str) -> impl Iterator<Item = String> + '_ {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(str::to_lowercase)
+}
+
+#[tonic::async_trait]
+impl AgentMatcher for Bm25Matcher {
+    async fn match_agents(&self, task: &str, limit: usize) -> Result<Vec<AgentMatch>, Box<dyn std::error::Error>> {
+        // sorted, so that the scores are summed up in the same order every time
+        let mut query: BTreeMap<String, usize> = BTreeMap::new();
+        for term in tokenize(task) {
+            *query.entry(term).or_default() += 1;
+        }
+
+        let index = self.index.read().unwrap();
+        let mut matches: Vec<AgentMatch> = index
+            .documents
+            .iter()
+            .map(|(pool, document)| AgentMatch {
+                name: pool.clone(),
+                score: self.score(&index, &query, document),
+            })
+            .filter(|m| m.score > 0.0)
+            .collect();
+        matches.sort_by(|a, b| b.score.total_cmp(&a.score).then_with(|| a.name.cmp(&b.name)));
+        matches.truncate(limit);
+        tracing::debug!("matched {:?} to {:?}", task, matches);
+
+        Ok(matches)
+    }
+
+    async fn add_agent(&self, agent: ConnectedAgent) -> Result<(), Box<dyn std::error::Error>> {
+        let document = Self::document(&agent);
+        self.index.write().unwrap().insert(agent.pool, document);
+        Ok(())
+    }
+
+    async fn remove_agent(&self, name: &str) -> Result<(), Box<dyn std::error::Error>> {
+        self.index.write().unwrap().remove(name);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::common::{AgentMatcher, ConnectedAgent};
+
+    use super::Bm25Matcher;
+
+    fn agent(name: &str, description: &str, commands: &[&str], examples: &str) -> ConnectedAgent {
+        ConnectedAgent {
+            description: description.to_string(),
+            examples: examples.to_string(),
+            ..ConnectedAgent::for_test(name, commands)
+        }
+    }
+
+    #[tokio::test]
+    pub async fn test_bm25_matcher() {
+        let matcher = Bm25Matcher::default();
+        matcher
+            .add_agent(agent(
+                "calculator",
+                "evaluates mathematical expressions",
+                &["calculate(expression)"],
+                "Input: what is 999 - 1?\nAction: calculate[999 - 1]",
+            ))
+            .await
+            .unwrap();
+        matcher
+            .add_agent(agent(
+                "tweetu",
+                "writes tweets",
+                &["tweet(topic)"],
+                "Input: write a tweet about coffee\nAction: tweet[coffee]",
+            ))
+            .await
+            .unwrap();
+        matcher
+            .add_agent(agent(
+                "wiki",
+                "looks things up on wikipedia",
+                &["summary(topic)", "search(query)"],
+                "Input: write a summary of Rust\nAction: summary[Rust]",
+            ))
+            .await
+            .unwrap();
+
+        let matches = matcher.match_agents("write a tweet about the rust release", 3).await.unwrap();
+        assert_eq!(matches[0].name, "tweetu");
+        assert!(matches.windows(2).all(|pair| pair[0].score >= pair[1].score));
+        assert!(matches.iter().all(|m| m.score > 0.0 && m.score <= 1.0));
+        assert_eq!(matcher.match_agents("calculate 12 * 7", 3).await.unwrap()[0].name, "calculator");
+        assert_eq!(matcher.match_agents("search wikipedia for Paris", 1).await.unwrap()[0].name, "wiki");
+        assert!(matcher.match_agents("book flights", 3).await.unwrap().is_empty());
+
+        let again = matcher.match_agents("write a tweet about the rust release", 3).await.unwrap();
+        assert_eq!(matches, again, "matching is deterministic");
+
+        matcher.remove_agent("tweetu").await.unwrap();
+        let matches = matcher.match_agents("write a tweet about the rust release", 3).await.unwrap();
+        assert!(matches.iter().all(|m| m.name != "tweetu"));
+        assert_eq!(matches[0].name, "wiki");
+
+        // registering again replaces the document instead of adding to it
+        let calc = agent("calculator", "", &["calculate(expression)"], "");
+        matcher.add_agent(calc.clone()).await.unwrap();
+        matcher.add_agent(calc).await.unwrap();
+        assert_eq!(matcher.index.read().unwrap().document_frequency["calculate"], 1);
+    }
+}
@@ -0,0 +1,195 @@
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// Controls when the master stops routing tasks to an agent that keeps failing.
+#[derive(Debug, Clone)]
+pub struct BreakerConfig {
+    /// Consecutive failures after which the circuit opens.
+    pub failure_threshold: u32,
+    /// How long an open circuit rejects tasks before a single trial task is let through.
+    pub cooldown: Duration,
+}
+
+impl Default for BreakerConfig {
+    fn default() -> Self {
+        BreakerConfig {
+            failure_threshold: 5,
+            cooldown: Duration::from_secs(30),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BreakerState {
+    /// Tasks are routed to the agent.
+    Closed,
+    /// The agent failed too often. Tasks are rejected until the cooldown has passed.
+    Open,
+    /// The cooldown has passed and one trial task is in flight. Its outcome closes or reopens the
+    /// circuit.
+    HalfOpen,
+}
+
+#[derive(Debug)]
+struct Inner {
+    state: BreakerState,
+    failures: u32,
+    /// When the circuit last opened. Only meaningful while it is open.
+    opened_at: Instant,
+}
+
+/// A circuit breaker for a single agent.
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    config: BreakerConfig,
+    inner: Mutex<Inner>,
+}
+
+impl Default for CircuitBreaker {
+    fn default() -> Self {
+        CircuitBreaker::new(BreakerConfig::default())
+    }
+}
+
+impl CircuitBreaker {
+    pub fn new(config: BreakerConfig) -> Self {
+        CircuitBreaker {
+            config,
+            inner: Mutex::new(Inner {
+                state: BreakerState::Closed,
+                failures: 0,
+                opened_at: Instant::now(),
+            }),
+        }
+    }
+
+    pub fn state(&self) -> BreakerState {
+        self.inner.lock().unwrap().state
+    }
+
+    /// Whether a task may be sent right now, without changing the state.
+    pub fn allows(&self) -> bool {
+        let inner = self.inner.lock().unwrap();
+        match inner.state {
+            BreakerState::Closed => true,
+            BreakerState::Open => inner.opened_at.elapsed() >= self.config.cooldown,
+            BreakerState::HalfOpen => false,
+        }
+    }
+
+    /// Asks to send a task. An open circuit whose cooldown has passed lets this one task through
+    /// and becomes half-open. The outcome of the task is recorded through the returned permit, and
+    /// a permit dropped before that, e.g. because the call was cancelled, counts as a failure so
+    /// that a half-open circuit never waits for a trial that is gone.
+    pub fn try_acquire(&self) -> Option<Permit<'_>> {
+        let mut inner = self.inner.lock().unwrap();
+        match inner.state {
+            BreakerState::Closed => {}
+            BreakerState::Open if inner.opened_at.elapsed() >= self.config.cooldown => {
+                tracing::info!("circuit half-open. sending a trial task");
+                inner.state = BreakerState::HalfOpen;
+            }
+            BreakerState::Open | BreakerState::HalfOpen => return None,
+        }
+        Some(Permit {
+            breaker: self,
+            completed: false,
+        })
+    }
+
+    fn record_success(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.state != BreakerState::Closed {
+            tracing::info!("circuit closed");
+        }
+        inner.state = BreakerState::Closed;
+        inner.failures = 0;
+    }
+
+    fn record_failure(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.failures += 1;
+        if inner.state == BreakerState::HalfOpen || inner.failures >= self.config.failure_threshold {
+            if inner.state != BreakerState::Open {
+                tracing::warn!("circuit opened after {} consecutive failures", inner.failures);
+            }
+            inner.state = BreakerState::Open;
+            inner.opened_at = Instant::now();
+        }
+    }
+}
+
+/// Permission to send one task through a circuit breaker. Records a failure when dropped unless
+/// the outcome of the task was recorded with `success` or `failure`.
+#[derive(Debug)]
+pub struct Permit<'a> {
+    breaker: &'a CircuitBreaker,
+    completed: bool,
+}
+
+impl Permit<'_> {
+    pub fn success(mut self) {
+        self.completed = true;
+        self.breaker.record_success();
+    }
+
+    pub fn failure(mut self) {
+        self.completed = true;
+        self.breaker.record_failure();
+    }
+}
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        if !self.completed {
+            self.breaker.record_failure();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{BreakerConfig, BreakerState, CircuitBreaker};
+
+    #[test]
+    pub fn test_circuit_breaker() {
+        let breaker = CircuitBreaker::new(BreakerConfig {
+            failure_threshold: 2,
+            cooldown: Duration::ZERO,
+        });
+
+        breaker.record_failure();
+        assert_eq!(breaker.state(), BreakerState::Closed);
+        breaker.record_failure();
+        assert_eq!(breaker.state(), BreakerState::Open);
+
+        let trial = breaker.try_acquire().unwrap();
+        assert_eq!(breaker.state(), BreakerState::HalfOpen);
+        assert!(breaker.try_acquire().is_none());
+        trial.failure();
+        assert_eq!(breaker.state(), BreakerState::Open);
+
+        breaker.try_acquire().unwrap().success();
+        assert_eq!(breaker.state(), BreakerState::Closed);
+
+        // a trial that is dropped without an outcome, e.g. because its call was cancelled, reopens
+        // the circuit instead of leaving it half-open forever
+        breaker.record_failure();
+        breaker.record_failure();
+        drop(breaker.try_acquire().unwrap());
+        assert_eq!(breaker.state(), BreakerState::Open);
+        assert!(breaker.try_acquire().is_some());
+
+        let breaker = CircuitBreaker::new(BreakerConfig {
+            failure_threshold: 1,
+            cooldown: Duration::from_secs(60),
+        });
+        breaker.record_failure();
+        assert!(!breaker.allows());
+        assert!(breaker.try_acquire().is_none());
+    }
+}
@@ -0,0 +1,219 @@
+use crate::common::ConnectedAgent;
+
+#[derive(Debug, Clone, PartialEq, thiserror::Error)]
+pub enum CanonicalizeError {
+    #[error("no agent advertises any commands")]
+    NoCommands,
+
+    #[error("{instruction} is not a known command. the closest is {closest} with confidence {confidence:.2}")]
+    LowConfidence {
+        instruction: String,
+        closest: String,
+        confidence: f64,
+    },
+
+    #[error("{instruction} is ambiguous. it could be any of {candidates:?}")]
+    Ambiguous {
+        instruction: String,
+        candidates: Vec<String>,
+    },
+}
+
+/// A command an instruction was mapped to.
+#[derive(Clone)]
+pub struct Canonical<'a> {
+    pub command: String,
+    pub agent: &'a ConnectedAgent,
+    /// 1.0 for an exact match, lower the more the instruction had to be changed.
+    pub confidence: f64,
+}
+
+/// Maps instructions chosen by a parser onto the commands the agents advertised. An instruction
+/// matches a command exactly, after normalization (case and punctuation are ignored), through an
+/// alias declared in the command's schema, or by edit distance.
+#[derive(Debug, Clone)]
+pub struct Canonicalizer {
+    /// Matches below this confidence are rejected.
+    pub min_confidence: f64,
+}
+
+impl Default for Canonicalizer {
+    fn default() -> Self {
+        Canonicalizer { min_confidence: 0.75 }
+    }
+}
+
+impl Canonicalizer {
+    pub fn new(min_confidence: f64) -> Self {
+        Canonicalizer { min_confidence }
+    }
+
+    /// Maps `instruction` onto a command of `agents`. If several agents advertise the command, the
+    /// one that comes first in `agents` wins.
+    pub fn canonicalize<'a>(
+        &self,
+        instruction: &str,
+        agents: &'a [ConnectedAgent],
+    ) -> Result<Canonical<'a>, CanonicalizeError> {
+        let normalized = normalize(instruction);
+
+        let mut best: Vec<Canonical<'a>> = Vec::new();
+        for agent in agents {
+            for schema in agent.schemas() {
+                let confidence = std::iter::once(&schema.name)
+                    .chain(&schema.aliases)
+                    .map(|name| match name == instruction {
+                        true => 1.0,
+                        false => similarity(&normalized, &normalize(name)),
+                    })
+                    .fold(0.0, f64::max);
+
+                match best.first() {
+                    Some(current) if confidence < current.confidence => continue,
+                    Some(current) if confidence > current.confidence => best.clear(),
+                    _ => {}
+                }
+                if !best.iter().any(|candidate| candidate.command == schema.name) {
+                    best.push(Canonical {
+                        command: schema.name.clone(),
+                        agent,
+                        confidence,
+                    });
+                }
+            }
+        }
+
+        let mut best = best.into_iter();
+        let canonical = best.next().ok_or(CanonicalizeError::NoCommands)?;
+        if canonical.confidence < self.min_confidence {
+            return Err(CanonicalizeError::LowConfidence {
+                instruction: instruction.to_string(),
+                closest: canonical.command,
+                confidence: canonical.confidence,
+            });
+        }
+
+        let others: Vec<String> = best.map(|candidate| candidate.command).collect();
+        if !others.is_empty() {
+            return Err(CanonicalizeError::Ambiguous {
+                instruction: instruction.to_string(),
+                candidates: std::iter::once(canonical.command).chain(others).collect(),
+            });
+        }
+
+        Ok(canonical)
+    }
+}
+
+/// Lowercases `name` and drops everything but letters and digits, so that `Tweet`, `tweet()` and
+/// `tw-eet` compare equal.
+fn normalize(name: &str) -> String {
+    name.chars()
+        .filter(|c| c.is_alphanumeric())
+        .flat_map(char::to_lowercase)
+        .collect()
+}
+
+/// Similarity of two normalized names between 0.0 and 1.0, based on their edit distance.
+fn similarity(a: &str, b: &str) -> f64 {
+    if a == b {
+        // only differed before normalization
+        return 0.95;
+    }
+
+    let len = a.chars().count().max(b.chars().count());
+    if len == 0 {
+        return 0.0;
+    }
+    // an edit is never as certain as a match
+    (1.0 - levenshtein(a, b) as f64 / len as f64).min(0.9)
+}
+
+fn levenshtein(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut prev: Vec<usize> = (0..=b.len()).collect();
+    let mut cur = vec![0; b.len() + 1];
+
+    for (i, ca) in a.chars().enumerate() {
+        cur[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = prev[j] + usize::from(ca != *cb);
+            cur[j + 1] = substitution.min(prev[j + 1] + 1).min(cur[j] + 1);
+        }
+        std::mem::swap(&mut prev, &mut cur);
+    }
+
+    prev[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        common::ConnectedAgent,
+        meeseeks_proto::{CommandSchema, ParameterSchema},
+    };
+
+    use super::{levenshtein, CanonicalizeError, Canonicalizer};
+
+    fn agent(name: &str, schemas: Vec<CommandSchema>) -> ConnectedAgent {
+        ConnectedAgent {
+            schemas,
+            ..ConnectedAgent::for_test(name, &[])
+        }
+    }
+
+    fn command(name: &str, aliases: &[&str]) -> CommandSchema {
+        CommandSchema {
+            name: name.to_string(),
+            description: String::new(),
+            parameters: vec![ParameterSchema::string("input", "", true)],
+            aliases: aliases.iter().map(|alias| alias.to_string()).collect(),
+        }
+    }
+
+    #[tokio::test]
+    pub async fn test_canonicalize_instructions() {
+        let agents = vec![
+            agent("tweetu", vec![command("tweet", &[])]),
+            agent("calc", vec![command("calculate", &["calc", "math"])]),
+            agent("wiki", vec![command("summary", &["summarize"]), command("search", &[])]),
+        ];
+        let canonicalizer = Canonicalizer::default();
+
+        for (instruction, command) in [
+            ("tweet", "tweet"),
+            ("Tweet", "tweet"),
+            ("twee", "tweet"),
+            ("tweeit", "tweet"),
+            ("tweeet", "tweet"),
+            ("calc", "calculate"),
+            ("summarise", "summary"),
+        ] {
+            let canonical = canonicalizer.canonicalize(instruction, &agents).unwrap();
+            assert_eq!(canonical.command, command, "{}", instruction);
+        }
+        assert_eq!(canonicalizer.canonicalize("calc", &agents).unwrap().agent.name(), "calc");
+        assert_eq!(canonicalizer.canonicalize("tweet", &agents).unwrap().confidence, 1.0);
+
+        assert!(matches!(
+            canonicalizer.canonicalize("book_flight", &agents),
+            Err(CanonicalizeError::LowConfidence { .. })
+        ));
+        assert!(matches!(canonicalizer.canonicalize("tweet", &[]), Err(CanonicalizeError::NoCommands)));
+
+        let replicas = vec![
+            agent("tweetu-b", vec![command("tweet", &[])]),
+            agent("tweetu-a", vec![command("tweet", &[])]),
+        ];
+        assert_eq!(canonicalizer.canonicalize("twee", &replicas).unwrap().agent.name(), "tweetu-b");
+        assert_eq!(canonicalizer.canonicalize("twee", &replicas[1..]).unwrap().agent.name(), "tweetu-a");
+    }
+
+    #[test]
+    pub fn test_levenshtein() {
+        assert_eq!(levenshtein("tweet", "tweet"), 0);
+        assert_eq!(levenshtein("twee", "tweet"), 1);
+        assert_eq!(levenshtein("kitten", "sitting"), 3);
+        assert_eq!(levenshtein("", "abc"), 3);
+    }
+}
@@ -1,16 +1,36 @@
+use std::{
+    str::FromStr,
+    sync::{atomic::{AtomicUsize, Ordering}, Arc},
+    time::{Instant, SystemTime},
+};
+
 use tonic::transport::Channel;
 
-use crate::meeseeks_proto::{TaskRequest, TaskResponse, agent_client::AgentClient};
+use crate::{
+    breaker::CircuitBreaker,
+    error::ParseError,
+    meeseeks_proto::{agent_client::AgentClient, AgentConnectRequest, CommandSchema, TaskRequest, TaskResponse},
+    schema::schemas_from_commands,
+};
 
 
 #[derive(Clone)]
 pub struct ConnectedAgent {
     pub(crate) name: String,
+    pub(crate) pool: String,
     pub(crate) description: String,
     pub(crate) addr: String,
-    pub(crate) client: Option<AgentClient<Channel>>,
+    pub(crate) client: AgentClient<Channel>,
     pub(crate) examples: String,
     pub(crate) commands: Vec<String>,
+    pub(crate) schemas: Vec<CommandSchema>,
+    pub(crate) last_seen: Instant,
+    /// When the agent last sent its registration.
+    pub(crate) registered_at: SystemTime,
+    pub(crate) healthy: bool,
+    /// Tasks sent to this agent that have not completed yet. Shared by all clones.
+    pub(crate) outstanding: Arc<AtomicUsize>,
+    pub(crate) breaker: Arc<CircuitBreaker>,
 }
 
 impl ConnectedAgent {
@@ -18,9 +38,92 @@ impl ConnectedAgent {
         self.name.as_ref()
     }
 
+    /// Name of the pool of replicas this agent belongs to. Tasks are routed to pools, not to
+    /// individual agents.
+    pub fn pool(&self) -> &str {
+        self.pool.as_ref()
+    }
+
+    pub fn addr(&self) -> &str {
+        self.addr.as_ref()
+    }
+
     pub fn description(&self) -> &str {
         self.description.as_ref()
     }
+
+    /// Names of the commands this agent advertises, e.g. `tweet` for `tweet(topic)`.
+    pub fn command_names(&self) -> impl Iterator<Item = &str> {
+        self.commands
+            .iter()
+            .map(|command| command.split(['(', '[']).next().unwrap_or_default().trim())
+    }
+
+    pub fn has_command(&self, name: &str) -> bool {
+        self.command_names().any(|command| command == name)
+    }
+
+    pub fn schemas(&self) -> &[CommandSchema] {
+        self.schemas.as_ref()
+    }
+
+    /// Whether the agent has sent a heartbeat recently enough to be routed to.
+    pub fn is_healthy(&self) -> bool {
+        self.healthy
+    }
+
+    pub fn last_seen(&self) -> Instant {
+        self.last_seen
+    }
+
+    pub fn registered_at(&self) -> SystemTime {
+        self.registered_at
+    }
+
+    /// The registration the agent connected with.
+    pub fn registration(&self) -> AgentConnectRequest {
+        AgentConnectRequest {
+            name: self.name.clone(),
+            from: self.addr.clone(),
+            description: self.description.clone(),
+            commands: self.commands.clone(),
+            examples: self.examples.clone(),
+            schemas: self.schemas.clone(),
+            tool: self.pool.clone(),
+        }
+    }
+
+    pub fn outstanding(&self) -> usize {
+        self.outstanding.load(Ordering::Relaxed)
+    }
+
+    pub fn breaker(&self) -> &CircuitBreaker {
+        &self.breaker
+    }
+}
+
+#[cfg(test)]
+impl ConnectedAgent {
+    /// A healthy agent for tests that is the only replica of the pool `name`, connected lazily to
+    /// an address nothing listens on. Its schemas are derived from `commands`.
+    pub(crate) fn for_test(name: &str, commands: &[&str]) -> ConnectedAgent {
+        let commands: Vec<String> = commands.iter().map(|command| command.to_string()).collect();
+        ConnectedAgent {
+            name: name.to_string(),
+            pool: name.to_string(),
+            description: String::new(),
+            addr: "http://127.0.0.1:9001".to_string(),
+            client: AgentClient::new(tonic::transport::Endpoint::from_static("http://127.0.0.1:9001").connect_lazy()),
+            examples: String::new(),
+            schemas: schemas_from_commands(&commands),
+            commands,
+            last_seen: Instant::now(),
+            registered_at: SystemTime::now(),
+            healthy: true,
+            outstanding: Arc::default(),
+            breaker: Arc::default(),
+        }
+    }
 }
 
 
@@ -29,9 +132,44 @@ impl ConnectedAgent {
 //     pub args: Vec<String>,
 // }
 
+/// Turns inputs written by a user into tasks for the connected agents.
 #[tonic::async_trait]
-pub trait TaskParser {
-    async fn parse(&self, input: &str, tasks: &mut Vec<TaskRequest>);
+pub trait TaskParser: Send + Sync {
+    /// Parses `input` into tasks for `agents`. Fails with [`ParseError::NoTool`] if none of the
+    /// agents can handle the input.
+    async fn parse(&self, input: &str, agents: &[ConnectedAgent]) -> Result<Vec<TaskRequest>, ParseError>;
+
+    /// Parses a batch of inputs and returns the tasks of every input, in input order. Inputs no
+    /// agent can handle get no tasks. Parsers that can do better than parsing the inputs one by one
+    /// override this.
+    async fn plan(&self, inputs: &[String], agents: &[ConnectedAgent]) -> Result<Vec<Vec<TaskRequest>>, ParseError> {
+        let mut planned = Vec::with_capacity(inputs.len());
+        for input in inputs {
+            match self.parse(input, agents).await {
+                Ok(tasks) => planned.push(tasks),
+                Err(ParseError::NoTool) => planned.push(Vec::new()),
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(planned)
+    }
+
+    /// Fixes `task`, which an agent rejected with `error`. Returns `None` if the parser has no
+    /// better task to offer.
+    async fn correct(
+        &self,
+        _input: &str,
+        _agents: &[ConnectedAgent],
+        _task: &TaskRequest,
+        _error: &str,
+    ) -> Result<Option<TaskRequest>, ParseError> {
+        Err(ParseError::Unsupported("correcting tasks"))
+    }
+
+    /// Answers `input` without any tool, for inputs none of the agents can handle.
+    async fn answer(&self, _input: &str) -> Result<String, ParseError> {
+        Err(ParseError::Unsupported("answering inputs directly"))
+    }
 }
 
 #[tonic::async_trait]
@@ -40,13 +178,73 @@ pub trait TaskExecutor {
 
     fn commands<'a>(&self) -> &'a[&'a str];
 
-    fn examples<'a>(&self) -> &'a str;
+    /// Example inputs and actions for the parser's prompt. Read again whenever the agent
+    /// registers with the master.
+    fn examples(&self) -> String;
+
+    /// Typed schemas of the commands this executor understands. Defaults to string parameters
+    /// derived from `commands`.
+    fn schemas(&self) -> Vec<CommandSchema> {
+        let commands: Vec<String> = self.commands().iter().map(|x| x.to_string()).collect();
+        schemas_from_commands(&commands)
+    }
+}
+
+/// A pool the matcher considers for a task.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AgentMatch {
+    pub name: String,
+    /// How similar the task is to what the pool advertises, from 0 (unrelated) to 1.
+    pub score: f32,
+}
+
+/// Which matcher the master uses to find the agents for an input.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MatcherKind {
+    /// The ToolDB service, see [`crate::tooldb::ToolDB`].
+    ToolDb,
+    /// Embeddings computed in process, see [`crate::embedding::EmbeddingMatcher`].
+    Embedding,
+    /// Word overlap ranked with BM25, see [`crate::bm25::Bm25Matcher`].
+    Bm25,
+}
+
+impl FromStr for MatcherKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "tooldb" => Ok(MatcherKind::ToolDb),
+            "embedding" => Ok(MatcherKind::Embedding),
+            "bm25" => Ok(MatcherKind::Bm25),
+            _ => Err(format!("unknown matcher {}. expected tooldb, embedding or bm25", s)),
+        }
+    }
 }
 
 #[tonic::async_trait]
 pub trait AgentMatcher {
 
-    async fn match_agent(&self, task: &str) -> Result<String, Box<dyn std::error::Error>>;
+    /// The pools most similar to `task`, best first. Returns at most `limit` of them. No pool
+    /// applying to the task is not an error: the list is empty then.
+    async fn match_agents(&self, task: &str, limit: usize) -> Result<Vec<AgentMatch>, Box<dyn std::error::Error>>;
 
     async fn add_agent(&self, agent: ConnectedAgent) -> Result<(), Box<dyn std::error::Error>>;
+
+    async fn remove_agent(&self, name: &str) -> Result<(), Box<dyn std::error::Error>>;
+}
+
+#[tonic::async_trait]
+impl<M: AgentMatcher + Send + Sync + ?Sized> AgentMatcher for Box<M> {
+    async fn match_agents(&self, task: &str, limit: usize) -> Result<Vec<AgentMatch>, Box<dyn std::error::Error>> {
+        (**self).match_agents(task, limit).await
+    }
+
+    async fn add_agent(&self, agent: ConnectedAgent) -> Result<(), Box<dyn std::error::Error>> {
+        (**self).add_agent(agent).await
+    }
+
+    async fn remove_agent(&self, name: &str) -> Result<(), Box<dyn std::error::Error>> {
+        (**self).remove_agent(name).await
+    }
 }
@@ -0,0 +1,331 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::PathBuf,
+    sync::{Arc, Mutex, RwLock},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::common::{AgentMatch, AgentMatcher, ConnectedAgent};
+
+/// Turns texts into vectors that point in similar directions for related texts.
+pub trait Embedder: Send + Sync {
+    /// Identifies the embedder, so that persisted vectors are only reused by the embedder that
+    /// computed them.
+    fn id(&self) -> String;
+
+    fn embed(&self, text: &str) -> Result<Vec<f32>, Box<dyn std::error::Error>>;
+}
+
+/// A small embedder that needs no model: words and their character trigrams are hashed into a
+/// fixed number of dimensions. It only captures overlap in wording, not meaning, but works well
+/// enough to tell apart agents whose examples use different vocabulary.
+#[derive(Debug, Clone)]
+pub struct HashingEmbedder {
+    dimensions: usize,
+}
+
+impl Default for HashingEmbedder {
+    fn default() -> Self {
+        HashingEmbedder { dimensions: 512 }
+    }
+}
+
+impl HashingEmbedder {
+    pub fn new(dimensions: usize) -> Self {
+        HashingEmbedder {
+            dimensions: dimensions.max(1),
+        }
+    }
+
+    fn add(&self, vector: &mut [f32], feature: &str, weight: f32) {
+        let hash = fnv1a(feature.as_bytes());
+        // the sign bit keeps collisions from always adding up
+        let sign = if hash >> 63 == 0 { 1.0 } else { -1.0 };
+        vector[(hash % self.dimensions as u64) as usize] += sign * weight;
+    }
+}
+
+impl Embedder for HashingEmbedder {
+    fn id(&self) -> String {
+        format!("hashing:{}", self.dimensions)
+    }
+
+    fn embed(&self, text: &str) -> Result<Vec<f32>, Box<dyn std::error::Error>> {
+        let mut vector = vec![0.0; self.dimensions];
+        let text = text.to_lowercase();
+        for word in text.split(|c: char| !c.is_alphanumeric()).filter(|word| !word.is_empty()) {
+            self.add(&mut vector, word, 1.0);
+
+            let chars: Vec<char> = format!("<{}>", word).chars().collect();
+            for trigram in chars.windows(3) {
+                self.add(&mut vector, &trigram.iter().collect::<String>(), 0.5);
+            }
+        }
+        Ok(vector)
+    }
+}
+
+/// The 64-bit FNV-1a hash of `bytes`. Stable across platforms and Rust releases.
+pub(crate) fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// Cosine similarity of two vectors, 0 if either is all zeros.
+pub fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    let dot: f32 = a.iter().zip(b).map(|(a, b)| a * b).sum();
+    let norm = |v: &[f32]| v.iter().map(|x| x * x).sum::<f32>().sqrt();
+    match norm(a) * norm(b) {
+        norm if norm > 0.0 => dot / norm,
+        _ => 0.0,
+    }
+}
+
+struct Document {
+    pool: String,
+    vector: Vec<f32>,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct VectorStore {
+    embedder: String,
+    vectors: HashMap<String, Vec<f32>>,
+}
+
+/// Matches inputs to agents in process, replacing the ToolDB service. Every command and example
+/// line of an agent is embedded, and a pool's score for an input is the cosine similarity of its
+/// most similar document.
+///
+/// With a store configured, the vectors are written to disk and loaded again on start, so that
+/// agents which register again after a restart are not embedded a second time.
+pub struct EmbeddingMatcher {
+    embedder: Arc<dyn Embedder>,
+    documents: RwLock<Vec<Document>>,
+    store: Option<PathBuf>,
+    vectors: Mutex<HashMap<String, Vec<f32>>>,
+}
+
+impl EmbeddingMatcher {
+    pub fn new(embedder: Arc<dyn Embedder>) -> Self {
+        EmbeddingMatcher {
+            embedder,
+            documents: RwLock::new(Vec::new()),
+            store: None,
+            vectors: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Persists the vectors to `path`, reusing those already stored there by the same embedder.
+    pub fn with_store(mut self, path: PathBuf) -> Self {
+        let vectors = fs::read(&path)
+            .ok()
+            .and_then(|bytes| match bincode::deserialize::<VectorStore>(&bytes) {
+                Ok(store) => Some(store),
+                Err(e) => {
+                    tracing::warn!("ignoring corrupt vector store {}: {}", path.display(), e);
+                    None
+                }
+            })
+            .filter(|store| store.embedder == self.embedder.id())
+            .map(|store| store.vectors)
+            .unwrap_or_default();
+        tracing::debug!("loaded {} vectors from {}", vectors.len(), path.display());
+
+        self.vectors = Mutex::new(vectors);
+        self.store = Some(path);
+        self
+    }
+
+    /// The texts an agent is matched by: its commands and the lines of its examples.
+    fn texts(agent: &ConnectedAgent) -> Vec<String> {
+        agent
+            .commands
+            .iter()
+            .map(String::as_str)
+            .chain(agent.examples.lines())
+            .map(str::trim)
+            .filter(|text| !text.is_empty())
+            .map(str::to_string)
+            .collect()
+    }
+
+    async fn embed(&self, text: &str) -> Result<Vec<f32>, Box<dyn std::error::Error>> {
+        if let Some(vector) = self.vectors.lock().unwrap().get(text) {
+            return Ok(vector.clone());
+        }
+
+        let vector = embed_blocking(&self.embedder, text).await?;
+        self.vectors.lock().unwrap().insert(text.to_string(), vector.clone());
+        Ok(vector)
+    }
+
+    fn save(&self) {
+        let path = match &self.store {
+            Some(path) => path,
+            None => return,
+        };
+
+        let store = VectorStore {
+            embedder: self.embedder.id(),
+            vectors: self.vectors.lock().unwrap().clone(),
+        };
+        let result = bincode::serialize(&store).map_err(|e| e.to_string()).and_then(|bytes| {
+            if let Some(dir) = path.parent() {
+                fs::create_dir_all(dir).map_err(|e| e.to_string())?;
+            }
+            let tmp = path.with_extension("tmp");
+            fs::write(&tmp, bytes).map_err(|e| e.to_string())?;
+            fs::rename(&tmp, path).map_err(|e| e.to_string())
+        });
+        if let Err(e) = result {
+            tracing::warn!("failed to persist vectors to {}: {}", path.display(), e);
+        }
+    }
+}
+
+/// Embeds `text` on the blocking thread pool. With the llama embedder this is a forward pass of the
+/// model, which would otherwise hold up the runtime's workers and with them heartbeats and tasks.
+async fn embed_blocking(embedder: &Arc<dyn Embedder>, text: &str) -> Result<Vec<f32>, Box<dyn std::error::Error>> {
+    let (embedder, text) = (embedder.clone(), text.to_string());
+    let vector = tokio::task::spawn_blocking(move || embedder.embed(&text).map_err(|e| e.to_string())).await??;
+    Ok(vector)
+}
+
+#[tonic::async_trait]
+impl AgentMatcher for EmbeddingMatcher {
+    async fn match_agents(&self, task: &str, limit: usize) -> Result<Vec<AgentMatch>, Box<dyn std::error::Error>> {
+        let query = embed_blocking(&self.embedder, task).await?;
+
+        // a pool is as similar to the task as its most similar document
+        let mut scores: HashMap<&str, f32> = HashMap::new();
+        let documents = self.documents.read().unwrap();
+        for document in documents.iter() {
+            let score = cosine_similarity(&query, &document.vector);
+            let best = scores.entry(&document.pool).or_insert(score);
+            *best = best.max(score);
+        }
+
+        let mut matches: Vec<AgentMatch> = scores
+            .into_iter()
+            .map(|(name, score)| AgentMatch {
+                name: name.to_string(),
+                score: score.max(0.0),
+            })
+            .collect();
+        matches.sort_by(|a, b| b.score.total_cmp(&a.score).then_with(|| a.name.cmp(&b.name)));
+        matches.truncate(limit);
+        tracing::debug!("matched {:?} to {:?}", task, matches);
+
+        Ok(matches)
+    }
+
+    async fn add_agent(&self, agent: ConnectedAgent) -> Result<(), Box<dyn std::error::Error>> {
+        let mut documents = Vec::new();
+        for text in Self::texts(&agent) {
+            documents.push(Document {
+                pool: agent.pool.clone(),
+                vector: self.embed(&text).await?,
+            });
+        }
+        self.save();
+
+        // replace the documents of a pool that registers again instead of adding duplicates
+        let mut all = self.documents.write().unwrap();
+        all.retain(|document| document.pool != agent.pool);
+        all.extend(documents);
+
+        Ok(())
+    }
+
+    async fn remove_agent(&self, name: &str) -> Result<(), Box<dyn std::error::Error>> {
+        self.documents.write().unwrap().retain(|document| document.pool != name);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, process, sync::{Arc, Mutex}};
+
+    use crate::common::{AgentMatch, AgentMatcher, ConnectedAgent};
+
+    use super::{cosine_similarity, Embedder, EmbeddingMatcher, HashingEmbedder};
+
+    fn agent(name: &str, commands: &[&str], examples: &str) -> ConnectedAgent {
+        ConnectedAgent {
+            examples: examples.to_string(),
+            ..ConnectedAgent::for_test(name, commands)
+        }
+    }
+
+    /// Counts the texts it embeds.
+    struct Counting(HashingEmbedder, Mutex<usize>);
+
+    impl Embedder for Counting {
+        fn id(&self) -> String {
+            self.0.id()
+        }
+
+        fn embed(&self, text: &str) -> Result<Vec<f32>, Box<dyn std::error::Error>> {
+            *self.1.lock().unwrap() += 1;
+            self.0.embed(text)
+        }
+    }
+
+    #[tokio::test]
+    pub async fn test_embedding_matcher() {
+        let embedder = HashingEmbedder::default();
+        let a = embedder.embed("write a tweet about rust").unwrap();
+        assert!((cosine_similarity(&a, &a) - 1.0).abs() < 1e-5);
+        assert!(
+            cosine_similarity(&a, &embedder.embed("tweet about the weather").unwrap())
+                > cosine_similarity(&a, &embedder.embed("what is 999 - 1?").unwrap())
+        );
+
+        let matcher = EmbeddingMatcher::new(Arc::new(HashingEmbedder::default()));
+        assert!(matcher.match_agents("what is 2 + 2?", 3).await.unwrap().is_empty());
+
+        matcher
+            .add_agent(agent("calc", &["calculate(expression)"], "Input: what is 999 - 1?\nAction: calculate[999 - 1]"))
+            .await
+            .unwrap();
+        matcher
+            .add_agent(agent("tweetu", &["tweet(topic)"], "Input: write a tweet about coffee\nAction: tweet[coffee]"))
+            .await
+            .unwrap();
+        let best = |matches: Vec<AgentMatch>| matches[0].name.clone();
+        assert_eq!(best(matcher.match_agents("calculate 12 * 7", 3).await.unwrap()), "calc");
+
+        let matches = matcher.match_agents("write a tweet about the rust release", 3).await.unwrap();
+        assert_eq!(matches.len(), 2);
+        assert_eq!(matches[0].name, "tweetu");
+        assert!(matches[0].score > matches[1].score);
+        assert!(matches.iter().all(|m| (0.0..=1.0).contains(&m.score)));
+        assert_eq!(matcher.match_agents("write a tweet about the rust release", 1).await.unwrap().len(), 1);
+
+        matcher.remove_agent("tweetu").await.unwrap();
+        assert_eq!(best(matcher.match_agents("write a tweet about the rust release", 3).await.unwrap()), "calc");
+    }
+
+    #[tokio::test]
+    pub async fn test_embedding_store() {
+        let path = std::env::temp_dir().join(format!("meeseeks-vectors-{}.bin", process::id()));
+        let calc = agent("calc", &["calculate(expression)"], "Input: what is 999 - 1?");
+
+        let embedder = Arc::new(Counting(HashingEmbedder::default(), Mutex::new(0)));
+        let matcher = EmbeddingMatcher::new(embedder.clone()).with_store(path.clone());
+        matcher.add_agent(calc.clone()).await.unwrap();
+        assert_eq!(*embedder.1.lock().unwrap(), 2);
+
+        let embedder = Arc::new(Counting(HashingEmbedder::default(), Mutex::new(0)));
+        let restarted = EmbeddingMatcher::new(embedder.clone()).with_store(path.clone());
+        restarted.add_agent(calc).await.unwrap();
+        assert_eq!(*embedder.1.lock().unwrap(), 0, "stored vectors are reused");
+        assert_eq!(restarted.match_agents("what is 1 + 1?", 1).await.unwrap()[0].name, "calc");
+
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -1,5 +1,14 @@
+use std::{collections::HashMap, fmt, path::PathBuf, time::Duration};
+
 use thiserror::Error;
-use tonic::Status;
+use tonic::{Code, Status};
+
+use crate::{
+    action::ActionParseError,
+    common::AgentMatch,
+    meeseeks_proto::{self, ErrorKind, TaskError, TaskResponse},
+    schema::SchemaError,
+};
 
 #[derive(Error, Debug)]
 pub enum MeeseeksError {
@@ -9,8 +18,328 @@ pub enum MeeseeksError {
     #[error("failed to connect to agent")]
     ConnectionError(#[from] tonic::transport::Error),
 
-    #[error("failed to execute task")]
+    #[error("failed to execute task: {0}")]
     TaskExecutorError(String),
+
+    #[error("invalid instruction {instruction}. available instructions are: {available:?}")]
+    InvalidInstruction {
+        instruction: String,
+        available: Vec<String>,
+    },
+
+    #[error("invalid arguments: {0}")]
+    InvalidArguments(String),
+
+    #[error("upstream API error: {0}")]
+    UpstreamError(String),
+
+    #[error("rate limited by upstream API: {0}")]
+    RateLimited(String),
+
+    #[error("timed out: {0}")]
+    Timeout(String),
+}
+
+impl MeeseeksError {
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            MeeseeksError::InvalidInstruction { .. } => ErrorKind::InvalidInstruction,
+            MeeseeksError::InvalidArguments(_) => ErrorKind::InvalidArguments,
+            MeeseeksError::UpstreamError(_) => ErrorKind::UpstreamError,
+            MeeseeksError::RateLimited(_) => ErrorKind::RateLimited,
+            MeeseeksError::Timeout(_) => ErrorKind::Timeout,
+            MeeseeksError::GrpcError(status) if status.code() == Code::DeadlineExceeded => ErrorKind::Timeout,
+            MeeseeksError::GrpcError(_) | MeeseeksError::ConnectionError(_) | MeeseeksError::TaskExecutorError(_) => {
+                ErrorKind::Internal
+            }
+        }
+    }
+
+    /// Whether the same task may succeed if it is sent again.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            MeeseeksError::RateLimited(_) | MeeseeksError::Timeout(_) | MeeseeksError::ConnectionError(_) => true,
+            MeeseeksError::GrpcError(status) => matches!(
+                status.code(),
+                Code::Unavailable | Code::ResourceExhausted | Code::Aborted | Code::DeadlineExceeded
+            ),
+            _ => false,
+        }
+    }
+
+    fn details(&self) -> HashMap<String, String> {
+        match self {
+            MeeseeksError::InvalidInstruction { instruction, available } => HashMap::from([
+                ("instruction".to_string(), instruction.clone()),
+                ("available".to_string(), available.join(",")),
+            ]),
+            _ => HashMap::new(),
+        }
+    }
+}
+
+impl From<SchemaError> for MeeseeksError {
+    fn from(error: SchemaError) -> Self {
+        match error {
+            SchemaError::UnknownCommand(instruction) => MeeseeksError::InvalidInstruction {
+                instruction,
+                available: Vec::new(),
+            },
+            error => MeeseeksError::InvalidArguments(error.to_string()),
+        }
+    }
+}
+
+impl From<reqwest::Error> for MeeseeksError {
+    fn from(error: reqwest::Error) -> Self {
+        if error.is_timeout() {
+            MeeseeksError::Timeout(error.to_string())
+        } else if error.status() == Some(reqwest::StatusCode::TOO_MANY_REQUESTS) {
+            MeeseeksError::RateLimited(error.to_string())
+        } else {
+            MeeseeksError::UpstreamError(error.to_string())
+        }
+    }
+}
+
+impl From<MeeseeksError> for TaskError {
+    fn from(error: MeeseeksError) -> Self {
+        TaskError {
+            kind: error.kind().into(),
+            message: error.to_string(),
+            retryable: error.is_retryable(),
+            details: error.details(),
+        }
+    }
+}
+
+impl From<MeeseeksError> for Status {
+    fn from(error: MeeseeksError) -> Self {
+        let code = match &error {
+            MeeseeksError::GrpcError(status) => return status.clone(),
+            MeeseeksError::ConnectionError(_) => Code::Unavailable,
+            MeeseeksError::InvalidInstruction { .. } => Code::Unimplemented,
+            MeeseeksError::InvalidArguments(_) => Code::InvalidArgument,
+            MeeseeksError::UpstreamError(_) => Code::Unavailable,
+            MeeseeksError::RateLimited(_) => Code::ResourceExhausted,
+            MeeseeksError::Timeout(_) => Code::DeadlineExceeded,
+            MeeseeksError::TaskExecutorError(_) => Code::Internal,
+        };
+        Status::new(code, error.to_string())
+    }
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = match self {
+            ErrorKind::Internal => "internal error",
+            ErrorKind::InvalidInstruction => "invalid instruction",
+            ErrorKind::InvalidArguments => "invalid arguments",
+            ErrorKind::UpstreamError => "upstream error",
+            ErrorKind::RateLimited => "rate limited",
+            ErrorKind::Timeout => "timeout",
+        };
+        write!(f, "{}", kind)
+    }
+}
+
+impl TaskError {
+    /// Whether the agent rejected the instruction or arguments of the task, so that a corrected
+    /// task may succeed.
+    pub fn is_correctable(&self) -> bool {
+        matches!(self.kind(), ErrorKind::InvalidInstruction | ErrorKind::InvalidArguments)
+    }
+}
+
+impl fmt::Display for TaskError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.kind(), self.message)?;
+        if self.retryable {
+            write!(f, " (retryable)")?;
+        }
+        Ok(())
+    }
+}
+
+impl TaskResponse {
+    pub fn success(response: impl Into<String>) -> Self {
+        TaskResponse {
+            status: meeseeks_proto::Status::Success.into(),
+            response: response.into(),
+            error: None,
+        }
+    }
+
+    pub fn failure(error: MeeseeksError) -> Self {
+        TaskResponse {
+            status: meeseeks_proto::Status::Failure.into(),
+            response: error.to_string(),
+            error: Some(error.into()),
+        }
+    }
+
+    /// Whether the task failed with an error that may go away if it is sent again.
+    pub fn is_retryable(&self) -> bool {
+        matches!(&self.error, Some(error) if error.retryable)
+    }
+
+    pub fn is_correctable(&self) -> bool {
+        matches!(&self.error, Some(error) if error.is_correctable())
+    }
+
+    /// The error of a failed task, or its response text if the agent did not send a structured
+    /// error.
+    pub fn failure_message(&self) -> String {
+        match &self.error {
+            Some(error) => error.to_string(),
+            None => self.response.clone(),
+        }
+    }
 }
 
 pub type Result<T> = std::result::Result<T, MeeseeksError>;
+
+/// Why a parser could not turn an input into tasks.
+#[derive(Error, Debug)]
+pub enum ParseError {
+    #[error("this parser does not support {0}")]
+    Unsupported(&'static str),
+
+    #[error("failed to parse action {output:?}: {error}")]
+    InvalidAction { output: String, error: ActionParseError },
+
+    #[error("inference failed: {0}")]
+    Inference(String),
+
+    #[error("request to the parser backend failed: {0}")]
+    Backend(#[from] reqwest::Error),
+
+    #[error("invalid parser config: {0}")]
+    Config(String),
+
+    /// None of the agents can handle the input, e.g. because the model answered `NONE`. Not a
+    /// failure of the parser.
+    #[error("no tool applies to the input")]
+    NoTool,
+}
+
+/// Why the master found no agent for an input.
+#[derive(Error, Debug)]
+pub enum MatchError {
+    /// No pool applies to the input, or none scores high enough. Lists the pools that were
+    /// considered with their scores.
+    #[error("no agent matches the input{}", format_matches(.0))]
+    NoMatch(Vec<AgentMatch>),
+
+    #[error("blocked by routing rule {rule}: {reason}")]
+    Blocked { rule: String, reason: String },
+
+    /// Agents match the input but none of them can take tasks right now.
+    #[error("{0}")]
+    Unavailable(String),
+
+    #[error("failed to match input task to agent: {0}")]
+    Matcher(String),
+}
+
+fn format_matches(matches: &[AgentMatch]) -> String {
+    match matches.is_empty() {
+        true => String::new(),
+        false => {
+            let scores: Vec<String> = matches.iter().map(|m| format!("{} ({:.2})", m.name, m.score)).collect();
+            format!(" well enough. matches: {}", scores.join(", "))
+        }
+    }
+}
+
+/// Why routing rules could not be loaded.
+#[derive(Error, Debug)]
+pub enum RoutingError {
+    #[error("invalid routing rules: {0}")]
+    Config(String),
+}
+
+/// Why a prompt template could not be loaded.
+#[derive(Error, Debug)]
+pub enum TemplateError {
+    #[error("failed to read template {}: {error}", path.display())]
+    Io { path: PathBuf, error: std::io::Error },
+
+    #[error("template {template} has an unclosed placeholder at byte {offset}")]
+    Unclosed { template: String, offset: usize },
+
+    #[error("template {template} uses unknown variable {variable}. expected one of: {expected:?}")]
+    UnknownVariable {
+        template: String,
+        variable: String,
+        expected: Vec<String>,
+    },
+
+    #[error("template {template} is missing the placeholder for {variable}")]
+    MissingVariable { template: String, variable: String },
+}
+
+/// Why the master could not get a response to a task from an agent.
+#[derive(Error, Debug)]
+pub enum DispatchError {
+    #[error("agent {0} not found")]
+    NotFound(String),
+
+    #[error("invalid task: {0}")]
+    InvalidTask(#[from] SchemaError),
+
+    #[error("agent {0} is not responding")]
+    Unavailable(String),
+
+    #[error("circuit for agent {0} is open after repeated failures")]
+    CircuitOpen(String),
+
+    #[error("agent {agent} did not respond within {timeout:?}")]
+    TimedOut { agent: String, timeout: Duration },
+
+    #[error("agent {agent} returned an error: {status}")]
+    Rpc { agent: String, status: Status },
+
+    #[error("{error} (gave up after {attempts} attempts)")]
+    Exhausted {
+        attempts: u32,
+        error: Box<DispatchError>,
+    },
+}
+
+impl DispatchError {
+    /// Whether sending the task again may succeed.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            DispatchError::TimedOut { .. } => true,
+            DispatchError::Rpc { status, .. } => matches!(
+                status.code(),
+                Code::Unavailable | Code::ResourceExhausted | Code::Aborted | Code::DeadlineExceeded | Code::Cancelled
+            ),
+            _ => false,
+        }
+    }
+
+    /// Whether the agent could not be reached at all, as opposed to failing while running the task.
+    pub fn is_transport(&self) -> bool {
+        matches!(self, DispatchError::Rpc { status, .. } if status.code() == Code::Unavailable)
+    }
+
+    /// Whether the task was rejected because of its instruction or arguments, so that a corrected
+    /// task may succeed.
+    pub fn is_correctable(&self) -> bool {
+        match self.last() {
+            DispatchError::InvalidTask(_) => true,
+            DispatchError::Rpc { status, .. } => matches!(status.code(), Code::InvalidArgument | Code::Unimplemented),
+            _ => false,
+        }
+    }
+
+    /// The error of the last attempt.
+    pub fn last(&self) -> &DispatchError {
+        match self {
+            DispatchError::Exhausted { error, .. } => error.last(),
+            _ => self,
+        }
+    }
+}
@@ -0,0 +1,156 @@
+use crate::common::ConnectedAgent;
+
+const THOUGHT: &[u8] = b"Thought:";
+const ACTION: &[u8] = b"Action: ";
+const NONE: &[u8] = b"NONE";
+
+/// The output the parser accepts from the model: a `Thought: ...` line followed by either
+/// `Action: <command>[<args>]` for one of the advertised commands, or `NONE`.
+///
+/// The grammar works on bytes so that tokens that end in the middle of a UTF-8 character can be
+/// checked as well.
+#[derive(Debug, Clone)]
+pub struct ActionGrammar {
+    /// Every command followed by its opening bracket, e.g. `tweet[`.
+    commands: Vec<Vec<u8>>,
+}
+
+/// How far the model's output got through the grammar.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum GrammarState {
+    /// Inside the `Thought:` marker, `matched` bytes of it were emitted.
+    Thought { matched: usize },
+    /// Inside the text of the thought, up to the end of the line.
+    ThoughtText,
+    /// At the start of the line after the thought. `matched` is what was emitted of `Action: ` or
+    /// `NONE`.
+    Step { matched: Vec<u8> },
+    /// After `Action: `. `matched` is what was emitted of the command and its opening bracket.
+    Command { matched: Vec<u8> },
+    /// Inside the arguments. `depth` counts the brackets opened within them.
+    Args { depth: usize },
+    /// The action was closed or the model answered `NONE`. Nothing else may be generated.
+    Done,
+}
+
+impl ActionGrammar {
+    pub fn new<'a>(commands: impl IntoIterator<Item = &'a str>) -> Self {
+        let mut commands: Vec<Vec<u8>> = commands
+            .into_iter()
+            .map(|command| format!("{}[", command).into_bytes())
+            .collect();
+        commands.sort();
+        commands.dedup();
+
+        ActionGrammar { commands }
+    }
+
+    /// A grammar that allows the commands advertised in the schemas of `agents`.
+    pub fn for_agents(agents: &[ConnectedAgent]) -> Self {
+        Self::new(
+            agents
+                .iter()
+                .flat_map(|agent| agent.schemas())
+                .map(|schema| schema.name.as_str()),
+        )
+    }
+
+    pub fn start(&self) -> GrammarState {
+        GrammarState::Thought { matched: 0 }
+    }
+
+    /// The state after `text` was emitted in `state`, or `None` if the grammar does not allow it.
+    pub fn advance(&self, state: &GrammarState, text: &[u8]) -> Option<GrammarState> {
+        text.iter()
+            .try_fold(state.clone(), |state, &byte| self.advance_byte(state, byte))
+    }
+
+    /// Whether the model may emit `token` in `state`. Tokens that produce no text, like the end of
+    /// text token, are never allowed because the output is only complete once the grammar is done.
+    pub fn allows(&self, state: &GrammarState, token: &[u8]) -> bool {
+        !token.is_empty() && self.advance(state, token).is_some()
+    }
+
+    fn advance_byte(&self, state: GrammarState, byte: u8) -> Option<GrammarState> {
+        match state {
+            // the model may start with blank lines or spaces before the thought
+            GrammarState::Thought { matched: 0 } if byte.is_ascii_whitespace() => Some(state),
+            GrammarState::Thought { matched } => match THOUGHT.get(matched) {
+                Some(&expected) if expected == byte => match matched + 1 == THOUGHT.len() {
+                    true => Some(GrammarState::ThoughtText),
+                    false => Some(GrammarState::Thought { matched: matched + 1 }),
+                },
+                _ => None,
+            },
+            GrammarState::ThoughtText if byte == b'\n' => Some(GrammarState::Step { matched: Vec::new() }),
+            GrammarState::ThoughtText => Some(GrammarState::ThoughtText),
+            GrammarState::Step { mut matched } => {
+                matched.push(byte);
+                if matched == ACTION {
+                    return Some(GrammarState::Command { matched: Vec::new() });
+                }
+                if matched == NONE {
+                    return Some(GrammarState::Done);
+                }
+                let allowed = ACTION.starts_with(&matched) || NONE.starts_with(&matched);
+                allowed.then_some(GrammarState::Step { matched })
+            }
+            GrammarState::Command { mut matched } => {
+                matched.push(byte);
+                if self.commands.contains(&matched) {
+                    return Some(GrammarState::Args { depth: 0 });
+                }
+                let allowed = self.commands.iter().any(|command| command.starts_with(&matched));
+                allowed.then_some(GrammarState::Command { matched })
+            }
+            GrammarState::Args { .. } if byte == b'\n' => None,
+            GrammarState::Args { depth } => match byte {
+                b'[' => Some(GrammarState::Args { depth: depth + 1 }),
+                b']' if depth == 0 => Some(GrammarState::Done),
+                b']' => Some(GrammarState::Args { depth: depth - 1 }),
+                _ => Some(GrammarState::Args { depth }),
+            },
+            GrammarState::Done => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ActionGrammar, GrammarState};
+
+    #[test]
+    pub fn test_action_grammar() {
+        let grammar = ActionGrammar::new(["tweet", "summary", "search"]);
+        let start = grammar.start();
+
+        for output in [
+            "Thought: I should tweet\nAction: tweet[earthquake in Delhi]",
+            "\nThought: I need the summary of a page\nAction: summary[Rust [language]]",
+            "Thought: no tool can do this\nNONE",
+        ] {
+            assert_eq!(grammar.advance(&start, output.as_bytes()), Some(GrammarState::Done), "{}", output);
+        }
+
+        for output in [
+            "Action: tweet[hello]",
+            "Thought: I should tweet\nAction: twee[hello]",
+            "Thought: I should tweet\nAction: tweet(hello)",
+            "Thought: I should tweet\nAction: tweet[hello\nworld]",
+            "Thought: I should tweet\nAction: tweet[hello] again",
+            "Thought: I should tweet\nAnswer: done",
+        ] {
+            assert_eq!(grammar.advance(&start, output.as_bytes()), None, "{}", output);
+        }
+
+        let state = grammar.advance(&start, b"Thought: search or summary?\nAction: s").unwrap();
+        assert!(grammar.allows(&state, b"ear"));
+        assert!(grammar.allows(&state, b"ummary[Ru"));
+        assert!(!grammar.allows(&state, b"ummarize"));
+        assert!(!grammar.allows(&state, b""));
+
+        let grammar = ActionGrammar::new([]);
+        assert!(grammar.advance(&start, b"Thought: nothing to do\nNONE").is_some());
+        assert!(grammar.advance(&start, b"Thought: nothing to do\nAction: tweet").is_none());
+    }
+}
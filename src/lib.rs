@@ -1,8 +1,22 @@
+pub mod action;
 pub mod agent;
+pub mod bm25;
+pub mod breaker;
+pub mod canonical;
 pub mod common;
+pub mod embedding;
 pub mod error;
+pub mod grammar;
 pub mod llama_parser;
 pub mod master;
+pub mod parser;
+pub mod prompt_cache;
+pub mod registry;
+pub mod registry_store;
+pub mod routing;
+pub mod schema;
+pub mod task_graph;
+pub mod template;
 pub mod tool;
 pub mod tooldb;
 
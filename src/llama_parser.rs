@@ -1,59 +1,355 @@
 use color_eyre::eyre::bail;
-use dyn_fmt::AsStrFormatExt;
 use rand::SeedableRng;
-use std::{cell::RefCell, path::Path};
+use serde::Deserialize;
+use std::{cell::RefCell, collections::HashMap, convert::Infallible, path::Path, str::FromStr, sync::Arc};
+use tokio::task::block_in_place;
 
-use llm::{KnownModel, InferenceParameters, ModelParameters, InferenceRequest, InferenceSessionConfig};
+use llm::{
+    InferenceError, InferenceParameters, InferenceRequest, InferenceSession, InferenceSessionConfig,
+    InferenceSnapshot, LoadProgress, Model, ModelArchitecture, ModelKVMemoryType, ModelParameters, OutputRequest,
+    TokenBias,
+};
 
-use crate::{common::ConnectedAgent, meeseeks_proto::TaskRequest};
+use crate::{
+    common::{AgentMatcher, ConnectedAgent, TaskParser},
+    embedding::Embedder,
+    error::ParseError,
+    grammar::{ActionGrammar, GrammarState},
+    master::MasterAgent,
+    meeseeks_proto::{Status, TaskRequest},
+    parser::{
+        construct_prompt, correction_from_response, into_task, parse_action, parse_action_for, task_from_response, Prompt,
+        INPUT_MARKER,
+    },
+    prompt_cache::PrefixCache,
+    template::Templates,
+};
 
-lazy_static::lazy_static! {
-static ref RE: regex::Regex = regex::Regex::new(r"Action: (?P<command>\w+)\[(?P<args>.*?)\]").unwrap();
+/// A single iteration of the ReAct loop: the action the model chose and what running it returned.
+#[derive(Debug, Clone)]
+pub struct ReactStep {
+    pub thought: String,
+    /// The action as the model wrote it, e.g. `question[query="capital of India", topic=India]`.
+    pub action: String,
+    pub agent: String,
+    /// The task the action was parsed into. Empty if it could not be parsed.
+    pub task: TaskRequest,
+    pub observation: String,
 }
 
-const PROMPT_TEMPLATE: &'static str = r#"
-You run in a loop of Input, Thought and Action. I will provide the Input and you are supposed to use only Thought or Action. Use Thought to describe your thoughts about the question you have been asked. If there is no tool available, you can just respond with NONE. 
-Use Action to run one of these actions available to you:
-{}
+/// Result of running the ReAct loop on an input. `answer` is `None` if the model gave up or
+/// the step limit was reached before it produced a final answer.
+#[derive(Debug, Clone)]
+pub struct ReactOutcome {
+    pub steps: Vec<ReactStep>,
+    pub answer: Option<String>,
+}
 
-{}
-Input: {}
-"#;
+#[derive(Debug)]
+enum NextStep {
+    /// The model chose `action`, which was parsed into `task`.
+    Action {
+        thought: String,
+        action: String,
+        task: Result<TaskRequest, ParseError>,
+    },
+    Answer(String),
+    NoTool,
+}
 
-pub struct LlamaParser {
-    model: llm::models::Llama,
-    inference_params: InferenceParameters,
+/// Precision of the model's key/value memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub enum KvMemory {
+    #[serde(rename = "f16")]
+    Float16,
+    #[serde(rename = "f32")]
+    Float32,
 }
 
-impl LlamaParser {
-    pub fn init(model_path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
-        let params = ModelParameters::default();
-        let model = llm::models::Llama::load(model_path, params, |_| ())?;
-        let inference_params = InferenceParameters {
+impl FromStr for KvMemory {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "f16" => Ok(KvMemory::Float16),
+            "f32" => Ok(KvMemory::Float32),
+            _ => Err(format!("unknown memory type {}. expected f16 or f32", s)),
+        }
+    }
+}
+
+impl From<KvMemory> for ModelKVMemoryType {
+    fn from(memory: KvMemory) -> Self {
+        match memory {
+            KvMemory::Float16 => ModelKVMemoryType::Float16,
+            KvMemory::Float32 => ModelKVMemoryType::Float32,
+        }
+    }
+}
+
+/// Which model the parser loads and how it samples from it. Read from a TOML file where every key
+/// is optional:
+///
+/// ```toml
+/// architecture = "gptneox"
+/// context_size = 2048
+/// threads = 8
+/// kv_memory = "f16"
+/// temperature = 0.1
+/// top_k = 10000
+/// max_tokens = 1024
+/// stop_sequences = ["###"]
+/// ```
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ModelConfig {
+    /// One of bloom, gpt2, gptj, gptneox or llama.
+    pub architecture: String,
+    pub context_size: usize,
+    pub prefer_mmap: bool,
+    pub threads: usize,
+    pub batch_size: usize,
+    pub kv_memory: KvMemory,
+    pub temperature: f32,
+    pub top_k: usize,
+    pub top_p: f32,
+    pub repeat_penalty: f32,
+    /// Number of most recent tokens the repeat penalty applies to.
+    pub repeat_last_n: usize,
+    /// Maximum number of tokens generated for a single prompt.
+    pub max_tokens: usize,
+    /// Generation stops at any of these in addition to the stop sequences of the prompt.
+    pub stop_sequences: Vec<String>,
+}
+
+impl Default for ModelConfig {
+    fn default() -> Self {
+        ModelConfig {
+            architecture: "llama".to_string(),
+            context_size: 2048,
+            prefer_mmap: true,
+            threads: num_cpus::get(),
+            batch_size: 8,
+            kv_memory: KvMemory::Float16,
             temperature: 0.1,
             top_k: 10000,
-            n_threads: num_cpus::get(),
+            top_p: 0.95,
+            repeat_penalty: 1.3,
+            repeat_last_n: 512,
+            max_tokens: 1024,
+            stop_sequences: Vec::new(),
+        }
+    }
+}
+
+impl ModelConfig {
+    pub fn from_file(path: &Path) -> Result<Self, ParseError> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| ParseError::Config(format!("failed to read {}: {}", path.display(), e)))?;
+        Self::from_toml(&text)
+    }
+
+    pub fn from_toml(text: &str) -> Result<Self, ParseError> {
+        let config: ModelConfig = toml::from_str(text).map_err(|e| ParseError::Config(e.to_string()))?;
+        config.model_architecture()?;
+        Ok(config)
+    }
+
+    pub fn model_architecture(&self) -> Result<ModelArchitecture, ParseError> {
+        match self.architecture.to_lowercase().as_str() {
+            "bloom" => Ok(ModelArchitecture::Bloom),
+            "gpt2" => Ok(ModelArchitecture::Gpt2),
+            "gptj" => Ok(ModelArchitecture::GptJ),
+            "gptneox" => Ok(ModelArchitecture::GptNeoX),
+            "llama" => Ok(ModelArchitecture::Llama),
+            other => Err(ParseError::Config(format!(
+                "unknown model architecture {}. expected bloom, gpt2, gptj, gptneox or llama",
+                other
+            ))),
+        }
+    }
+
+    fn model_params(&self) -> ModelParameters {
+        ModelParameters {
+            prefer_mmap: self.prefer_mmap,
+            n_context_tokens: self.context_size,
             ..Default::default()
-        };
+        }
+    }
+
+    fn inference_params(&self) -> InferenceParameters {
+        InferenceParameters {
+            n_threads: self.threads,
+            n_batch: self.batch_size,
+            top_k: self.top_k,
+            top_p: self.top_p,
+            repeat_penalty: self.repeat_penalty,
+            temperature: self.temperature,
+            repetition_penalty_last_n: self.repeat_last_n,
+            ..Default::default()
+        }
+    }
+
+    fn session_config(&self) -> InferenceSessionConfig {
+        InferenceSessionConfig {
+            memory_k_type: self.kv_memory.into(),
+            memory_v_type: self.kv_memory.into(),
+        }
+    }
+}
+
+pub struct LlamaParser {
+    model: Box<dyn Model>,
+    /// Identifies the model in the keys of the prefix cache.
+    model_id: String,
+    inference_params: InferenceParameters,
+    session_config: InferenceSessionConfig,
+    max_tokens: usize,
+    stop_sequences: Vec<String>,
+    prefix_cache: PrefixCache<InferenceSnapshot>,
+    templates: Arc<Templates>,
+}
+
+impl LlamaParser {
+    /// Loads the model at `model_path` as described by `config`. `load` is called as the model
+    /// loads, e.g. to report progress.
+    pub fn init(
+        model_path: &Path,
+        config: &ModelConfig,
+        load: impl FnMut(LoadProgress),
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let model = llm::load_dynamic(config.model_architecture()?, model_path, config.model_params(), load)?;
 
         Ok(Self {
             model,
-            inference_params,
+            model_id: format!("{}:{}", config.architecture, model_path.display()),
+            inference_params: config.inference_params(),
+            session_config: config.session_config(),
+            max_tokens: config.max_tokens,
+            stop_sequences: config.stop_sequences.clone(),
+            prefix_cache: PrefixCache::new(0, None),
+            templates: Arc::default(),
         })
     }
 
-    #[tracing::instrument(name="parse", skip(self, agents))]
-    pub fn parse(
+    pub fn with_prefix_cache(mut self, prefix_cache: PrefixCache<InferenceSnapshot>) -> Self {
+        self.prefix_cache = prefix_cache;
+        self
+    }
+
+    pub fn with_templates(mut self, templates: Arc<Templates>) -> Self {
+        self.templates = templates;
+        self
+    }
+
+    /// Drops the cached prompt prefixes if the connected agents changed. `generation` is the
+    /// master's [`MasterAgent::agents_generation`].
+    pub fn sync_agents(&self, generation: u64) {
+        self.prefix_cache.sync(generation);
+    }
+
+    /// Runs the ReAct loop on `input`. Every action the model picks is executed on the agent that
+    /// advertises it and the response is fed back to the model as an `Observation:` until it
+    /// answers, gives up, or `max_steps` actions have been run. Actions that cannot be parsed or
+    /// run are answered with an observation of the error, so that the model can try again.
+    #[tracing::instrument(name="react", skip(self, master, agents))]
+    pub async fn run<Matcher: AgentMatcher + Send + Sync>(
         &self,
+        master: &MasterAgent<Matcher>,
         input: &str,
         agents: &[ConnectedAgent],
-    ) -> color_eyre::Result<TaskRequest> {
-        let session_config = InferenceSessionConfig::default();
-        let mut session = self.model.start_session(session_config);
+        max_steps: usize,
+    ) -> color_eyre::Result<ReactOutcome> {
+        let mut steps: Vec<ReactStep> = Vec::new();
 
-        let mut rng = rand::rngs::StdRng::from_entropy();
+        while steps.len() < max_steps {
+            match block_in_place(|| self.next_step(input, agents, &steps))? {
+                NextStep::Action { thought, action, task } => {
+                    let task = match task {
+                        Ok(task) => task,
+                        Err(e) => {
+                            let observation = format!("error: {}", e);
+                            let task = TaskRequest::default();
+                            steps.push(ReactStep { thought, action, agent: String::new(), task, observation });
+                            continue;
+                        }
+                    };
+                    let (task, agent) = match master.canonicalize(task.clone(), agents) {
+                        Ok((task, agent)) => (task, agent.pool().to_string()),
+                        Err(e) => {
+                            let observation = format!("error: {}", e);
+                            steps.push(ReactStep { thought, action, agent: String::new(), task, observation });
+                            continue;
+                        }
+                    };
 
-        let prompt = construct_prompt(PROMPT_TEMPLATE, agents, input);
+                    tracing::info!("running action {:?} on agent {}", task, agent);
+                    let observation = match master.send_task_to_agent(&agent, task.clone()).await {
+                        Ok(res) => match res.status() {
+                            Status::Success => res.response,
+                            Status::Failure => format!("error: {}", res.failure_message()),
+                        },
+                        Err(e) => format!("error: failed to send task to agent: {}", e),
+                    };
+                    tracing::info!("observation: {:?}", observation);
+
+                    steps.push(ReactStep { thought, action, agent, task, observation });
+                }
+                NextStep::Answer(answer) => {
+                    return Ok(ReactOutcome { steps, answer: Some(answer) });
+                }
+                NextStep::NoTool => {
+                    return Ok(ReactOutcome { steps, answer: None });
+                }
+            }
+        }
+
+        tracing::warn!("reached the step limit ({}) without an answer", max_steps);
+        Ok(ReactOutcome { steps, answer: None })
+    }
+
+    fn next_step(
+        &self,
+        input: &str,
+        agents: &[ConnectedAgent],
+        steps: &[ReactStep],
+    ) -> color_eyre::Result<NextStep> {
+        let mut prompt = construct_prompt(&self.templates.get("react"), agents, input);
+        for step in steps {
+            prompt.rest.push_str(&format!(
+                "Thought: {}\nAction: {}\nObservation: {}\n",
+                step.thought, step.action, step.observation
+            ));
+        }
+
+        let text = self.infer(prompt, &["Observation", "Input"])?;
+        tracing::info!("llama output: {:?}", text);
+
+        let mut thought = String::new();
+        for line in text.lines().map(str::trim) {
+            if let Some(t) = line.strip_prefix("Thought:") {
+                thought = t.trim().to_string();
+            } else if let Some(answer) = line.strip_prefix("Answer:") {
+                return Ok(NextStep::Answer(answer.trim().to_string()));
+            } else if let Some(action) = line.strip_prefix("Action:") {
+                let task = parse_action(line, input, agents);
+                let action = action.trim().to_string();
+                return Ok(NextStep::Action { thought, action, task });
+            } else if line == "NONE" {
+                return Ok(NextStep::NoTool);
+            }
+        }
+
+        bail!("failed to infer next step")
+    }
+
+    /// Runs inference on `prompt` and returns the generated text, stopping as soon as it contains
+    /// any of the `stop` sequences. The stop sequence itself is not included in the output.
+    ///
+    /// Inference keeps the thread busy until it is done, so async callers run it in
+    /// [`block_in_place`] to let the runtime move its other tasks to another worker.
+    fn infer(&self, prompt: Prompt, stop: &[&str]) -> Result<String, ParseError> {
+        let mut rng = rand::rngs::StdRng::from_entropy();
 
         let text = RefCell::new(String::new());
 
@@ -61,14 +357,28 @@ impl LlamaParser {
             spinners::Spinners::Dots9,
             "Running inference on input".into(),
         );
-        
+
+        let mut session = match self.start_session(&prompt.prefix) {
+            Ok(session) => session,
+            Err(e) => {
+                sp.stop();
+                return Err(e);
+            }
+        };
+        let prompt = prompt.rest.as_str();
+        let stop: Vec<&str> = stop
+            .iter()
+            .copied()
+            .chain(self.stop_sequences.iter().map(String::as_str))
+            .collect();
+
         let mut infer_req = InferenceRequest::default();
-        infer_req.prompt = &prompt;
-        infer_req.maximum_token_count = Some(1024);
+        infer_req.prompt = prompt;
+        infer_req.maximum_token_count = Some(self.max_tokens);
         infer_req.parameters = Some(&self.inference_params);
 
         match session.infer(
-            &self.model,
+            self.model.as_ref(),
             &mut rng,
             &mut infer_req,
             &mut Default::default(),
@@ -76,7 +386,8 @@ impl LlamaParser {
                 text.borrow_mut().push_str(new_text);
                 if text.borrow().len() > prompt.len() {
                     tracing::debug!("llama is generating output: {}", new_text);
-                    if text.borrow()[prompt.len()..].contains("Input") {
+                    let generated = &text.borrow()[prompt.len()..];
+                    if stop.iter().any(|s| generated.contains(s)) {
                         return Err(LlamaInferenceError::Done);
                     }
                 }
@@ -88,56 +399,256 @@ impl LlamaParser {
         }
 
         sp.stop();
-        println!("");
+        println!();
 
         let text = text.into_inner();
-        let text = text[prompt.len()..].trim();
-        let text = text.split('\n').nth(1).unwrap_or_else(|| {
-            ""
+        let mut generated = text.get(prompt.len()..).unwrap_or_default();
+        if let Some(end) = stop.iter().filter_map(|s| generated.find(s)).min() {
+            generated = &generated[..end];
+        }
+
+        Ok(generated.to_string())
+    }
+
+    /// Runs inference on `prompt` while masking every token that `grammar` does not allow at that
+    /// point, so the output is always a thought followed by an action on one of the advertised
+    /// commands or `NONE`. Generation stops as soon as the action is closed.
+    fn infer_action(&self, prompt: Prompt, grammar: &ActionGrammar) -> Result<String, ParseError> {
+        let mut rng = rand::rngs::StdRng::from_entropy();
+
+        let mut sp = spinners::Spinner::new(
+            spinners::Spinners::Dots9,
+            "Running inference on input".into(),
+        );
+
+        let mut output_request = OutputRequest::default();
+        let fed = self.start_session(&prompt.prefix).and_then(|mut session| {
+            session
+                .feed_prompt(
+                    self.model.as_ref(),
+                    &self.inference_params,
+                    &prompt.rest,
+                    &mut output_request,
+                    |_| Ok::<(), Infallible>(()),
+                )
+                .map_err(|e| ParseError::Inference(format!("failed to feed prompt: {}", e)))?;
+            Ok(session)
         });
+        let mut session = match fed {
+            Ok(session) => session,
+            Err(e) => {
+                sp.stop();
+                return Err(e);
+            }
+        };
 
-        tracing::info!("llama output: {:?}", text);
+        let vocabulary = &self.model.vocabulary().id_to_token;
+        // the tokens the grammar allows only depend on its state, and most of the output is
+        // generated in a few states, so the vocabulary is checked once per state
+        let mut masked: HashMap<GrammarState, InferenceParameters> = HashMap::new();
+        let mut state = grammar.start();
+        let mut generated = Vec::new();
+        let mut tokens = 0;
 
-        match RE.captures(text) {
-            Some(caps) => {
-                let command = caps.get(1);
-                let args = caps.get(2);
+        while state != GrammarState::Done {
+            if tokens >= self.max_tokens {
+                sp.stop();
+                return Err(ParseError::Inference(format!(
+                    "model did not finish the action within {} tokens",
+                    self.max_tokens
+                )));
+            }
+            tokens += 1;
 
-                match (command, args) {
-                    (Some(instruction), Some(args)) => {
-                        let instruction = instruction.as_str().to_owned();
-                        let args = vec![args.as_str().to_owned(), input.to_owned()];
+            let params = masked.entry(state.clone()).or_insert_with(|| InferenceParameters {
+                bias_tokens: TokenBias::new(
+                    vocabulary
+                        .iter()
+                        .enumerate()
+                        .filter(|(_, token)| !grammar.allows(&state, token))
+                        .map(|(id, _)| (id as llm::TokenId, f32::NEG_INFINITY))
+                        .collect(),
+                ),
+                ..self.inference_params.clone()
+            });
 
-                        let task = TaskRequest { instruction, args };
-                        tracing::debug!("inferred new task: {:?}", task);
+            let token = match session.infer_next_token(self.model.as_ref(), params, &mut output_request, &mut rng) {
+                Ok(token) => token,
+                Err(InferenceError::EndOfText) => break,
+                Err(e) => {
+                    sp.stop();
+                    return Err(ParseError::Inference(e.to_string()));
+                }
+            };
+            tracing::debug!("llama is generating output: {}", String::from_utf8_lossy(token));
 
-                        Ok(task)
-                    }
-                    _ => {
-                        bail!("failed to infer task")
-                    }
+            // the mask only lets through tokens the grammar allows
+            state = grammar.advance(&state, token).unwrap_or(GrammarState::Done);
+            generated.extend_from_slice(token);
+        }
+
+        sp.stop();
+        println!();
+
+        Ok(String::from_utf8_lossy(&generated).into_owned())
+    }
+
+    /// Starts a session with `prefix` already fed to the model. The evaluated prefix is restored
+    /// from the prefix cache if it was evaluated before, and added to it otherwise.
+    fn start_session(&self, prefix: &str) -> Result<InferenceSession, ParseError> {
+        let key = PrefixCache::<InferenceSnapshot>::key(&self.model_id, prefix);
+        if let Some(snapshot) = self.prefix_cache.get(&key) {
+            match InferenceSession::from_snapshot(InferenceSnapshot::clone(&snapshot), self.model.as_ref()) {
+                Ok(session) => {
+                    tracing::debug!("reusing cached prompt prefix {}", key);
+                    return Ok(session);
                 }
+                Err(e) => tracing::warn!("failed to restore cached prompt prefix: {}", e),
             }
-            None => {
-                bail!("failed to infer task")
-            }
+        }
+
+        let mut session = self.model.start_session(self.session_config);
+        session
+            .feed_prompt(
+                self.model.as_ref(),
+                &self.inference_params,
+                prefix,
+                &mut OutputRequest::default(),
+                |_| Ok::<(), Infallible>(()),
+            )
+            .map_err(|e| ParseError::Inference(format!("failed to feed prompt prefix: {}", e)))?;
+
+        if self.prefix_cache.is_enabled() {
+            // SAFETY: the snapshot is copied before the session is used again
+            let snapshot = unsafe { session.get_snapshot() }.to_owned();
+            self.prefix_cache.insert(&key, snapshot);
+        }
+        Ok(session)
+    }
+}
+
+/// Embeds texts with the loaded model, so that agents can be matched without a separate embedding
+/// model. The embedding of a text is the model's embedding of its last token.
+impl Embedder for LlamaParser {
+    fn id(&self) -> String {
+        self.model_id.clone()
+    }
+
+    fn embed(&self, text: &str) -> Result<Vec<f32>, Box<dyn std::error::Error>> {
+        let mut session = self.model.start_session(self.session_config);
+        let mut output_request = OutputRequest {
+            embeddings: Some(Vec::new()),
+            ..Default::default()
+        };
+        session
+            .feed_prompt(
+                self.model.as_ref(),
+                &self.inference_params,
+                text,
+                &mut output_request,
+                |_| Ok::<(), Infallible>(()),
+            )
+            .map_err(|e| format!("failed to embed text: {}", e))?;
+
+        match output_request.embeddings {
+            Some(embeddings) if !embeddings.is_empty() => Ok(embeddings),
+            _ => Err("the model returned no embeddings".into()),
         }
     }
 }
 
-fn construct_prompt(template: &str, agents: &[ConnectedAgent], input: &str) -> String {
-    let mut list_tools = String::new();
-    let mut list_examples = String::new();
+#[tonic::async_trait]
+impl TaskParser for LlamaParser {
+    #[tracing::instrument(name="parse", skip(self, agents))]
+    async fn parse(&self, input: &str, agents: &[ConnectedAgent]) -> Result<Vec<TaskRequest>, ParseError> {
+        let prompt = construct_prompt(&self.templates.get("parse"), agents, input);
+        let text = block_in_place(|| self.infer_action(prompt, &ActionGrammar::for_agents(agents)))?;
+        tracing::info!("llama output: {:?}", text);
+
+        let task = task_from_response(&text, input, agents)?;
+        tracing::debug!("inferred new task: {:?}", task);
+        Ok(vec![task])
+    }
+
+    /// Parses a whole batch of inputs with a single inference using the planning prompt. Inputs
+    /// the model did not map to any command get no tasks.
+    #[tracing::instrument(name="plan", skip(self, agents))]
+    async fn plan(&self, inputs: &[String], agents: &[ConnectedAgent]) -> Result<Vec<Vec<TaskRequest>>, ParseError> {
+        let mut list_tools = String::new();
+        for agent in agents {
+            for command in &agent.commands {
+                list_tools.push_str(&format!("- {}\n", command));
+            }
+        }
 
-    for agent in agents {
-        for command in &agent.commands {
-            list_tools.push_str(&format!("- {}\n", command));
+        let mut list_tasks = String::new();
+        for (i, input) in inputs.iter().enumerate() {
+            list_tasks.push_str(&format!("{}. {}\n", i + 1, input.trim()));
         }
-        list_examples.push('\n');
-        list_examples.push_str(&agent.examples.trim());
+
+        let prompt = Prompt::new(
+            self.templates.render("task-parse", &[("tools", &list_tools), ("tasks", INPUT_MARKER)]),
+            &list_tasks,
+        );
+        let text = block_in_place(|| self.infer(prompt, &["###"]))?;
+        tracing::info!("llama output: {:?}", text);
+
+        let mut planned = vec![Vec::new(); inputs.len()];
+        for line in text.lines().map(str::trim) {
+            let (index, action) = match line.split_once('.') {
+                Some((index, action)) => (index, action),
+                None => continue,
+            };
+
+            let index = match index.trim().parse::<usize>() {
+                Ok(n) if n >= 1 && n <= inputs.len() => n - 1,
+                _ => continue,
+            };
+
+            let action = match parse_action_for(action, agents) {
+                Ok(action) => action,
+                Err(e) => {
+                    tracing::debug!("could not parse planned action for task {}: {}", index + 1, e);
+                    continue;
+                }
+            };
+
+            if planned[index].is_empty() {
+                let task = into_task(action, &inputs[index], agents);
+                tracing::debug!("planned task {}: {:?}", index + 1, task);
+                planned[index].push(task);
+            }
+        }
+
+        Ok(planned)
+    }
+
+    /// Asks the model to fix `task`, which an agent rejected with `error`.
+    #[tracing::instrument(name="correct", skip(self, agents))]
+    async fn correct(
+        &self,
+        input: &str,
+        agents: &[ConnectedAgent],
+        task: &TaskRequest,
+        error: &str,
+    ) -> Result<Option<TaskRequest>, ParseError> {
+        let prompt = Prompt::correction(&self.templates, input, agents, task, error);
+        let text = block_in_place(|| self.infer_action(prompt, &ActionGrammar::for_agents(agents)))?;
+        tracing::info!("llama output: {:?}", text);
+
+        let task = correction_from_response(&text, input, agents)?;
+        tracing::debug!("corrected task: {:?}", task);
+        Ok(task)
     }
 
-    template.format([&list_tools, &list_examples, input.trim()])
+    /// Lets the model answer `input` on its own.
+    #[tracing::instrument(name="answer", skip(self))]
+    async fn answer(&self, input: &str) -> Result<String, ParseError> {
+        let prompt = Prompt::new(self.templates.render("answer", &[("input", INPUT_MARKER)]), input.trim());
+        let text = block_in_place(|| self.infer(prompt, &["Input:", "###"]))?;
+        tracing::info!("llama output: {:?}", text);
+        Ok(text.trim().to_string())
+    }
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -145,3 +656,34 @@ enum LlamaInferenceError {
     #[error("done")]
     Done,
 }
+
+#[cfg(test)]
+mod tests {
+    use llm::ModelArchitecture;
+
+    use crate::error::ParseError;
+
+    use super::{KvMemory, ModelConfig};
+
+    #[test]
+    pub fn test_model_config() {
+        let config = ModelConfig::from_toml(
+            r#"
+architecture = "gptneox"
+context_size = 4096
+kv_memory = "f32"
+temperature = 0.7
+stop_sequences = ["Observation:"]
+"#,
+        )
+        .unwrap();
+        assert_eq!(config.model_architecture().unwrap(), ModelArchitecture::GptNeoX);
+        assert_eq!(config.context_size, 4096);
+        assert_eq!(config.kv_memory, KvMemory::Float32);
+        assert_eq!(config.stop_sequences, vec!["Observation:".to_string()]);
+        assert_eq!(config.max_tokens, ModelConfig::default().max_tokens);
+
+        assert!(matches!(ModelConfig::from_toml("architecture = \"rwkv\""), Err(ParseError::Config(_))));
+        assert!(matches!(ModelConfig::from_toml("temprature = 0.7"), Err(ParseError::Config(_))));
+    }
+}
@@ -1,25 +1,168 @@
 
-use std::{collections::HashMap, net::SocketAddr, sync::Arc};
-use color_eyre::eyre::bail;
-use tonic::{Request, Response, Status};
-
-use async_mutex::Mutex;
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant, SystemTime},
+};
+use futures::future::join_all;
+use tonic::{transport::Endpoint, Code, Request, Response, Status};
 
 use crate::{
+    agent::Backoff,
+    breaker::{BreakerConfig, CircuitBreaker},
+    canonical::{CanonicalizeError, Canonicalizer},
     common::{ConnectedAgent, AgentMatcher},
+    error::{DispatchError, MatchError},
+    registry::{AgentPool, AgentRegistry, Balancing},
+    registry_store::{RegistryStore, StoredAgent},
+    routing::{RouteAction, RoutingRules},
+    schema,
     meeseeks_proto::{
-        self, agent_client::AgentClient, AgentConnectRequest, AgentConnectResponse, AgentInfo,
-        ConnectedAgentInfo, EmptyParams, TaskRequest, TaskResponse,
+        self, agent_client::AgentClient, AgentConnectRequest, AgentConnectResponse,
+        AgentDisconnectRequest, AgentDisconnectResponse, AgentInfo, AgentPoolInfo, ConnectedAgentInfo, EmptyParams, HeartbeatRequest, HeartbeatResponse, TaskRequest,
+        TaskResponse,
     },
 };
 
+/// Controls how the master decides that an agent has died.
+#[derive(Debug, Clone)]
+pub struct HealthConfig {
+    /// How often the master checks when agents were last heard from.
+    pub check_interval: Duration,
+    /// An agent that has not sent a heartbeat for this long is marked unhealthy and no longer
+    /// receives tasks.
+    pub unhealthy_after: Duration,
+    /// An agent that has not sent a heartbeat for this long is removed from the master and the
+    /// matcher. It can rejoin by connecting again.
+    pub evict_after: Duration,
+}
+
+impl Default for HealthConfig {
+    fn default() -> Self {
+        HealthConfig {
+            check_interval: Duration::from_secs(5),
+            unhealthy_after: Duration::from_secs(15),
+            evict_after: Duration::from_secs(60),
+        }
+    }
+}
+
+/// Controls how long the master waits for agents and how it reacts when they fail.
+#[derive(Debug, Clone)]
+pub struct DispatchConfig {
+    /// Timeout for tasks without a more specific one.
+    pub timeout: Duration,
+    /// Timeouts by agent (pool) name. Take precedence over `timeout`.
+    pub agent_timeouts: HashMap<String, Duration>,
+    /// Timeouts by command name. Take precedence over agent timeouts.
+    pub command_timeouts: HashMap<String, Duration>,
+    /// How often a task is sent before giving up. Only timeouts and retryable errors are retried.
+    pub max_attempts: u32,
+    /// Delay between attempts.
+    pub retry_backoff: Backoff,
+    pub breaker: BreakerConfig,
+}
+
+impl Default for DispatchConfig {
+    fn default() -> Self {
+        DispatchConfig {
+            timeout: Duration::from_secs(30),
+            agent_timeouts: HashMap::new(),
+            command_timeouts: HashMap::new(),
+            max_attempts: 3,
+            retry_backoff: Backoff {
+                initial: Duration::from_millis(200),
+                max: Duration::from_secs(2),
+            },
+            breaker: BreakerConfig::default(),
+        }
+    }
+}
+
+impl DispatchConfig {
+    pub fn timeout_for(&self, agent: &str, command: &str) -> Duration {
+        self.command_timeouts
+            .get(command)
+            .or_else(|| self.agent_timeouts.get(agent))
+            .copied()
+            .unwrap_or(self.timeout)
+    }
+}
+
+/// Controls which agents the master considers for an input.
+#[derive(Debug, Clone)]
+pub struct MatchConfig {
+    /// How many of the best matching pools are handed to the parser.
+    pub candidates: usize,
+    /// Pools whose score is below this are never considered.
+    pub min_score: f32,
+}
+
+impl Default for MatchConfig {
+    fn default() -> Self {
+        MatchConfig {
+            candidates: 3,
+            min_score: 0.0,
+        }
+    }
+}
+
+/// How long the master waits for a restored agent to accept a connection.
+const REACHABILITY_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// What happens to inputs no agent applies to.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum Fallback {
+    /// Ask the user to rephrase the input.
+    #[default]
+    Rephrase,
+    /// Let the parser's model answer the input without a tool.
+    Answer,
+    /// Offer the input to the pool with this name alone.
+    Agent(String),
+}
+
+impl FromStr for Fallback {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            Some(("agent", pool)) if !pool.is_empty() => Ok(Fallback::Agent(pool.to_string())),
+            None if s == "rephrase" => Ok(Fallback::Rephrase),
+            None if s == "answer" => Ok(Fallback::Answer),
+            _ => Err(format!("unknown fallback {}. expected rephrase, answer or agent:<name>", s)),
+        }
+    }
+}
+
+/// An agent the master considers for an input, with the score the matcher gave its pool.
+#[derive(Clone)]
+pub struct Candidate {
+    pub agent: ConnectedAgent,
+    pub score: f32,
+    /// The routing rule that chose the agent instead of the matcher.
+    pub rule: Option<String>,
+}
+
 pub struct MasterAgent<Matcher: AgentMatcher> {
     #[allow(dead_code)]
     name: String,
     #[allow(dead_code)]
     addr: SocketAddr,
-    agents: Arc<Mutex<HashMap<String, ConnectedAgent>>>,
+    agents: AgentRegistry,
     matcher: Matcher,
+    health: HealthConfig,
+    dispatch: DispatchConfig,
+    matching: MatchConfig,
+    routing: RoutingRules,
+    canonicalizer: Canonicalizer,
+    store: Option<RegistryStore>,
+    instance_id: String,
 }
 
 impl<Matcher: AgentMatcher + Send + Sync> MasterAgent<Matcher> {
@@ -28,60 +171,454 @@ impl<Matcher: AgentMatcher + Send + Sync> MasterAgent<Matcher> {
             name,
             addr,
             matcher,
-            agents: Mutex::new(HashMap::new()).into(),
-        }
-    }
-    
-    pub async fn match_agent(&self, input_task: &str) -> color_eyre::Result<ConnectedAgent> {
-        match self.matcher.match_agent(input_task).await {
-            Ok(agent_name) => {
-                let connected_agents = self.agents.lock().await;
-                match connected_agents.get(&agent_name) {
-                    Some(agent) => Ok(agent.clone()),
-                    None => {
-                        bail!("agent with name {} not found", agent_name);
-                    }
+            agents: AgentRegistry::new(Balancing::default()),
+            health: HealthConfig::default(),
+            dispatch: DispatchConfig::default(),
+            matching: MatchConfig::default(),
+            routing: RoutingRules::default(),
+            canonicalizer: Canonicalizer::default(),
+            store: None,
+            instance_id: format!("{:016x}", rand::random::<u64>()),
+        }
+    }
+
+    /// Identifies this run of the master. Agents use it to notice that the master has restarted
+    /// and lost their registration.
+    pub fn instance_id(&self) -> &str {
+        &self.instance_id
+    }
+
+    pub fn with_health_config(mut self, health: HealthConfig) -> Self {
+        self.health = health;
+        self
+    }
+
+    pub fn with_dispatch_config(mut self, dispatch: DispatchConfig) -> Self {
+        self.dispatch = dispatch;
+        self
+    }
+
+    pub fn with_match_config(mut self, matching: MatchConfig) -> Self {
+        self.matching = matching;
+        self
+    }
+
+    /// Rules that are checked before the matcher, see [`RoutingRules`].
+    pub fn with_routing_rules(mut self, routing: RoutingRules) -> Self {
+        self.routing = routing;
+        self
+    }
+
+    pub fn with_canonicalizer(mut self, canonicalizer: Canonicalizer) -> Self {
+        self.canonicalizer = canonicalizer;
+        self
+    }
+
+    pub fn with_balancing(mut self, balancing: Balancing) -> Self {
+        self.agents = AgentRegistry::new(balancing);
+        self
+    }
+
+    /// Persists the connected agents to `store` whenever they change. See [`MasterAgent::restore`].
+    pub fn with_registry_store(mut self, store: RegistryStore) -> Self {
+        self.store = Some(store);
+        self
+    }
+
+    /// Loads the agents persisted by an earlier run, adds their pools to the matcher again in case
+    /// it lost them as well, and checks which of them can be reached. Restored agents receive tasks
+    /// once they were reached or sent a heartbeat. The ones that do neither are evicted like any
+    /// other silent agent. Returns the number of restored agents.
+    pub async fn restore(&self) -> usize {
+        let stored = match &self.store {
+            Some(store) => store.load(),
+            None => return 0,
+        };
+
+        let mut restored = Vec::new();
+        for StoredAgent {
+            registration,
+            registered_at,
+        } in stored
+        {
+            let mut agent = match self.agent_from_registration(registration) {
+                Ok(agent) => agent,
+                Err(e) => {
+                    tracing::warn!("ignoring stored agent: {}", e);
+                    continue;
+                }
+            };
+            agent.healthy = false;
+            agent.registered_at = registered_at;
+            self.agents.insert(agent.clone());
+            restored.push(agent);
+        }
+
+        for agent in self.agents.list() {
+            if let Err(e) = self.matcher.add_agent(agent.clone()).await {
+                tracing::error!("failed to add restored agent {} to matcher: {}", agent.pool, e);
+            }
+        }
+
+        let reachable = join_all(restored.iter().map(|agent| async move {
+            let endpoint = Endpoint::from_shared(agent.addr.clone()).ok()?;
+            tokio::time::timeout(REACHABILITY_TIMEOUT, endpoint.connect()).await.ok()?.ok()
+        }))
+        .await;
+        for (agent, channel) in restored.iter().zip(reachable) {
+            match channel {
+                Some(_) => {
+                    self.agents.heartbeat(&agent.name, &agent.addr);
+                }
+                None => tracing::warn!(
+                    "restored agent {} at {} is not reachable. it receives no tasks until it sends a heartbeat",
+                    agent.name,
+                    agent.addr
+                ),
+            }
+        }
+
+        if let Some(store) = &self.store {
+            tracing::info!("restored {} agents from {}", restored.len(), store.path().display());
+        }
+        restored.len()
+    }
+
+    /// Writes the connected agents to the registry store, if there is one.
+    fn persist(&self) {
+        if let Some(store) = &self.store {
+            let agents: Vec<ConnectedAgent> = self
+                .agents
+                .pools()
+                .iter()
+                .flat_map(|pool| pool.replicas().iter().cloned())
+                .collect();
+            store.save(&agents);
+        }
+    }
+
+    /// The agent described by a registration, connected lazily. Fails if its address is invalid.
+    fn agent_from_registration(&self, req: AgentConnectRequest) -> Result<ConnectedAgent, String> {
+        let schemas = match req.schemas.is_empty() {
+            true => schema::schemas_from_commands(&req.commands),
+            false => req.schemas,
+        };
+        let channel = Endpoint::from_shared(req.from.clone())
+            .map_err(|_| format!("invalid agent address: {}", req.from))?
+            .connect_lazy();
+        let pool = match req.tool.is_empty() {
+            true => req.name.clone(),
+            false => req.tool,
+        };
+
+        Ok(ConnectedAgent {
+            name: req.name,
+            pool,
+            description: req.description,
+            addr: req.from,
+            examples: req.examples,
+            commands: req.commands,
+            schemas,
+            client: AgentClient::new(channel),
+            last_seen: Instant::now(),
+            registered_at: SystemTime::now(),
+            healthy: true,
+            outstanding: Arc::default(),
+            breaker: Arc::new(CircuitBreaker::new(self.dispatch.breaker.clone())),
+        })
+    }
+
+    /// The healthy agents of the pools that best match `input`, best first. Pools scoring below
+    /// the configured minimum are skipped. Fails with [`MatchError::NoMatch`] if no pool applies to
+    /// the input and with [`MatchError::Unavailable`] if the pools that do have no healthy agent.
+    ///
+    /// A routing rule that matches the input takes precedence over the matcher: the input either
+    /// goes to the rule's agent alone or is rejected.
+    pub async fn match_agents(&self, input: &str) -> Result<Vec<Candidate>, MatchError> {
+        if let Some(rule) = self.routing.route(input) {
+            match &rule.action {
+                RouteAction::Block(reason) => {
+                    tracing::info!("input {:?} blocked by routing rule {}", input, rule.name);
+                    return Err(MatchError::Blocked {
+                        rule: rule.name.clone(),
+                        reason: reason.clone(),
+                    });
                 }
+                RouteAction::Agent(pool) => {
+                    tracing::info!("input {:?} routed to {} by routing rule {}", input, pool, rule.name);
+                    return match self.agents.get(pool) {
+                        Some(agent) if agent.is_healthy() => Ok(vec![Candidate {
+                            agent,
+                            score: 1.0,
+                            rule: Some(rule.name.clone()),
+                        }]),
+                        Some(_) => Err(MatchError::Unavailable(format!(
+                            "routing rule {} sends the input to {}, which is not responding",
+                            rule.name, pool
+                        ))),
+                        None => Err(MatchError::Unavailable(format!(
+                            "routing rule {} sends the input to {}, which is not connected",
+                            rule.name, pool
+                        ))),
+                    };
+                }
+            }
+        }
+
+        let matches = self
+            .matcher
+            .match_agents(input, self.matching.candidates.max(1))
+            .await
+            .map_err(|e| MatchError::Matcher(e.to_string()))?;
+
+        let mut candidates = Vec::new();
+        let mut unavailable = Vec::new();
+        for m in &matches {
+            if m.score < self.matching.min_score {
+                tracing::debug!("skipping agent {} with score {:.2}", m.name, m.score);
+                continue;
+            }
+            match self.agents.get(&m.name) {
+                Some(agent) if agent.is_healthy() => candidates.push(Candidate {
+                    agent,
+                    score: m.score,
+                    rule: None,
+                }),
+                Some(_) => unavailable.push(format!("{} is not responding", m.name)),
+                None => unavailable.push(format!("{} is not connected", m.name)),
             }
-            Err(e) => {
-                bail!("failed to match input task to agent: {}", e);
+        }
+
+        // pools with equal scores are ordered by name, so that the first of them wins a tie
+        candidates.sort_by(|a, b| b.score.total_cmp(&a.score).then_with(|| a.agent.pool.cmp(&b.agent.pool)));
+        match (candidates.is_empty(), unavailable.is_empty()) {
+            (false, _) => Ok(candidates),
+            (true, true) => {
+                tracing::info!("no agent matches input {:?}", input);
+                Err(MatchError::NoMatch(matches))
             }
+            (true, false) => Err(MatchError::Unavailable(format!(
+                "no matching agent can take the task: {}",
+                unavailable.join(", ")
+            ))),
         }
-        
+    }
 
+    /// The healthy agent of `pool` as the only candidate, for inputs no pool applies to.
+    pub fn fallback_agent(&self, pool: &str) -> Result<Candidate, MatchError> {
+        match self.agents.get(pool) {
+            Some(agent) if agent.is_healthy() => Ok(Candidate {
+                agent,
+                score: 0.0,
+                rule: None,
+            }),
+            Some(_) => Err(MatchError::Unavailable(format!("fallback agent {} is not responding", pool))),
+            None => Err(MatchError::Unavailable(format!("fallback agent {} is not connected", pool))),
+        }
     }
 
-    pub async fn send_task_to_agent<'a>(
-        &'a self,
-        name: &str,
+    /// Maps the instruction of a parsed task onto a command one of `agents` advertised and returns
+    /// the task with the canonical instruction together with that agent. `agents` are the
+    /// candidates the parser was offered, so a task never goes to an agent the input was not
+    /// routed to. Fails if no command is close enough, so that misspelled instructions are caught
+    /// before they are sent.
+    pub fn canonicalize(
+        &self,
         task: TaskRequest,
-    ) -> Result<TaskResponse, Box<dyn std::error::Error>> {
-        let mut agents = self.agents.lock().await;
+        agents: &[ConnectedAgent],
+    ) -> Result<(TaskRequest, ConnectedAgent), CanonicalizeError> {
+        let canonical = self.canonicalizer.canonicalize(&task.instruction, agents)?;
+        if canonical.command != task.instruction {
+            tracing::info!(
+                "canonicalized instruction {} to {} (confidence {:.2})",
+                task.instruction,
+                canonical.command,
+                canonical.confidence
+            );
+        }
+
+        let agent = canonical.agent.clone();
+        let task = TaskRequest {
+            instruction: canonical.command,
+            ..task
+        };
+        Ok((task, agent))
+    }
+
+    /// Sends a task to a replica of the named pool. Replicas that cannot be reached are marked
+    /// unhealthy and the task is sent to another one. Timeouts, retryable errors and failures the
+    /// agent marks as retryable are retried with backoff, and replicas that keep failing are
+    /// skipped until their circuit half-opens. The registry is not locked while the agent runs the
+    /// task, so any number of tasks can be in flight at once, including several on one agent.
+    pub async fn send_task_to_agent(&self, name: &str, task: TaskRequest) -> Result<TaskResponse, DispatchError> {
+        if !self.agents.contains(name) {
+            return Err(DispatchError::NotFound(name.to_string()));
+        }
+
+        let give_up = |attempts, error| match attempts {
+            1 => error,
+            _ => DispatchError::Exhausted {
+                attempts,
+                error: Box::new(error),
+            },
+        };
+
+        let mut tried = Vec::new();
+        let mut attempts = 0;
+        let mut last_error = None;
+        loop {
+            let agent = match self.agents.pick(name, &tried) {
+                Some(agent) => agent,
+                // every replica was tried. report why the last one failed
+                None if last_error.is_some() => return Err(give_up(attempts, last_error.unwrap())),
+                None => {
+                    // a healthy replica that was not picked has an open circuit
+                    let healthy = matches!(self.agents.get(name), Some(agent) if agent.is_healthy());
+                    return Err(match healthy {
+                        true => DispatchError::CircuitOpen(name.to_string()),
+                        false => DispatchError::Unavailable(name.to_string()),
+                    });
+                }
+            };
 
-        let agent = agents
-            .get_mut(name)
-            .ok_or("failed to get client with name: {name}")?;
+            attempts += 1;
+            let error = match self.exec_on(&agent, task.clone()).await {
+                Ok(res) if res.is_retryable() && attempts < self.dispatch.max_attempts => {
+                    let delay = self.dispatch.retry_backoff.delay(attempts - 1);
+                    tracing::warn!("task {} on agent {} failed: {}. retrying in {:?}", task.instruction, name, res.failure_message(), delay);
+                    tokio::time::sleep(delay).await;
+                    continue;
+                }
+                Ok(res) => return Ok(res),
+                Err(e) => e,
+            };
+
+            if error.is_transport() {
+                tracing::warn!("agent {} at {} is unreachable: {}. trying another replica", agent.name, agent.addr, error);
+                self.agents.mark_unhealthy(name, &agent.addr);
+                tried.push(agent.addr);
+            } else if !error.is_retryable() {
+                return Err(error);
+            }
 
-        if agent.client.is_none() {
-            tracing::debug!("agent \"{}\" client is not connected. connecting...", name);
-            let client = AgentClient::connect(agent.addr.clone()).await?;
-            agent.client = Some(client);
+            if attempts >= self.dispatch.max_attempts {
+                return Err(give_up(attempts, error));
+            }
+
+            if !error.is_transport() {
+                let delay = self.dispatch.retry_backoff.delay(attempts - 1);
+                tracing::warn!("task {} on agent {} failed: {}. retrying in {:?}", task.instruction, name, error, delay);
+                tokio::time::sleep(delay).await;
+            }
+            last_error = Some(error);
         }
-        tracing::debug!("agent \"{}\" client connected.", name);
+    }
 
-        let mut client = agent.client.take().unwrap();
+    /// Sends a task to one replica, with a deadline, and records the outcome in its circuit
+    /// breaker. Responses with a retryable error count as failures, so that a replica that keeps
+    /// rejecting tasks as rate limited is skipped like one that cannot be reached.
+    async fn exec_on(&self, agent: &ConnectedAgent, task: TaskRequest) -> Result<TaskResponse, DispatchError> {
+        let task = schema::validate(task, &agent.schemas)?;
 
-        let result = client.exec_task(task).await?;
+        let permit = match agent.breaker.try_acquire() {
+            Some(permit) => permit,
+            None => return Err(DispatchError::CircuitOpen(agent.pool.clone())),
+        };
+
+        let timeout = self.dispatch.timeout_for(&agent.pool, &task.instruction);
+        let mut request = Request::new(task);
+        request.set_timeout(timeout);
 
-        agent.client = Some(client);
+        let mut client = agent.client.clone();
+        let _outstanding = Outstanding::start(&agent.outstanding);
+        let result = match tokio::time::timeout(timeout, client.exec_task(request)).await {
+            Ok(Ok(res)) => Ok(res.into_inner()),
+            Ok(Err(status)) if status.code() == Code::Cancelled || status.code() == Code::DeadlineExceeded => {
+                Err(DispatchError::TimedOut {
+                    agent: agent.pool.clone(),
+                    timeout,
+                })
+            }
+            Ok(Err(status)) => Err(DispatchError::Rpc {
+                agent: agent.pool.clone(),
+                status,
+            }),
+            Err(_) => Err(DispatchError::TimedOut {
+                agent: agent.pool.clone(),
+                timeout,
+            }),
+        };
 
-        Ok(result.into_inner())
+        match &result {
+            Ok(res) if res.is_retryable() => permit.failure(),
+            Ok(_) => permit.success(),
+            // the agent is fine, it only rejected the task
+            Err(e) if e.is_correctable() => permit.success(),
+            Err(_) => permit.failure(),
+        }
+        result
     }
 
+    /// One agent per pool.
     pub fn list_agents(&self) -> Vec<ConnectedAgent> {
-        let agents = futures::executor::block_on(self.agents.lock());
+        self.agents.list()
+    }
+
+    /// Changes whenever an agent joins or leaves. See [`AgentRegistry::generation`].
+    pub fn agents_generation(&self) -> u64 {
+        self.agents.generation()
+    }
+
+    pub fn list_pools(&self) -> Vec<AgentPool> {
+        self.agents.pools()
+    }
+
+    /// Marks agents that missed their heartbeats as unhealthy and evicts the ones that have been
+    /// silent for longer than the grace period.
+    pub async fn check_health(&self) {
+        let generation = self.agents.generation();
+        let evicted = self
+            .agents
+            .sweep(self.health.unhealthy_after, self.health.evict_after);
+        if self.agents.generation() != generation {
+            self.persist();
+        }
+
+        for name in evicted {
+            if let Err(e) = self.matcher.remove_agent(&name).await {
+                tracing::error!("failed to remove agent {} from matcher: {}", name, e);
+            }
+        }
+    }
+}
+
+/// Counts a task as outstanding on an agent until it is dropped.
+struct Outstanding<'a>(&'a AtomicUsize);
+
+impl<'a> Outstanding<'a> {
+    fn start(count: &'a AtomicUsize) -> Self {
+        count.fetch_add(1, Ordering::Relaxed);
+        Outstanding(count)
+    }
+}
+
+impl Drop for Outstanding<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
 
-        agents.values().map(|x| x.clone()).collect()
+impl<Matcher: AgentMatcher + Send + Sync + 'static> MasterAgent<Matcher> {
+    /// Spawns a task that runs `check_health` periodically.
+    pub fn spawn_health_monitor(self: &Arc<Self>) -> tokio::task::JoinHandle<()> {
+        let master = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(master.health.check_interval);
+            loop {
+                interval.tick().await;
+                master.check_health().await;
+            }
+        })
     }
 }
 
@@ -91,47 +628,555 @@ impl<Matcher: AgentMatcher + Send + Sync + 'static> meeseeks_proto::master_agent
         &self,
         request: Request<AgentConnectRequest>,
     ) -> Result<Response<AgentConnectResponse>, Status> {
-        let req = request.into_inner();
-        let agent = ConnectedAgent {
-            name: req.name.clone(),
-            description: req.description,
-            addr: req.from,
-            examples: req.examples,
-            commands: req.commands,
-            client: None,
-        };
+        let agent = self.agent_from_registration(request.into_inner()).map_err(Status::invalid_argument)?;
+        let (name, addr, pool) = (agent.name.clone(), agent.addr.clone(), agent.pool.clone());
 
-        let _agent_addr = agent.addr.clone();
-        let mut agents = self.agents.lock().await;
-        self.matcher.add_agent(agent.clone()).await.map_err(|_| tonic::Status::new(tonic::Code::Unavailable, "failed to add agent to tooldb"))?;
-        agents.insert(req.name, agent);
-        drop(agents);
+        // an agent that registers again may advertise different commands, so the matcher is always
+        // updated
+        self.matcher
+            .add_agent(agent.clone())
+            .await
+            .map_err(|e| Status::unavailable(format!("failed to add agent to matcher: {}", e)))?;
+        match self.agents.insert(agent) {
+            Some(_) => tracing::info!("agent {} at {} registered again. replacing it", name, addr),
+            None => tracing::info!("agent {} at {} joined pool {}", name, addr, pool),
+        }
+        self.persist();
 
         let res = AgentConnectResponse {
             status: meeseeks_proto::Status::Success.into(),
             message: "".into(),
+            instance_id: self.instance_id.clone(),
         };
 
         Ok(Response::new(res))
     }
 
+    async fn disconnect_from_master(
+        &self,
+        request: Request<AgentDisconnectRequest>,
+    ) -> Result<Response<AgentDisconnectResponse>, Status> {
+        let req = request.into_inner();
+
+        let (agent, pool_empty) = match self.agents.remove(&req.name, &req.from) {
+            Some(removed) => removed,
+            None => return Err(Status::not_found(format!("agent {} is not connected", req.name))),
+        };
+
+        if pool_empty {
+            self.matcher
+                .remove_agent(&agent.pool)
+                .await
+                .map_err(|e| Status::unavailable(format!("failed to remove agent from matcher: {}", e)))?;
+        }
+        tracing::info!("agent {} at {} disconnected", req.name, req.from);
+        self.persist();
+
+        Ok(Response::new(AgentDisconnectResponse {
+            status: meeseeks_proto::Status::Success.into(),
+            message: "".into(),
+        }))
+    }
+
     async fn connected_agents(
         &self,
         _: Request<EmptyParams>,
     ) -> Result<Response<ConnectedAgentInfo>, Status> {
-        let agents = self.agents.lock().await;
+        let info = |agent: &ConnectedAgent| AgentInfo {
+            name: agent.name.to_string(),
+            description: agent.description.to_string(),
+            healthy: agent.healthy,
+            addr: agent.addr.to_string(),
+            pool: agent.pool.to_string(),
+            outstanding: agent.outstanding() as u32,
+        };
 
         let mut connected_agents = Vec::new();
-        for (name, agent) in agents.iter() {
-            let info = AgentInfo {
-                name: name.to_string(),
-                description: agent.description.to_string(),
-            };
-            connected_agents.push(info);
+        let mut pools = Vec::new();
+        for pool in self.agents.pools() {
+            let replicas: Vec<AgentInfo> = pool.replicas().iter().map(info).collect();
+            connected_agents.extend(replicas.iter().cloned());
+            pools.push(AgentPoolInfo {
+                name: pool.name().to_string(),
+                description: pool
+                    .representative()
+                    .map(|agent| agent.description.to_string())
+                    .unwrap_or_default(),
+                replicas,
+            });
         }
 
         Ok(Response::new(ConnectedAgentInfo {
             agents: connected_agents,
+            pools,
+        }))
+    }
+
+    async fn heartbeat(
+        &self,
+        request: Request<HeartbeatRequest>,
+    ) -> Result<Response<HeartbeatResponse>, Status> {
+        let req = request.into_inner();
+        let registered = self.agents.heartbeat(&req.name, &req.from);
+
+        Ok(Response::new(HeartbeatResponse {
+            registered,
+            instance_id: self.instance_id.clone(),
         }))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs,
+        net::SocketAddr,
+        process,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::{Duration, Instant},
+    };
+
+    use futures::future::join_all;
+    use tokio_stream::wrappers::TcpListenerStream;
+    use tonic::{transport::Server, Code, Request};
+
+    use crate::{
+        agent::{Agent, Backoff},
+        bm25::Bm25Matcher,
+        breaker::BreakerConfig,
+        error::{DispatchError, MatchError, MeeseeksError},
+        common::{AgentMatch, AgentMatcher, ConnectedAgent, TaskExecutor},
+        meeseeks_proto::{
+            agent_server::AgentServer, master_agent_server::MasterAgent as _, AgentConnectRequest,
+            CommandSchema, ErrorKind, ParameterSchema, Status, TaskRequest, TaskResponse,
+        },
+    };
+
+    use super::{DispatchConfig, MasterAgent, MatchConfig, RegistryStore, RoutingRules};
+
+    #[derive(Default)]
+    struct Sleeper {
+        /// How often `busy` was called.
+        busy_calls: Arc<AtomicUsize>,
+        /// How many `sleep` tasks are running right now, and the most that ever ran at once.
+        sleeping: Arc<AtomicUsize>,
+        max_sleeping: Arc<AtomicUsize>,
+    }
+
+    #[tonic::async_trait]
+    impl TaskExecutor for Sleeper {
+        async fn exec(&self, task: TaskRequest) -> TaskResponse {
+            if task.instruction == "busy" {
+                self.busy_calls.fetch_add(1, Ordering::Relaxed);
+                return TaskResponse::failure(MeeseeksError::RateLimited("try again later".to_string()));
+            }
+
+            let ms = task.number_arg("ms").unwrap_or_default();
+            let sleeping = self.sleeping.fetch_add(1, Ordering::SeqCst) + 1;
+            self.max_sleeping.fetch_max(sleeping, Ordering::SeqCst);
+            tokio::time::sleep(Duration::from_millis(ms as u64)).await;
+            self.sleeping.fetch_sub(1, Ordering::SeqCst);
+            TaskResponse::success(format!("slept for {}ms", ms))
+        }
+
+        fn commands<'a>(&self) -> &'a [&'a str] {
+            &["sleep(ms)", "busy()"]
+        }
+
+        fn examples(&self) -> String {
+            String::new()
+        }
+
+        fn schemas(&self) -> Vec<CommandSchema> {
+            vec![
+                CommandSchema {
+                    name: "sleep".to_string(),
+                    description: "sleep for a number of milliseconds".to_string(),
+                    parameters: vec![ParameterSchema::number("ms", "", true)],
+                    aliases: Vec::new(),
+                },
+                CommandSchema {
+                    name: "busy".to_string(),
+                    description: "always fails with a retryable error".to_string(),
+                    parameters: Vec::new(),
+                    aliases: Vec::new(),
+                },
+            ]
+        }
+    }
+
+    struct OnlyAgent;
+
+    #[tonic::async_trait]
+    impl AgentMatcher for OnlyAgent {
+        async fn match_agents(&self, _: &str, _: usize) -> Result<Vec<AgentMatch>, Box<dyn std::error::Error>> {
+            Ok(vec![AgentMatch {
+                name: "sleeper".to_string(),
+                score: 1.0,
+            }])
+        }
+
+        async fn add_agent(&self, _: ConnectedAgent) -> Result<(), Box<dyn std::error::Error>> {
+            Ok(())
+        }
+
+        async fn remove_agent(&self, _: &str) -> Result<(), Box<dyn std::error::Error>> {
+            Ok(())
+        }
+    }
+
+    async fn serve_sleeper() -> String {
+        serve(Sleeper::default()).await
+    }
+
+    /// Serves `sleeper` on a free local port and returns its address.
+    async fn serve(sleeper: Sleeper) -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = format!("http://{}", listener.local_addr().unwrap());
+        let agent = Agent::new(
+            "sleeper".to_string(),
+            "sleeps".to_string(),
+            addr.clone(),
+            sleeper,
+            vec!["sleep(ms)".to_string()],
+        );
+        tokio::spawn(
+            Server::builder()
+                .add_service(AgentServer::new(agent))
+                .serve_with_incoming(TcpListenerStream::new(listener)),
+        );
+        addr
+    }
+
+    async fn register(master: &Arc<MasterAgent<OnlyAgent>>, addr: String) {
+        master
+            .connect_to_master(Request::new(AgentConnectRequest {
+                name: "sleeper".to_string(),
+                from: addr,
+                schemas: Sleeper::default().schemas(),
+                ..Default::default()
+            }))
+            .await
+            .unwrap();
+    }
+
+    fn master() -> Arc<MasterAgent<OnlyAgent>> {
+        master_with(DispatchConfig::default())
+    }
+
+    fn master_with(dispatch: DispatchConfig) -> Arc<MasterAgent<OnlyAgent>> {
+        Arc::new(
+            MasterAgent::new("master".to_string(), SocketAddr::from(([127, 0, 0, 1], 0)), OnlyAgent)
+                .with_dispatch_config(dispatch),
+        )
+    }
+
+    fn sleep_task(ms: u128) -> TaskRequest {
+        TaskRequest {
+            instruction: "sleep".to_string(),
+            args: vec![ms.to_string()],
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    pub async fn test_concurrent_tasks_do_not_serialize() {
+        let master = master();
+        let sleeper = Sleeper::default();
+        let max_sleeping = sleeper.max_sleeping.clone();
+        register(&master, serve(sleeper).await).await;
+
+        let tasks = 20;
+        let delay = Duration::from_millis(200);
+        let start = Instant::now();
+        let results = join_all((0..tasks).map(|_| master.send_task_to_agent("sleeper", sleep_task(delay.as_millis())))).await;
+        let elapsed = start.elapsed();
+
+        assert!(results.iter().all(|res| matches!(res, Ok(res) if res.status() == Status::Success)));
+        let max_sleeping = max_sleeping.load(Ordering::SeqCst);
+        assert!(max_sleeping > 1, "tasks ran one at a time");
+        assert!(elapsed < delay * tasks, "{} tasks took {:?}, at most {} at once", tasks, elapsed, max_sleeping);
+    }
+
+    #[tokio::test]
+    pub async fn test_match_agents() {
+        let master = master();
+        let err = master.match_agents("sleep for 10ms").await.err().unwrap();
+        assert!(matches!(err, MatchError::Unavailable(_)), "the matched agent is not connected: {}", err);
+
+        register(&master, "http://127.0.0.1:9001".to_string()).await;
+        let candidates = master.match_agents("sleep for 10ms").await.unwrap();
+        assert_eq!(candidates.len(), 1);
+        assert_eq!(candidates[0].agent.pool(), "sleeper");
+        assert_eq!(candidates[0].score, 1.0);
+
+        let strict = Arc::new(
+            MasterAgent::new("master".to_string(), SocketAddr::from(([127, 0, 0, 1], 0)), OnlyAgent).with_match_config(
+                MatchConfig {
+                    min_score: 1.5,
+                    ..Default::default()
+                },
+            ),
+        );
+        register(&strict, "http://127.0.0.1:9001".to_string()).await;
+        let err = strict.match_agents("sleep for 10ms").await.err().unwrap();
+        assert!(matches!(err, MatchError::NoMatch(_)), "{}", err);
+        assert!(err.to_string().contains("sleeper (1.00)"), "{}", err);
+    }
+
+    #[tokio::test]
+    pub async fn test_routing_rules_take_precedence() {
+        let rules = RoutingRules::from_toml(
+            r#"
+[[rules]]
+name = "naps"
+prefix = "nap"
+agent = "sleeper"
+
+[[rules]]
+name = "no secrets"
+keyword = "secret"
+block = "not allowed"
+priority = 1
+
+[[rules]]
+name = "math"
+regex = '^what is [0-9]'
+agent = "calculator"
+"#,
+        )
+        .unwrap();
+        let master = Arc::new(
+            MasterAgent::new("master".to_string(), SocketAddr::from(([127, 0, 0, 1], 0)), OnlyAgent)
+                .with_routing_rules(rules),
+        );
+        register(&master, "http://127.0.0.1:9001".to_string()).await;
+
+        let candidates = master.match_agents("nap for 10ms").await.unwrap();
+        assert_eq!(candidates[0].rule.as_deref(), Some("naps"));
+        assert!(master.match_agents("sleep for 10ms").await.unwrap()[0].rule.is_none());
+
+        let err = master.match_agents("nap and tell me a secret").await.err().unwrap();
+        assert_eq!(err.to_string(), "blocked by routing rule no secrets: not allowed");
+        let err = master.match_agents("what is 1 + 1").await.err().unwrap();
+        assert!(err.to_string().contains("calculator, which is not connected"), "{}", err);
+    }
+
+    #[tokio::test]
+    pub async fn test_registering_again_updates_the_matcher() {
+        let master = MasterAgent::new("master".to_string(), SocketAddr::from(([127, 0, 0, 1], 0)), Bm25Matcher::default());
+        let master = Arc::new(master);
+        for description in ["sleeps", "takes naps"] {
+            master
+                .connect_to_master(Request::new(AgentConnectRequest {
+                    name: "sleeper".to_string(),
+                    from: "http://127.0.0.1:9001".to_string(),
+                    description: description.to_string(),
+                    ..Default::default()
+                }))
+                .await
+                .unwrap();
+        }
+
+        let matches = master.matcher.match_agents("how long are your naps", 1).await.unwrap();
+        assert_eq!(matches.len(), 1);
+        assert_eq!(matches[0].name, "sleeper");
+    }
+
+    #[tokio::test]
+    pub async fn test_failover_to_another_replica() {
+        let master = master();
+        let dead = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let dead_addr = format!("http://{}", dead.local_addr().unwrap());
+        drop(dead);
+        register(&master, dead_addr.clone()).await;
+        register(&master, serve_sleeper().await).await;
+
+        let pools = master.list_pools();
+        assert_eq!(pools.len(), 1);
+        assert_eq!(pools[0].replicas().len(), 2);
+
+        for _ in 0..4 {
+            let res = master.send_task_to_agent("sleeper", sleep_task(1)).await.unwrap();
+            assert_eq!(res.status(), Status::Success);
+        }
+
+        let pools = master.list_pools();
+        let dead = pools[0].replicas().iter().find(|agent| agent.addr() == dead_addr).unwrap();
+        assert!(!dead.is_healthy());
+    }
+
+    #[tokio::test]
+    pub async fn test_timeouts_and_circuit_breaking() {
+        let master = master_with(DispatchConfig {
+            timeout: Duration::from_millis(50),
+            agent_timeouts: [("sleeper".to_string(), Duration::from_millis(100))].into(),
+            max_attempts: 2,
+            retry_backoff: Backoff {
+                initial: Duration::from_millis(1),
+                max: Duration::from_millis(1),
+            },
+            breaker: BreakerConfig {
+                failure_threshold: 2,
+                cooldown: Duration::from_secs(60),
+            },
+            ..Default::default()
+        });
+        register(&master, serve_sleeper().await).await;
+
+        let res = master.send_task_to_agent("sleeper", sleep_task(10)).await.unwrap();
+        assert_eq!(res.status(), Status::Success);
+
+        let err = master.send_task_to_agent("sleeper", sleep_task(1000)).await.unwrap_err();
+        assert!(matches!(err, DispatchError::Exhausted { attempts: 2, .. }), "{}", err);
+        assert!(matches!(err.last(), DispatchError::TimedOut { .. }), "{}", err);
+
+        let err = master.send_task_to_agent("sleeper", sleep_task(10)).await.unwrap_err();
+        assert!(matches!(err, DispatchError::CircuitOpen(_)), "{}", err);
+    }
+
+    #[tokio::test]
+    pub async fn test_retry_retryable_task_errors() {
+        let master = master_with(DispatchConfig {
+            retry_backoff: Backoff {
+                initial: Duration::from_millis(1),
+                max: Duration::from_millis(1),
+            },
+            ..Default::default()
+        });
+        let sleeper = Sleeper::default();
+        let busy_calls = sleeper.busy_calls.clone();
+        register(&master, serve(sleeper).await).await;
+
+        let res = master
+            .send_task_to_agent(
+                "sleeper",
+                TaskRequest {
+                    instruction: "busy".to_string(),
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+        assert_eq!(res.status(), Status::Failure);
+        assert_eq!(res.error.unwrap().kind(), ErrorKind::RateLimited);
+        assert_eq!(busy_calls.load(Ordering::Relaxed), 3);
+    }
+
+    #[tokio::test]
+    pub async fn test_retryable_task_errors_open_the_circuit() {
+        let master = master_with(DispatchConfig {
+            max_attempts: 1,
+            breaker: BreakerConfig {
+                failure_threshold: 2,
+                cooldown: Duration::from_secs(60),
+            },
+            ..Default::default()
+        });
+        let sleeper = Sleeper::default();
+        let busy_calls = sleeper.busy_calls.clone();
+        register(&master, serve(sleeper).await).await;
+
+        let busy = TaskRequest {
+            instruction: "busy".to_string(),
+            ..Default::default()
+        };
+        for _ in 0..2 {
+            let res = master.send_task_to_agent("sleeper", busy.clone()).await.unwrap();
+            assert_eq!(res.error.unwrap().kind(), ErrorKind::RateLimited);
+        }
+        let err = master.send_task_to_agent("sleeper", busy).await.unwrap_err();
+        assert!(matches!(err, DispatchError::CircuitOpen(_)), "{}", err);
+        assert_eq!(busy_calls.load(Ordering::Relaxed), 2);
+    }
+
+    #[tokio::test]
+    pub async fn test_unreachable_agents_report_the_transport_error() {
+        let master = master();
+        register(&master, "http://127.0.0.1:1".to_string()).await;
+
+        let err = master.send_task_to_agent("sleeper", sleep_task(1)).await.unwrap_err();
+        assert!(err.last().is_transport(), "{}", err);
+    }
+
+    #[tokio::test]
+    pub async fn test_agents_reject_tasks_outside_their_schemas() {
+        let master = master();
+        let mut schemas = Sleeper::default().schemas();
+        schemas.push(CommandSchema {
+            name: "nap".to_string(),
+            description: "a command the agent does not understand".to_string(),
+            parameters: Vec::new(),
+            aliases: Vec::new(),
+        });
+        master
+            .connect_to_master(Request::new(AgentConnectRequest {
+                name: "sleeper".to_string(),
+                from: serve_sleeper().await,
+                schemas,
+                ..Default::default()
+            }))
+            .await
+            .unwrap();
+
+        let nap = TaskRequest {
+            instruction: "nap".to_string(),
+            ..Default::default()
+        };
+        let err = master.send_task_to_agent("sleeper", nap).await.unwrap_err();
+        assert!(matches!(&err, DispatchError::Rpc { status, .. } if status.code() == Code::Unimplemented), "{}", err);
+        assert!(err.is_correctable());
+
+        let res = master.send_task_to_agent("sleeper", sleep_task(1)).await.unwrap();
+        assert_eq!(res.status(), Status::Success, "a rejected task does not count against the agent");
+    }
+
+    #[tokio::test]
+    pub async fn test_restore_registry() {
+        let path = std::env::temp_dir().join(format!("meeseeks-master-registry-{}.bin", process::id()));
+        let new_master = || {
+            Arc::new(
+                MasterAgent::new("master".to_string(), SocketAddr::from(([127, 0, 0, 1], 0)), Bm25Matcher::default())
+                    .with_registry_store(RegistryStore::new(path.clone())),
+            )
+        };
+
+        let master = new_master();
+        let reachable = serve_sleeper().await;
+        for (name, addr) in [("sleeper-1", reachable.as_str()), ("sleeper-2", "http://127.0.0.1:1")] {
+            master
+                .connect_to_master(Request::new(AgentConnectRequest {
+                    name: name.to_string(),
+                    from: addr.to_string(),
+                    tool: "sleeper".to_string(),
+                    commands: vec!["sleep(ms)".to_string()],
+                    schemas: Sleeper::default().schemas(),
+                    ..Default::default()
+                }))
+                .await
+                .unwrap();
+        }
+        drop(master);
+
+        let restarted = new_master();
+        assert_eq!(restarted.restore().await, 2);
+        let pools = restarted.list_pools();
+        let healthy: Vec<(&str, bool)> = pools[0]
+            .replicas()
+            .iter()
+            .map(|agent| (agent.name(), agent.is_healthy()))
+            .collect();
+        assert_eq!(healthy, vec![("sleeper-1", true), ("sleeper-2", false)], "only reachable agents are healthy");
+        assert_eq!(restarted.list_agents()[0].schemas(), Sleeper::default().schemas());
+
+        let matches = restarted.matcher.match_agents("sleep for 10ms", 1).await.unwrap();
+        assert_eq!(matches[0].name, "sleeper", "restored pools are added to the matcher again");
+        let res = restarted.send_task_to_agent("sleeper", sleep_task(1)).await.unwrap();
+        assert_eq!(res.status(), Status::Success);
+
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,219 @@
+use std::sync::Arc;
+
+use reqwest::Url;
+
+use crate::{
+    common::{ConnectedAgent, TaskParser},
+    error::ParseError,
+    meeseeks_proto::TaskRequest,
+    template::Templates,
+};
+
+use super::{construct_prompt, correction_from_response, task_from_response, Prompt, INPUT_MARKER};
+
+const STOP_SEQUENCES: &[&str] = &["Input:", "Observation:", "Error:"];
+
+/// A parser backed by an OpenAI compatible completion endpoint, e.g.
+/// `https://api.openai.com/v1/completions` or a local inference server. It uses the same prompt as
+/// the llama parser.
+pub struct HttpParser {
+    client: reqwest::Client,
+    url: Url,
+    model: String,
+    api_key: Option<String>,
+    max_tokens: usize,
+    temperature: f32,
+    templates: Arc<Templates>,
+}
+
+impl HttpParser {
+    pub fn new(url: Url, model: impl Into<String>) -> Self {
+        HttpParser {
+            client: reqwest::Client::new(),
+            url,
+            model: model.into(),
+            api_key: None,
+            max_tokens: 256,
+            temperature: 0.1,
+            templates: Arc::default(),
+        }
+    }
+
+    pub fn with_api_key(mut self, api_key: impl Into<String>) -> Self {
+        self.api_key = Some(api_key.into());
+        self
+    }
+
+    pub fn with_max_tokens(mut self, max_tokens: usize) -> Self {
+        self.max_tokens = max_tokens;
+        self
+    }
+
+    pub fn with_temperature(mut self, temperature: f32) -> Self {
+        self.temperature = temperature;
+        self
+    }
+
+    pub fn with_templates(mut self, templates: Arc<Templates>) -> Self {
+        self.templates = templates;
+        self
+    }
+
+    async fn complete(&self, prompt: Prompt) -> Result<String, ParseError> {
+        let payload = serde_json::json!({
+            "model": self.model,
+            "prompt": prompt.text(),
+            "max_tokens": self.max_tokens,
+            "temperature": self.temperature,
+            "stop": STOP_SEQUENCES,
+            "n": 1,
+            "stream": false,
+        });
+
+        let mut request = self.client.post(self.url.clone()).json(&payload);
+        if let Some(api_key) = &self.api_key {
+            request = request.bearer_auth(api_key);
+        }
+        let res: serde_json::Value = request.send().await?.error_for_status()?.json().await?;
+
+        let text = res
+            .get("choices")
+            .and_then(|choices| choices.get(0))
+            .and_then(|choice| choice.get("text"))
+            .and_then(serde_json::Value::as_str)
+            .ok_or_else(|| ParseError::Inference(format!("unexpected completion response: {}", res)))?;
+        tracing::info!("completion: {:?}", text);
+
+        Ok(text.to_string())
+    }
+}
+
+#[tonic::async_trait]
+impl TaskParser for HttpParser {
+    #[tracing::instrument(name = "http_parse", skip(self, agents))]
+    async fn parse(&self, input: &str, agents: &[ConnectedAgent]) -> Result<Vec<TaskRequest>, ParseError> {
+        let text = self.complete(construct_prompt(&self.templates.get("parse"), agents, input)).await?;
+        Ok(vec![task_from_response(&text, input, agents)?])
+    }
+
+    #[tracing::instrument(name = "http_correct", skip(self, agents))]
+    async fn correct(
+        &self,
+        input: &str,
+        agents: &[ConnectedAgent],
+        task: &TaskRequest,
+        error: &str,
+    ) -> Result<Option<TaskRequest>, ParseError> {
+        let text = self.complete(Prompt::correction(&self.templates, input, agents, task, error)).await?;
+        correction_from_response(&text, input, agents)
+    }
+
+    #[tracing::instrument(name = "http_answer", skip(self))]
+    async fn answer(&self, input: &str) -> Result<String, ParseError> {
+        let prompt = Prompt::new(self.templates.render("answer", &[("input", INPUT_MARKER)]), input.trim());
+        Ok(self.complete(prompt).await?.trim().to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use crate::{
+        common::{ConnectedAgent, TaskParser},
+        error::ParseError,
+    };
+
+    use super::HttpParser;
+
+
+    /// Serves one completion request per entry of `responses` and returns the bodies of the requests.
+    async fn serve_completions(responses: Vec<(u16, String)>) -> (String, tokio::task::JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/v1/completions", listener.local_addr().unwrap());
+
+        let handle = tokio::spawn(async move {
+            let mut bodies = Vec::new();
+            for (status, body) in responses {
+                let (mut stream, _) = listener.accept().await.unwrap();
+
+                let mut request = Vec::new();
+                let mut buf = [0; 4096];
+                let body_start = loop {
+                    let n = stream.read(&mut buf).await.unwrap();
+                    request.extend_from_slice(&buf[..n]);
+                    if let Some(end) = request.windows(4).position(|w| w == b"\r\n\r\n") {
+                        break end + 4;
+                    }
+                };
+                let headers = String::from_utf8_lossy(&request[..body_start]).to_lowercase();
+                let length: usize = headers
+                    .lines()
+                    .find_map(|line| line.strip_prefix("content-length:"))
+                    .map_or(0, |length| length.trim().parse().unwrap());
+                while request.len() < body_start + length {
+                    let n = stream.read(&mut buf).await.unwrap();
+                    request.extend_from_slice(&buf[..n]);
+                }
+                bodies.push(String::from_utf8_lossy(&request[body_start..]).into_owned());
+
+                let response = format!(
+                    "HTTP/1.1 {} OK\r\ncontent-type: application/json\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}",
+                    status,
+                    body.len(),
+                    body
+                );
+                stream.write_all(response.as_bytes()).await.unwrap();
+            }
+            bodies
+        });
+
+        (url, handle)
+    }
+
+    fn completion(text: &str) -> (u16, String) {
+        (200, serde_json::json!({ "choices": [{ "text": text }] }).to_string())
+    }
+
+    #[tokio::test]
+    pub async fn test_http_parser() {
+        let (url, server) = serve_completions(vec![
+            completion("Thought: I should tweet about it\nAction: tweet[rust 1.70]"),
+            completion("Thought: none of the tools can book flights\nNONE"),
+            completion("Thought: the command is called tweet\nAction: tweet[rust]"),
+            (500, "{}".to_string()),
+        ])
+        .await;
+
+        let parser = HttpParser::new(url.parse().unwrap(), "test-model").with_api_key("secret");
+        let agents = vec![ConnectedAgent::for_test("tweetu", &["tweet(topic)"])];
+
+        let tasks = parser.parse("write a tweet about rust 1.70", &agents).await.unwrap();
+        assert_eq!(tasks.len(), 1);
+        assert_eq!(tasks[0].instruction, "tweet");
+        assert_eq!(tasks[0].args[0], "rust 1.70");
+
+        assert!(matches!(parser.parse("book a flight to Paris", &agents).await, Err(ParseError::NoTool)));
+
+        let corrected = parser
+            .correct("write a tweet about rust", &agents, &tasks[0], "invalid instruction twet")
+            .await
+            .unwrap();
+        assert_eq!(corrected.unwrap().args[0], "rust");
+
+        assert!(matches!(
+            parser.parse("write a tweet about rust", &agents).await,
+            Err(ParseError::Backend(_))
+        ));
+
+        let bodies = server.await.unwrap();
+        let request: serde_json::Value = serde_json::from_str(&bodies[0]).unwrap();
+        assert_eq!(request["model"], "test-model");
+        assert!(request["prompt"].as_str().unwrap().contains("- tweet(topic)"));
+        assert!(request["prompt"].as_str().unwrap().ends_with("Input: write a tweet about rust 1.70\n"));
+        assert!(bodies[2].contains("invalid instruction twet"));
+    }
+}
@@ -0,0 +1,180 @@
+use std::str::FromStr;
+
+use crate::{
+    action::{Action, ActionParseError},
+    common::ConnectedAgent,
+    error::ParseError,
+    meeseeks_proto::TaskRequest,
+    schema,
+    template::{Template, Templates},
+};
+
+pub mod http;
+pub mod rules;
+
+/// Which parser the master uses to turn inputs into tasks.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ParserKind {
+    /// A local GGML model, see [`crate::llama_parser::LlamaParser`].
+    #[default]
+    Llama,
+    /// Regular expressions from a rules file, see [`rules::RuleParser`].
+    Rules,
+    /// An OpenAI compatible completion endpoint, see [`http::HttpParser`].
+    Http,
+}
+
+impl FromStr for ParserKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "llama" => Ok(ParserKind::Llama),
+            "rules" => Ok(ParserKind::Rules),
+            "http" => Ok(ParserKind::Http),
+            _ => Err(format!("unknown parser {}. expected llama, rules or http", s)),
+        }
+    }
+}
+
+/// Stands in for the input while a template is filled, to find where the prompt has to be split.
+pub(crate) const INPUT_MARKER: &str = "\u{0}";
+
+/// A prompt split into a prefix that only depends on the template and the agents, whose evaluated
+/// state can be cached, and the rest that depends on the input.
+pub(crate) struct Prompt {
+    pub(crate) prefix: String,
+    pub(crate) rest: String,
+}
+
+impl Prompt {
+    /// Splits `text` at the start of the line holding [`INPUT_MARKER`] and puts `input` in place of
+    /// the marker.
+    pub(crate) fn new(mut text: String, input: &str) -> Prompt {
+        let start = match text.find(INPUT_MARKER) {
+            Some(marker) => text[..marker].rfind('\n').map_or(0, |newline| newline + 1),
+            None => text.len(),
+        };
+        let rest = text[start..].replacen(INPUT_MARKER, input, 1);
+        text.truncate(start);
+
+        Prompt { prefix: text, rest }
+    }
+
+    /// The prompt asking the model to fix `task`, which an agent rejected with `error`.
+    pub(crate) fn correction(
+        templates: &Templates,
+        input: &str,
+        agents: &[ConnectedAgent],
+        task: &TaskRequest,
+        error: &str,
+    ) -> Prompt {
+        let mut prompt = construct_prompt(&templates.get("parse"), agents, input);
+        let action = format!(
+            "{}[{}]",
+            task.instruction,
+            task.args.first().map(String::as_str).unwrap_or_default()
+        );
+        prompt
+            .rest
+            .push_str(&templates.render("correct", &[("action", &action), ("error", error)]));
+        prompt
+    }
+
+    pub(crate) fn text(&self) -> String {
+        format!("{}{}", self.prefix, self.rest)
+    }
+}
+
+/// Renders `template` with the commands and examples of `agents`, keeping the input out of the
+/// cacheable prefix.
+pub(crate) fn construct_prompt(template: &Template, agents: &[ConnectedAgent], input: &str) -> Prompt {
+    let mut list_tools = String::new();
+    let mut list_examples = String::new();
+
+    for agent in agents {
+        for command in &agent.commands {
+            list_tools.push_str(&format!("- {}\n", command));
+        }
+        list_examples.push('\n');
+        list_examples.push_str(agent.examples.trim());
+    }
+
+    Prompt::new(
+        template.render(&[("tools", &list_tools), ("examples", &list_examples), ("input", INPUT_MARKER)]),
+        input.trim(),
+    )
+}
+
+/// Finds the `Action:` line in a model response and turns it into a task. Fails with
+/// [`ParseError::NoTool`] if the model answered `NONE` instead.
+pub(crate) fn task_from_response(text: &str, input: &str, agents: &[ConnectedAgent]) -> Result<TaskRequest, ParseError> {
+    match text.lines().map(str::trim).find(|line| line.starts_with("Action:") || *line == "NONE") {
+        Some("NONE") => Err(ParseError::NoTool),
+        Some(line) => parse_action(line, input, agents),
+        None => Err(ParseError::Inference(format!("the model chose neither an action nor NONE: {:?}", text))),
+    }
+}
+
+/// Like [`task_from_response`], but a model that answered `NONE` has no correction to offer.
+pub(crate) fn correction_from_response(
+    text: &str,
+    input: &str,
+    agents: &[ConnectedAgent],
+) -> Result<Option<TaskRequest>, ParseError> {
+    match task_from_response(text, input, agents) {
+        Err(ParseError::NoTool) => Ok(None),
+        task => task.map(Some),
+    }
+}
+
+/// Parses an `Action: command[args]` line into a task. Anything before the `Action:` marker is
+/// ignored.
+pub(crate) fn parse_action(text: &str, input: &str, agents: &[ConnectedAgent]) -> Result<TaskRequest, ParseError> {
+    let action = match text.find("Action:") {
+        Some(start) => &text[start + "Action:".len()..],
+        None => text,
+    };
+    let action = parse_action_for(action, agents).map_err(|error| ParseError::InvalidAction {
+        output: text.to_string(),
+        error,
+    })?;
+
+    Ok(into_task(action, input, agents))
+}
+
+/// Parses an action on a command of `agents`. `name=` only starts a named argument if the schema
+/// of the command declares a parameter `name`, see [`Action::parse_with`].
+pub(crate) fn parse_action_for(text: &str, agents: &[ConnectedAgent]) -> Result<Action, ActionParseError> {
+    Action::parse_with(text, |name| {
+        agents
+            .iter()
+            .flat_map(|agent| agent.schemas())
+            .find(|schema| schema.name == name)
+            .map(|schema| schema.parameters.iter().map(|param| param.name.clone()).collect())
+    })
+}
+
+/// Turns a parsed action into a task. If one of the agents has a schema for the command, the
+/// action's arguments are bound to its parameters. Tasks that do not fit the schema are left
+/// unbound for the master to reject.
+pub(crate) fn into_task(action: Action, input: &str, agents: &[ConnectedAgent]) -> TaskRequest {
+    let named_args = agents
+        .iter()
+        .flat_map(|agent| agent.schemas())
+        .find(|schema| schema.name == action.name)
+        .and_then(|schema| match schema::bind(&action, schema) {
+            Ok(args) => Some(args),
+            Err(e) => {
+                tracing::debug!("failed to bind arguments of {}: {}", action, e);
+                None
+            }
+        })
+        .unwrap_or_default();
+
+    TaskRequest {
+        args: vec![action.first_arg().to_owned(), input.to_owned()],
+        instruction: action.name,
+        named_args,
+    }
+}
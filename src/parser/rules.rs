@@ -0,0 +1,213 @@
+use std::path::Path;
+
+use regex::{Regex, RegexBuilder};
+use serde::Deserialize;
+
+use crate::{
+    action::{Action, Argument},
+    common::{ConnectedAgent, TaskParser},
+    error::ParseError,
+    meeseeks_proto::TaskRequest,
+};
+
+use super::into_task;
+
+#[derive(Debug, Deserialize)]
+struct RulesConfig {
+    #[serde(default)]
+    rules: Vec<RuleConfig>,
+}
+
+#[derive(Debug, Deserialize)]
+struct RuleConfig {
+    pattern: String,
+    command: String,
+    #[serde(default)]
+    args: Vec<String>,
+    #[serde(default)]
+    case_sensitive: bool,
+}
+
+/// Maps inputs that match `pattern` to `command`.
+#[derive(Debug, Clone)]
+pub struct Rule {
+    pattern: Regex,
+    command: String,
+    /// Templates of the positional arguments, e.g. `$1` or `${topic}`. If there are none, the
+    /// named groups of the pattern become named arguments, or the first group the only argument.
+    args: Vec<String>,
+}
+
+impl Rule {
+    fn apply(&self, input: &str) -> Option<Action> {
+        let caps = self.pattern.captures(input)?;
+
+        let args: Vec<Argument> = if !self.args.is_empty() {
+            self.args
+                .iter()
+                .map(|template| {
+                    let mut value = String::new();
+                    caps.expand(template, &mut value);
+                    argument(None, value)
+                })
+                .collect()
+        } else if self.pattern.capture_names().flatten().next().is_some() {
+            self.pattern
+                .capture_names()
+                .flatten()
+                .filter_map(|name| Some(argument(Some(name.to_string()), caps.name(name)?.as_str().to_string())))
+                .collect()
+        } else {
+            let value = caps.get(1).map_or(input, |group| group.as_str());
+            vec![argument(None, value.to_string())]
+        };
+
+        Some(Action {
+            name: self.command.clone(),
+            raw_args: args.iter().map(|arg| arg.value.as_str()).collect::<Vec<_>>().join(", "),
+            args,
+        })
+    }
+}
+
+fn argument(name: Option<String>, value: String) -> Argument {
+    Argument {
+        name,
+        value: value.trim().to_string(),
+        quoted: false,
+    }
+}
+
+/// A deterministic parser that maps inputs to commands with regular expressions, for when no model
+/// is available. Rules are read from a TOML file:
+///
+/// ```toml
+/// [[rules]]
+/// pattern = '^(?:what is|calculate) (?P<expression>[^?]+)'
+/// command = "calculate"
+///
+/// [[rules]]
+/// pattern = '^tweet about (.+)$'
+/// command = "tweet"
+/// args = ["$1"]
+/// ```
+///
+/// The first rule whose pattern matches the input and whose command is advertised by one of the
+/// agents wins. Patterns are case insensitive unless the rule sets `case_sensitive = true`.
+#[derive(Debug, Clone)]
+pub struct RuleParser {
+    rules: Vec<Rule>,
+}
+
+impl RuleParser {
+    pub fn from_file(path: &Path) -> Result<Self, ParseError> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| ParseError::Config(format!("failed to read {}: {}", path.display(), e)))?;
+        Self::from_toml(&text)
+    }
+
+    pub fn from_toml(text: &str) -> Result<Self, ParseError> {
+        let config: RulesConfig = toml::from_str(text).map_err(|e| ParseError::Config(e.to_string()))?;
+
+        let rules = config
+            .rules
+            .into_iter()
+            .map(|rule| {
+                let pattern = RegexBuilder::new(&rule.pattern)
+                    .case_insensitive(!rule.case_sensitive)
+                    .build()
+                    .map_err(|e| ParseError::Config(format!("invalid pattern for {}: {}", rule.command, e)))?;
+                Ok(Rule {
+                    pattern,
+                    command: rule.command,
+                    args: rule.args,
+                })
+            })
+            .collect::<Result<_, ParseError>>()?;
+
+        Ok(RuleParser { rules })
+    }
+
+    pub fn rules(&self) -> &[Rule] {
+        &self.rules
+    }
+}
+
+#[tonic::async_trait]
+impl TaskParser for RuleParser {
+    async fn parse(&self, input: &str, agents: &[ConnectedAgent]) -> Result<Vec<TaskRequest>, ParseError> {
+        let input = input.trim();
+        let action = self
+            .rules
+            .iter()
+            .filter(|rule| {
+                agents.iter().any(|agent| {
+                    agent.has_command(&rule.command) || agent.schemas().iter().any(|schema| schema.name == rule.command)
+                })
+            })
+            .find_map(|rule| rule.apply(input));
+
+        match action {
+            Some(action) => {
+                tracing::debug!("input {:?} matched rule for {}", input, action.name);
+                Ok(vec![into_task(action, input, agents)])
+            }
+            None => Err(ParseError::NoTool),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        common::{ConnectedAgent, TaskParser},
+        error::ParseError,
+    };
+
+    use super::RuleParser;
+
+
+    const RULES: &str = r#"
+[[rules]]
+pattern = '^(?:what is|calculate) (?P<expression>[^?]+)'
+command = "calculate"
+
+[[rules]]
+pattern = '^tweet about (.+)$'
+command = "tweet"
+args = ["$1"]
+
+[[rules]]
+pattern = '^book a flight to (.+)$'
+command = "book_flight"
+"#;
+
+    #[tokio::test]
+    pub async fn test_rule_parser() {
+        let parser = RuleParser::from_toml(RULES).unwrap();
+        let agents = vec![
+            ConnectedAgent::for_test("calc", &["calculate(expression)"]),
+            ConnectedAgent::for_test("tweetu", &["tweet(topic)"]),
+        ];
+
+        let tasks = parser.parse("What is 999 - 1?", &agents).await.unwrap();
+        assert_eq!(tasks.len(), 1);
+        assert_eq!(tasks[0].instruction, "calculate");
+        assert_eq!(tasks[0].args, vec!["999 - 1".to_string(), "What is 999 - 1?".to_string()]);
+        assert!(tasks[0].named_args.contains_key("expression"));
+
+        let tasks = parser.parse("tweet about rust", &agents).await.unwrap();
+        assert_eq!(tasks[0].instruction, "tweet");
+        assert_eq!(tasks[0].args[0], "rust");
+
+        // no agent advertises the command of the matching rule
+        assert!(matches!(parser.parse("book a flight to Paris", &agents).await, Err(ParseError::NoTool)));
+        assert!(matches!(parser.parse("who is the president of France?", &agents).await, Err(ParseError::NoTool)));
+
+        assert!(!RuleParser::from_toml(include_str!("../../config/rules.toml")).unwrap().rules().is_empty());
+        assert!(matches!(
+            RuleParser::from_toml("[[rules]]\npattern = '('\ncommand = \"calculate\"\n"),
+            Err(ParseError::Config(_))
+        ));
+    }
+}
@@ -0,0 +1,249 @@
+use std::{
+    collections::HashMap,
+    fmt, fs,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::embedding::fnv1a;
+
+/// Keeps the evaluated state of prompt prefixes, so that the instructions and agent examples at the
+/// start of a prompt are only fed to the model once for every set of connected agents.
+///
+/// Snapshots are keyed by the model and prefix and held in memory until the connected agents
+/// change. With a directory configured they are also written to disk, in a file named after a
+/// stable hash of the key, and found there again after a restart. Prefixes describe the agents they
+/// were built for, so a snapshot on disk is only reused for the same agents.
+pub struct PrefixCache<T> {
+    dir: Option<PathBuf>,
+    capacity: usize,
+    inner: Mutex<Inner<T>>,
+}
+
+struct Inner<T> {
+    /// The agents generation the snapshots in memory were made for.
+    generation: u64,
+    snapshots: HashMap<PrefixKey, Entry<T>>,
+}
+
+struct Entry<T> {
+    snapshot: Arc<T>,
+    last_used: Instant,
+}
+
+/// A prompt prefix evaluated by a model.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct PrefixKey {
+    model: String,
+    prefix: String,
+}
+
+impl PrefixKey {
+    /// FNV-1a of the model and prefix. Unlike `DefaultHasher` it does not change between Rust
+    /// releases, so files named after it are found again after a toolchain upgrade.
+    fn hash(&self) -> u64 {
+        // 0xff never occurs in UTF-8, so the boundary between model and prefix is unambiguous
+        fnv1a(&[self.model.as_bytes(), &[0xff], self.prefix.as_bytes()].concat())
+    }
+}
+
+impl fmt::Display for PrefixKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:016x}", self.hash())
+    }
+}
+
+/// A snapshot on disk together with the key it was made for. File names are hashes, which two
+/// prefixes may share, so the key is checked when the snapshot is loaded.
+#[derive(Serialize, Deserialize)]
+struct Stored<K, T> {
+    key: K,
+    snapshot: T,
+}
+
+impl<T: Serialize + DeserializeOwned> PrefixCache<T> {
+    /// A cache holding at most `capacity` snapshots in memory and persisting them to `dir` if set.
+    pub fn new(capacity: usize, dir: Option<PathBuf>) -> Self {
+        PrefixCache {
+            dir,
+            capacity,
+            inner: Mutex::new(Inner {
+                generation: 0,
+                snapshots: HashMap::new(),
+            }),
+        }
+    }
+
+    /// Whether snapshots are kept at all, in memory or on disk.
+    pub fn is_enabled(&self) -> bool {
+        self.capacity > 0 || self.dir.is_some()
+    }
+
+    /// The key of a prefix evaluated by the model at `model`.
+    pub fn key(model: &str, prefix: &str) -> PrefixKey {
+        PrefixKey {
+            model: model.to_string(),
+            prefix: prefix.to_string(),
+        }
+    }
+
+    /// Drops the snapshots in memory if the agents changed since they were made, i.e. if
+    /// `generation` differs from the one the cache last saw.
+    pub fn sync(&self, generation: u64) {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.generation != generation {
+            if !inner.snapshots.is_empty() {
+                tracing::debug!("agents changed. dropping {} cached prompt prefixes", inner.snapshots.len());
+            }
+            inner.snapshots.clear();
+            inner.generation = generation;
+        }
+    }
+
+    pub fn get(&self, key: &PrefixKey) -> Option<Arc<T>> {
+        {
+            let mut inner = self.inner.lock().unwrap();
+            if let Some(entry) = inner.snapshots.get_mut(key) {
+                entry.last_used = Instant::now();
+                return Some(entry.snapshot.clone());
+            }
+        }
+
+        let snapshot = Arc::new(self.load(key)?);
+        self.remember(key, snapshot.clone());
+        Some(snapshot)
+    }
+
+    pub fn insert(&self, key: &PrefixKey, snapshot: T) -> Arc<T> {
+        self.store(key, &snapshot);
+        let snapshot = Arc::new(snapshot);
+        self.remember(key, snapshot.clone());
+        snapshot
+    }
+
+    fn remember(&self, key: &PrefixKey, snapshot: Arc<T>) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        let mut inner = self.inner.lock().unwrap();
+        while inner.snapshots.len() >= self.capacity {
+            let oldest = inner
+                .snapshots
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(key, _)| key.clone());
+            match oldest {
+                Some(oldest) => inner.snapshots.remove(&oldest),
+                None => break,
+            };
+        }
+        inner.snapshots.insert(
+            key.clone(),
+            Entry {
+                snapshot,
+                last_used: Instant::now(),
+            },
+        );
+    }
+
+    fn path(&self, key: &PrefixKey) -> Option<PathBuf> {
+        Some(self.dir.as_ref()?.join(format!("{}.prefix", key)))
+    }
+
+    fn load(&self, key: &PrefixKey) -> Option<T> {
+        let path = self.path(key)?;
+        let bytes = fs::read(&path).ok()?;
+        match bincode::deserialize::<Stored<PrefixKey, T>>(&bytes) {
+            Ok(stored) if stored.key != *key => {
+                tracing::debug!("prompt prefix in {} was made for another prefix", path.display());
+                None
+            }
+            Ok(stored) => {
+                tracing::debug!("loaded prompt prefix from {}", path.display());
+                Some(stored.snapshot)
+            }
+            Err(e) => {
+                tracing::warn!("ignoring corrupt prompt prefix {}: {}", path.display(), e);
+                None
+            }
+        }
+    }
+
+    fn store(&self, key: &PrefixKey, snapshot: &T) {
+        let path = match self.path(key) {
+            Some(path) => path,
+            None => return,
+        };
+
+        let result = bincode::serialize(&Stored { key, snapshot })
+            .map_err(|e| e.to_string())
+            .and_then(|bytes| {
+                fs::create_dir_all(path.parent().unwrap_or(&path)).map_err(|e| e.to_string())?;
+                // write to a temporary file first so that a crash never leaves a truncated snapshot
+                let tmp = path.with_extension("tmp");
+                fs::write(&tmp, bytes).map_err(|e| e.to_string())?;
+                fs::rename(&tmp, &path).map_err(|e| e.to_string())
+            });
+        if let Err(e) = result {
+            tracing::warn!("failed to persist prompt prefix to {}: {}", path.display(), e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, process};
+
+    use super::PrefixCache;
+
+    #[test]
+    pub fn test_prefix_cache() {
+        let cache: PrefixCache<Vec<u8>> = PrefixCache::new(2, None);
+        let (a, b, c) = (
+            PrefixCache::<Vec<u8>>::key("llama", "a"),
+            PrefixCache::<Vec<u8>>::key("llama", "b"),
+            PrefixCache::<Vec<u8>>::key("llama", "c"),
+        );
+        assert_ne!(a, PrefixCache::<Vec<u8>>::key("alpaca", "a"));
+
+        cache.insert(&a, vec![1]);
+        cache.insert(&b, vec![2]);
+        assert_eq!(*cache.get(&a).unwrap(), vec![1]);
+        cache.insert(&c, vec![3]);
+        assert!(cache.get(&b).is_none(), "the least recently used snapshot is evicted");
+        assert!(cache.get(&a).is_some());
+
+        cache.sync(0);
+        assert!(cache.get(&a).is_some());
+        cache.sync(1);
+        assert!(cache.get(&a).is_none());
+        assert!(cache.get(&c).is_none());
+    }
+
+    #[test]
+    pub fn test_prefix_cache_persistence() {
+        let dir = std::env::temp_dir().join(format!("meeseeks-prefix-cache-{}", process::id()));
+        let key = PrefixCache::<Vec<u8>>::key("llama", "prefix");
+
+        let cache: PrefixCache<Vec<u8>> = PrefixCache::new(1, Some(dir.clone()));
+        cache.insert(&key, vec![1, 2, 3]);
+        assert!(dir.join("7a6319c881de569f.prefix").exists(), "file names do not depend on the toolchain");
+
+        let restarted: PrefixCache<Vec<u8>> = PrefixCache::new(1, Some(dir.clone()));
+        assert_eq!(*restarted.get(&key).unwrap(), vec![1, 2, 3]);
+        restarted.sync(1);
+        assert!(restarted.get(&key).is_some(), "snapshots on disk survive changes of the agents");
+
+        // a different prefix whose hash names the same file is not mistaken for the stored one
+        let other = PrefixCache::<Vec<u8>>::key("llama", "other prefix");
+        fs::rename(dir.join(format!("{}.prefix", key)), dir.join(format!("{}.prefix", other))).unwrap();
+        let restarted: PrefixCache<Vec<u8>> = PrefixCache::new(1, Some(dir.clone()));
+        assert!(restarted.get(&other).is_none());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,315 @@
+use std::{
+    collections::HashMap,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        RwLock,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::common::ConnectedAgent;
+
+/// How the master picks a replica of a pool for a task.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Balancing {
+    /// Cycle through the healthy replicas.
+    #[default]
+    RoundRobin,
+    /// Pick the healthy replica with the fewest tasks in flight.
+    LeastOutstanding,
+}
+
+impl FromStr for Balancing {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "round-robin" => Ok(Balancing::RoundRobin),
+            "least-outstanding" => Ok(Balancing::LeastOutstanding),
+            _ => Err(format!(
+                "unknown balancing strategy {}. expected round-robin or least-outstanding",
+                s
+            )),
+        }
+    }
+}
+
+/// Replicas of one tool. Tasks routed to the pool are spread over its healthy replicas.
+#[derive(Clone)]
+pub struct AgentPool {
+    name: String,
+    replicas: Vec<ConnectedAgent>,
+    next: usize,
+}
+
+impl AgentPool {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn replicas(&self) -> &[ConnectedAgent] {
+        &self.replicas
+    }
+
+    /// The replica used to describe the pool to matchers and parsers, preferring a healthy one.
+    pub fn representative(&self) -> Option<&ConnectedAgent> {
+        self.replicas
+            .iter()
+            .find(|agent| agent.is_healthy())
+            .or_else(|| self.replicas.first())
+    }
+
+    pub fn is_healthy(&self) -> bool {
+        self.replicas.iter().any(|agent| agent.is_healthy())
+    }
+
+    fn pick(&mut self, balancing: Balancing, exclude: &[String]) -> Option<ConnectedAgent> {
+        let candidates: Vec<&ConnectedAgent> = self
+            .replicas
+            .iter()
+            .filter(|agent| agent.is_healthy() && agent.breaker.allows() && !exclude.contains(&agent.addr))
+            .collect();
+        if candidates.is_empty() {
+            return None;
+        }
+
+        let picked = match balancing {
+            Balancing::RoundRobin => {
+                self.next = self.next.wrapping_add(1);
+                candidates[self.next % candidates.len()]
+            }
+            Balancing::LeastOutstanding => candidates.iter().min_by_key(|agent| agent.outstanding())?,
+        };
+        Some(picked.clone())
+    }
+}
+
+/// The agents connected to the master, grouped into pools by tool and identified within a pool by
+/// their address.
+///
+/// The lock is only held for the duration of a map operation and never across a call to an agent,
+/// so tasks sent to agents run in parallel with each other, with registrations and with listings.
+/// Agents carry their own client, which is cheap to clone and multiplexes concurrent requests over
+/// one connection.
+#[derive(Default)]
+pub struct AgentRegistry {
+    pools: RwLock<HashMap<String, AgentPool>>,
+    balancing: Balancing,
+    /// Incremented whenever an agent joins, is replaced or leaves.
+    generation: AtomicU64,
+}
+
+impl AgentRegistry {
+    pub fn new(balancing: Balancing) -> Self {
+        AgentRegistry {
+            pools: RwLock::default(),
+            balancing,
+            generation: AtomicU64::new(0),
+        }
+    }
+
+    /// Changes whenever the set of connected agents changes, so that anything derived from the
+    /// agents can tell when it is stale.
+    pub fn generation(&self) -> u64 {
+        self.generation.load(Ordering::SeqCst)
+    }
+
+    /// Adds an agent to its pool, creating the pool if needed. Returns the replica it replaced if
+    /// an agent with the same address was already in the pool.
+    pub fn insert(&self, agent: ConnectedAgent) -> Option<ConnectedAgent> {
+        let mut pools = self.pools.write().unwrap();
+        let pool = pools.entry(agent.pool.clone()).or_insert_with(|| AgentPool {
+            name: agent.pool.clone(),
+            replicas: Vec::new(),
+            next: 0,
+        });
+        self.generation.fetch_add(1, Ordering::SeqCst);
+
+        match pool.replicas.iter_mut().find(|replica| replica.addr == agent.addr) {
+            Some(replica) => Some(std::mem::replace(replica, agent)),
+            None => {
+                pool.replicas.push(agent);
+                None
+            }
+        }
+    }
+
+    /// Removes the agent with the given name listening on `addr`. Returns the removed agent and
+    /// whether its pool is now empty, in which case the pool is removed as well.
+    pub fn remove(&self, name: &str, addr: &str) -> Option<(ConnectedAgent, bool)> {
+        let mut pools = self.pools.write().unwrap();
+        let pool = pools
+            .values_mut()
+            .find(|pool| pool.replicas.iter().any(|agent| agent.name == name && agent.addr == addr))?;
+        let index = pool.replicas.iter().position(|agent| agent.addr == addr)?;
+        let agent = pool.replicas.remove(index);
+        self.generation.fetch_add(1, Ordering::SeqCst);
+
+        let empty = pool.replicas.is_empty();
+        if empty {
+            pools.remove(&agent.pool);
+        }
+        Some((agent, empty))
+    }
+
+    /// The representative agent of the named pool.
+    pub fn get(&self, pool: &str) -> Option<ConnectedAgent> {
+        self.pools.read().unwrap().get(pool)?.representative().cloned()
+    }
+
+    pub fn contains(&self, pool: &str) -> bool {
+        self.pools.read().unwrap().contains_key(pool)
+    }
+
+    /// Picks a healthy replica of the named pool whose circuit is not open and that is not
+    /// listening on one of the `exclude` addresses.
+    pub fn pick(&self, pool: &str, exclude: &[String]) -> Option<ConnectedAgent> {
+        self.pools.write().unwrap().get_mut(pool)?.pick(self.balancing, exclude)
+    }
+
+    /// One representative agent per pool.
+    pub fn list(&self) -> Vec<ConnectedAgent> {
+        self.pools
+            .read()
+            .unwrap()
+            .values()
+            .filter_map(|pool| pool.representative().cloned())
+            .collect()
+    }
+
+    pub fn pools(&self) -> Vec<AgentPool> {
+        self.pools.read().unwrap().values().cloned().collect()
+    }
+
+    /// Records a heartbeat from the agent with the given name listening on `addr`. Returns false if
+    /// no such agent is connected.
+    pub fn heartbeat(&self, name: &str, addr: &str) -> bool {
+        let mut pools = self.pools.write().unwrap();
+        let agent = pools
+            .values_mut()
+            .flat_map(|pool| pool.replicas.iter_mut())
+            .find(|agent| agent.name == name && agent.addr == addr);
+
+        match agent {
+            Some(agent) => {
+                if !agent.healthy {
+                    tracing::info!("agent {} at {} is healthy again", name, addr);
+                }
+                agent.last_seen = Instant::now();
+                agent.healthy = true;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Marks the replica of `pool` listening on `addr` as unhealthy until its next heartbeat.
+    pub fn mark_unhealthy(&self, pool: &str, addr: &str) {
+        let mut pools = self.pools.write().unwrap();
+        if let Some(agent) = pools
+            .get_mut(pool)
+            .and_then(|pool| pool.replicas.iter_mut().find(|agent| agent.addr == addr))
+        {
+            agent.healthy = false;
+        }
+    }
+
+    /// Marks agents that have been silent for `unhealthy_after` as unhealthy and removes the ones
+    /// that have been silent for `evict_after`. Returns the names of the pools that were left
+    /// without replicas and removed.
+    pub fn sweep(&self, unhealthy_after: Duration, evict_after: Duration) -> Vec<String> {
+        let now = Instant::now();
+
+        let mut pools = self.pools.write().unwrap();
+        for pool in pools.values_mut() {
+            pool.replicas.retain_mut(|agent| {
+                let silent_for = now.duration_since(agent.last_seen);
+                if silent_for >= evict_after {
+                    tracing::warn!("evicting agent {} at {}. no heartbeat for {:?}", agent.name, agent.addr, silent_for);
+                    self.generation.fetch_add(1, Ordering::SeqCst);
+                    return false;
+                }
+                if silent_for >= unhealthy_after && agent.healthy {
+                    tracing::warn!("agent {} at {} is unhealthy. no heartbeat for {:?}", agent.name, agent.addr, silent_for);
+                    agent.healthy = false;
+                }
+                true
+            });
+        }
+
+        let mut evicted = Vec::new();
+        pools.retain(|name, pool| {
+            if pool.replicas.is_empty() {
+                evicted.push(name.clone());
+                return false;
+            }
+            true
+        });
+
+        evicted
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::atomic::Ordering, time::Duration};
+
+    use crate::common::ConnectedAgent;
+
+    use super::{AgentRegistry, Balancing};
+
+    fn replica(name: &str, pool: &str, addr: &str) -> ConnectedAgent {
+        ConnectedAgent {
+            pool: pool.to_string(),
+            addr: addr.to_string(),
+            ..ConnectedAgent::for_test(name, &[])
+        }
+    }
+
+    #[tokio::test]
+    pub async fn test_pools_balance_over_healthy_replicas() {
+        let registry = AgentRegistry::new(Balancing::RoundRobin);
+        assert!(registry.insert(replica("wiki-1", "wiki", "http://127.0.0.1:9001")).is_none());
+        assert!(registry.insert(replica("wiki-2", "wiki", "http://127.0.0.1:9002")).is_none());
+        let generation = registry.generation();
+        assert!(registry.insert(replica("wiki-2", "wiki", "http://127.0.0.1:9002")).is_some());
+        assert_ne!(registry.generation(), generation);
+        assert_eq!(registry.list().len(), 1);
+        assert_eq!(registry.pools()[0].replicas().len(), 2);
+
+        let picked: Vec<String> = (0..4).map(|_| registry.pick("wiki", &[]).unwrap().addr).collect();
+        assert_ne!(picked[0], picked[1]);
+        assert_eq!(picked[0], picked[2]);
+
+        registry.mark_unhealthy("wiki", "http://127.0.0.1:9001");
+        for _ in 0..3 {
+            assert_eq!(registry.pick("wiki", &[]).unwrap().name, "wiki-2");
+        }
+        assert!(registry.pick("wiki", &["http://127.0.0.1:9002".to_string()]).is_none());
+        let generation = registry.generation();
+        assert!(registry.heartbeat("wiki-1", "http://127.0.0.1:9001"));
+        assert!(!registry.heartbeat("wiki-1", "http://127.0.0.1:9002"));
+        assert_eq!(registry.generation(), generation);
+
+        assert_eq!(registry.remove("wiki-1", "http://127.0.0.1:9001").map(|(_, empty)| empty), Some(false));
+        assert_eq!(registry.remove("wiki-2", "http://127.0.0.1:9002").map(|(_, empty)| empty), Some(true));
+        assert!(!registry.contains("wiki"));
+    }
+
+    #[tokio::test]
+    pub async fn test_least_outstanding_balancing() {
+        let registry = AgentRegistry::new(Balancing::LeastOutstanding);
+        let busy = replica("calc-1", "calc", "http://127.0.0.1:9001");
+        busy.outstanding.store(3, Ordering::Relaxed);
+        registry.insert(busy);
+        registry.insert(replica("calc-2", "calc", "http://127.0.0.1:9002"));
+
+        assert_eq!(registry.pick("calc", &[]).unwrap().name, "calc-2");
+
+        let evicted = registry.sweep(Duration::ZERO, Duration::from_secs(60));
+        assert!(evicted.is_empty());
+        assert!(registry.pick("calc", &[]).is_none());
+        assert_eq!(registry.sweep(Duration::ZERO, Duration::ZERO), vec!["calc".to_string()]);
+    }
+}
@@ -0,0 +1,154 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use prost::Message;
+use serde::{Deserialize, Serialize};
+
+use crate::{common::ConnectedAgent, meeseeks_proto::AgentConnectRequest};
+
+#[derive(Serialize, Deserialize)]
+struct Record {
+    /// The protobuf encoded `AgentConnectRequest`.
+    registration: Vec<u8>,
+    /// Seconds since the unix epoch.
+    registered_at: u64,
+}
+
+/// An agent read back from a [`RegistryStore`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct StoredAgent {
+    /// The registration the agent connected with: its name, address, description, commands,
+    /// schemas and examples.
+    pub registration: AgentConnectRequest,
+    pub registered_at: SystemTime,
+}
+
+/// Keeps the registrations of the agents connected to the master in a file, so that a restarted
+/// master knows its agents before they register again. The file is replaced as a whole on every
+/// change.
+pub struct RegistryStore {
+    path: PathBuf,
+    /// Serializes writes, which share the temporary file.
+    write: Mutex<()>,
+}
+
+impl RegistryStore {
+    pub fn new(path: PathBuf) -> Self {
+        RegistryStore {
+            path,
+            write: Mutex::new(()),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The stored agents. A missing or corrupt store holds none.
+    pub fn load(&self) -> Vec<StoredAgent> {
+        let bytes = match fs::read(&self.path) {
+            Ok(bytes) => bytes,
+            Err(_) => return Vec::new(),
+        };
+        let records: Vec<Record> = match bincode::deserialize(&bytes) {
+            Ok(records) => records,
+            Err(e) => {
+                tracing::warn!("ignoring corrupt registry {}: {}", self.path.display(), e);
+                return Vec::new();
+            }
+        };
+
+        records
+            .into_iter()
+            .filter_map(|record| match AgentConnectRequest::decode(record.registration.as_slice()) {
+                Ok(registration) => Some(StoredAgent {
+                    registration,
+                    registered_at: UNIX_EPOCH + Duration::from_secs(record.registered_at),
+                }),
+                Err(e) => {
+                    tracing::warn!("ignoring corrupt agent in registry {}: {}", self.path.display(), e);
+                    None
+                }
+            })
+            .collect()
+    }
+
+    /// Replaces the stored agents with `agents`.
+    pub fn save(&self, agents: &[ConnectedAgent]) {
+        let records: Vec<Record> = agents
+            .iter()
+            .map(|agent| Record {
+                registration: agent.registration().encode_to_vec(),
+                registered_at: agent
+                    .registered_at()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_secs(),
+            })
+            .collect();
+
+        let _write = self.write.lock().unwrap();
+        let result = bincode::serialize(&records).map_err(|e| e.to_string()).and_then(|bytes| {
+            if let Some(dir) = self.path.parent() {
+                fs::create_dir_all(dir).map_err(|e| e.to_string())?;
+            }
+            let tmp = self.path.with_extension("tmp");
+            fs::write(&tmp, bytes).map_err(|e| e.to_string())?;
+            fs::rename(&tmp, &self.path).map_err(|e| e.to_string())
+        });
+        match result {
+            Ok(()) => tracing::debug!("persisted {} agents to {}", agents.len(), self.path.display()),
+            Err(e) => tracing::warn!("failed to persist agents to {}: {}", self.path.display(), e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs, process,
+        time::{Duration, UNIX_EPOCH},
+    };
+
+    use crate::common::ConnectedAgent;
+
+    use super::RegistryStore;
+
+    fn agent(name: &str, addr: &str) -> ConnectedAgent {
+        ConnectedAgent {
+            pool: "calculator".to_string(),
+            description: "evaluates mathematical expressions".to_string(),
+            addr: addr.to_string(),
+            examples: "Input: what is 1 + 1?\nAction: calculate[1 + 1]".to_string(),
+            registered_at: UNIX_EPOCH + Duration::from_secs(1_700_000_000),
+            ..ConnectedAgent::for_test(name, &["calculate(expression)"])
+        }
+    }
+
+    #[tokio::test]
+    pub async fn test_registry_store() {
+        let path = std::env::temp_dir().join(format!("meeseeks-registry-{}", process::id())).join("agents.bin");
+        let store = RegistryStore::new(path.clone());
+        assert!(store.load().is_empty(), "a missing store holds no agents");
+
+        let agents = vec![agent("calc-1", "http://127.0.0.1:9001"), agent("calc-2", "http://127.0.0.1:9002")];
+        store.save(&agents);
+        let stored = store.load();
+        assert_eq!(stored.len(), 2);
+        assert_eq!(stored[1].registration, agents[1].registration());
+        assert_eq!(stored[1].registration.tool, "calculator");
+        assert_eq!(stored[0].registered_at, agents[0].registered_at);
+
+        store.save(&agents[..1]);
+        assert_eq!(RegistryStore::new(path.clone()).load().len(), 1, "saving replaces the stored agents");
+
+        fs::write(&path, b"not a registry").unwrap();
+        assert!(store.load().is_empty());
+
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+}
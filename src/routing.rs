@@ -0,0 +1,193 @@
+use std::path::Path;
+
+use regex::{Regex, RegexBuilder};
+use serde::Deserialize;
+
+use crate::error::RoutingError;
+
+#[derive(Debug, Deserialize)]
+struct RoutingConfig {
+    #[serde(default)]
+    rules: Vec<RuleConfig>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RuleConfig {
+    name: Option<String>,
+    regex: Option<String>,
+    keyword: Option<String>,
+    prefix: Option<String>,
+    agent: Option<String>,
+    block: Option<String>,
+    #[serde(default)]
+    priority: i32,
+    #[serde(default)]
+    case_sensitive: bool,
+}
+
+/// What happens to an input a rule matches.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RouteAction {
+    /// Send the input to the pool with this name.
+    Agent(String),
+    /// Reject the input with this reason.
+    Block(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct RoutingRule {
+    pub name: String,
+    pub priority: i32,
+    pub action: RouteAction,
+    pattern: Regex,
+}
+
+impl RoutingRule {
+    pub fn matches(&self, input: &str) -> bool {
+        self.pattern.is_match(input)
+    }
+}
+
+/// Rules that decide where inputs go before the matcher is asked. Read from a TOML file:
+///
+/// ```toml
+/// [[rules]]
+/// name = "arithmetic"
+/// regex = '^what is [0-9]'
+/// agent = "calculator"
+/// priority = 10
+///
+/// [[rules]]
+/// keyword = "password"
+/// block = "inputs about credentials are not allowed"
+/// ```
+///
+/// Every rule has one of `regex`, `keyword` (a whole word anywhere in the input) or `prefix`, and
+/// either sends matching inputs to the pool named by `agent` or blocks them with the reason in
+/// `block`. Rules with a higher priority are tried first, rules with the same priority in the order
+/// of the file. Matching is case insensitive unless the rule sets `case_sensitive = true`.
+#[derive(Debug, Clone, Default)]
+pub struct RoutingRules {
+    rules: Vec<RoutingRule>,
+}
+
+impl RoutingRules {
+    pub fn from_file(path: &Path) -> Result<Self, RoutingError> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| RoutingError::Config(format!("failed to read {}: {}", path.display(), e)))?;
+        Self::from_toml(&text)
+    }
+
+    pub fn from_toml(text: &str) -> Result<Self, RoutingError> {
+        let config: RoutingConfig = toml::from_str(text).map_err(|e| RoutingError::Config(e.to_string()))?;
+
+        let mut rules = config
+            .rules
+            .into_iter()
+            .enumerate()
+            .map(|(i, rule)| {
+                let (kind, pattern) = match (rule.regex, rule.keyword, rule.prefix) {
+                    (Some(regex), None, None) => ("regex", regex),
+                    (None, Some(keyword), None) => ("keyword", format!(r"\b{}\b", regex::escape(&keyword))),
+                    (None, None, Some(prefix)) => ("prefix", format!(r"^\s*{}", regex::escape(&prefix))),
+                    _ => {
+                        return Err(RoutingError::Config(format!(
+                            "rule {} needs exactly one of regex, keyword or prefix",
+                            i + 1
+                        )))
+                    }
+                };
+                let name = rule.name.unwrap_or_else(|| format!("{} {}", kind, pattern));
+                let action = match (rule.agent, rule.block) {
+                    (Some(agent), None) => RouteAction::Agent(agent),
+                    (None, Some(reason)) => RouteAction::Block(reason),
+                    _ => {
+                        return Err(RoutingError::Config(format!(
+                            "rule {} needs exactly one of agent or block",
+                            name
+                        )))
+                    }
+                };
+                let pattern = RegexBuilder::new(&pattern)
+                    .case_insensitive(!rule.case_sensitive)
+                    .build()
+                    .map_err(|e| RoutingError::Config(format!("invalid pattern for rule {}: {}", name, e)))?;
+
+                Ok(RoutingRule {
+                    name,
+                    priority: rule.priority,
+                    action,
+                    pattern,
+                })
+            })
+            .collect::<Result<Vec<_>, RoutingError>>()?;
+        // stable, so rules with the same priority keep the order of the file
+        rules.sort_by_key(|rule| std::cmp::Reverse(rule.priority));
+
+        Ok(RoutingRules { rules })
+    }
+
+    pub fn rules(&self) -> &[RoutingRule] {
+        &self.rules
+    }
+
+    /// The rule with the highest priority that matches `input`.
+    pub fn route(&self, input: &str) -> Option<&RoutingRule> {
+        self.rules.iter().find(|rule| rule.matches(input.trim()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::RoutingError;
+
+    use super::{RouteAction, RoutingRules};
+
+    const RULES: &str = r#"
+[[rules]]
+name = "arithmetic"
+regex = '^what is [0-9]'
+agent = "calculator"
+
+[[rules]]
+keyword = "password"
+block = "inputs about credentials are not allowed"
+priority = 100
+
+[[rules]]
+prefix = "tweet:"
+agent = "tweetu"
+
+[[rules]]
+name = "math words"
+keyword = "sum"
+agent = "wiki"
+priority = -1
+"#;
+
+    #[test]
+    pub fn test_routing_rules() {
+        let rules = RoutingRules::from_toml(RULES).unwrap();
+        assert_eq!(rules.rules()[0].action, RouteAction::Block("inputs about credentials are not allowed".to_string()));
+
+        let route = |input| rules.route(input).map(|rule| (rule.name.as_str(), rule.action.clone()));
+        assert_eq!(route("What is 999 - 1?"), Some(("arithmetic", RouteAction::Agent("calculator".to_string()))));
+        assert_eq!(route("what is the sum of 1 and 2").unwrap().0, "math words");
+        assert_eq!(route("  Tweet: rust 1.70 is out").unwrap().1, RouteAction::Agent("tweetu".to_string()));
+        assert!(matches!(route("what is 1 + my password").unwrap().1, RouteAction::Block(_)));
+        assert!(route("passwords are hard").is_none(), "keywords only match whole words");
+        assert!(route("write a tweet: about rust").is_none(), "prefixes only match at the start");
+
+        assert!(!RoutingRules::from_toml(include_str!("../config/routing.toml")).unwrap().rules().is_empty());
+        for invalid in [
+            "[[rules]]\nagent = \"calculator\"\n",
+            "[[rules]]\nregex = '^what'\nprefix = \"what\"\nagent = \"calculator\"\n",
+            "[[rules]]\nregex = '^what'\n",
+            "[[rules]]\nregex = '('\nagent = \"calculator\"\n",
+            "[[rules]]\nregex = '^what'\nagent = \"calculator\"\nweight = 1\n",
+        ] {
+            assert!(matches!(RoutingRules::from_toml(invalid), Err(RoutingError::Config(_))), "{}", invalid);
+        }
+    }
+}
@@ -0,0 +1,318 @@
+use std::collections::HashMap;
+
+use crate::{
+    action::{Action, CommandSignature},
+    meeseeks_proto::{arg_value::Value, ArgValue, CommandSchema, ParameterSchema, ParameterType, TaskRequest},
+};
+
+#[derive(Debug, Clone, PartialEq, thiserror::Error)]
+pub enum SchemaError {
+    #[error("unknown command {0}")]
+    UnknownCommand(String),
+
+    #[error("command {command} has no parameter named {param}")]
+    UnknownParameter { command: String, param: String },
+
+    #[error("too many arguments for command {command}. expected at most {expected}")]
+    TooManyArguments { command: String, expected: usize },
+
+    #[error("missing required argument {param} for command {command}")]
+    MissingArgument { command: String, param: String },
+
+    #[error("argument {param} of command {command} must be a {expected}")]
+    InvalidType {
+        command: String,
+        param: String,
+        expected: &'static str,
+    },
+}
+
+impl CommandSchema {
+    /// Builds a schema from a command signature like `tweet(topic)`. Every parameter is a
+    /// required string.
+    pub fn from_signature(signature: &CommandSignature) -> Self {
+        CommandSchema {
+            name: signature.name.clone(),
+            description: String::new(),
+            parameters: signature
+                .params
+                .iter()
+                .map(|param| ParameterSchema::string(param, "", true))
+                .collect(),
+            aliases: Vec::new(),
+        }
+    }
+
+    pub fn parameter(&self, name: &str) -> Option<&ParameterSchema> {
+        self.parameters.iter().find(|param| param.name == name)
+    }
+}
+
+impl ParameterSchema {
+    pub fn string(name: &str, description: &str, required: bool) -> Self {
+        ParameterSchema {
+            name: name.to_string(),
+            description: description.to_string(),
+            r#type: ParameterType::String.into(),
+            required,
+        }
+    }
+
+    pub fn number(name: &str, description: &str, required: bool) -> Self {
+        ParameterSchema {
+            r#type: ParameterType::Number.into(),
+            ..ParameterSchema::string(name, description, required)
+        }
+    }
+}
+
+impl ArgValue {
+    pub fn string(value: impl Into<String>) -> Self {
+        ArgValue {
+            value: Some(Value::StringValue(value.into())),
+        }
+    }
+
+    pub fn number(value: f64) -> Self {
+        ArgValue {
+            value: Some(Value::NumberValue(value)),
+        }
+    }
+
+    pub fn boolean(value: bool) -> Self {
+        ArgValue {
+            value: Some(Value::BoolValue(value)),
+        }
+    }
+}
+
+impl TaskRequest {
+    pub fn string_arg(&self, name: &str) -> Option<&str> {
+        match self.named_args.get(name)?.value.as_ref()? {
+            Value::StringValue(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn number_arg(&self, name: &str) -> Option<f64> {
+        match self.named_args.get(name)?.value.as_ref()? {
+            Value::NumberValue(value) => Some(*value),
+            _ => None,
+        }
+    }
+
+    pub fn bool_arg(&self, name: &str) -> Option<bool> {
+        match self.named_args.get(name)?.value.as_ref()? {
+            Value::BoolValue(value) => Some(*value),
+            _ => None,
+        }
+    }
+}
+
+/// Derives schemas for commands that were advertised without one. Commands whose signature
+/// cannot be parsed are skipped.
+pub fn schemas_from_commands(commands: &[String]) -> Vec<CommandSchema> {
+    commands
+        .iter()
+        .filter_map(|command| CommandSignature::parse(command).ok())
+        .map(|signature| CommandSchema::from_signature(&signature))
+        .collect()
+}
+
+/// Binds the arguments of a parsed action to the parameters of `schema`. Named arguments are
+/// matched by name and positional ones fill the remaining parameters in order. If the command has a
+/// single parameter, the whole argument list is bound to it so that unquoted commas survive.
+pub fn bind(action: &Action, schema: &CommandSchema) -> Result<HashMap<String, ArgValue>, SchemaError> {
+    let mut bound = HashMap::new();
+
+    let positional: Vec<&str> = action
+        .args
+        .iter()
+        .filter(|arg| arg.name.is_none())
+        .map(|arg| arg.value.as_str())
+        .collect();
+
+    if schema.parameters.len() == 1 && positional.len() > 1 && action.args.iter().all(|arg| !arg.quoted) {
+        bound.insert(schema.parameters[0].name.clone(), ArgValue::string(&action.raw_args));
+        return validate_args(schema, bound);
+    }
+
+    for arg in action.args.iter().filter(|arg| arg.name.is_some()) {
+        let name = arg.name.clone().unwrap();
+        if schema.parameter(&name).is_none() {
+            return Err(SchemaError::UnknownParameter {
+                command: schema.name.clone(),
+                param: name,
+            });
+        }
+        bound.insert(name, ArgValue::string(&arg.value));
+    }
+
+    let free: Vec<&ParameterSchema> = schema
+        .parameters
+        .iter()
+        .filter(|param| !bound.contains_key(&param.name))
+        .collect();
+    let mut free = free.into_iter();
+    for value in positional {
+        match free.next() {
+            Some(param) => {
+                bound.insert(param.name.clone(), ArgValue::string(value));
+            }
+            None => {
+                return Err(SchemaError::TooManyArguments {
+                    command: schema.name.clone(),
+                    expected: schema.parameters.len(),
+                })
+            }
+        }
+    }
+
+    validate_args(schema, bound)
+}
+
+/// Validates a task against the schemas of the agent it is sent to. Arguments are converted to the
+/// parameter types where possible, and a task without named arguments gets its first positional
+/// argument bound to the command's first parameter.
+pub fn validate(task: TaskRequest, schemas: &[CommandSchema]) -> Result<TaskRequest, SchemaError> {
+    let schema = schemas
+        .iter()
+        .find(|schema| schema.name == task.instruction)
+        .ok_or_else(|| SchemaError::UnknownCommand(task.instruction.clone()))?;
+
+    let mut task = task;
+    let mut named_args = std::mem::take(&mut task.named_args);
+    if named_args.is_empty() {
+        if let (Some(param), Some(arg)) = (schema.parameters.first(), task.args.first()) {
+            named_args.insert(param.name.clone(), ArgValue::string(arg));
+        }
+    }
+
+    task.named_args = validate_args(schema, named_args)?;
+    Ok(task)
+}
+
+fn validate_args(
+    schema: &CommandSchema,
+    args: HashMap<String, ArgValue>,
+) -> Result<HashMap<String, ArgValue>, SchemaError> {
+    let mut validated = HashMap::new();
+
+    for (name, value) in args {
+        let param = schema.parameter(&name).ok_or_else(|| SchemaError::UnknownParameter {
+            command: schema.name.clone(),
+            param: name.clone(),
+        })?;
+
+        let invalid = |expected| SchemaError::InvalidType {
+            command: schema.name.clone(),
+            param: name.clone(),
+            expected,
+        };
+
+        let value = match (param.r#type(), value.value) {
+            (ParameterType::String, Some(Value::StringValue(v))) => ArgValue::string(v),
+            (ParameterType::String, Some(Value::NumberValue(v))) => ArgValue::string(v.to_string()),
+            (ParameterType::String, Some(Value::BoolValue(v))) => ArgValue::string(v.to_string()),
+            (ParameterType::Number, Some(Value::NumberValue(v))) => ArgValue::number(v),
+            (ParameterType::Number, Some(Value::StringValue(v))) => {
+                ArgValue::number(v.trim().parse().map_err(|_| invalid("number"))?)
+            }
+            (ParameterType::Boolean, Some(Value::BoolValue(v))) => ArgValue::boolean(v),
+            (ParameterType::Boolean, Some(Value::StringValue(v))) => match v.trim().to_lowercase().as_str() {
+                "true" | "yes" => ArgValue::boolean(true),
+                "false" | "no" => ArgValue::boolean(false),
+                _ => return Err(invalid("boolean")),
+            },
+            (ParameterType::Number, _) => return Err(invalid("number")),
+            (ParameterType::Boolean, _) => return Err(invalid("boolean")),
+            (ParameterType::String, None) => return Err(invalid("string")),
+        };
+        validated.insert(name, value);
+    }
+
+    if let Some(missing) = schema
+        .parameters
+        .iter()
+        .find(|param| param.required && !validated.contains_key(&param.name))
+    {
+        return Err(SchemaError::MissingArgument {
+            command: schema.name.clone(),
+            param: missing.name.clone(),
+        });
+    }
+
+    Ok(validated)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        action::Action,
+        meeseeks_proto::{CommandSchema, ParameterSchema, TaskRequest},
+    };
+
+    use super::{bind, validate, SchemaError};
+
+    fn schemas() -> Vec<CommandSchema> {
+        vec![
+            CommandSchema {
+                name: "tweet".to_string(),
+                description: "write a tweet".to_string(),
+                parameters: vec![ParameterSchema::string("topic", "what to tweet about", true)],
+                aliases: Vec::new(),
+            },
+            CommandSchema {
+                name: "round".to_string(),
+                description: "round a number".to_string(),
+                parameters: vec![
+                    ParameterSchema::number("value", "number to round", true),
+                    ParameterSchema::number("digits", "number of decimal digits", false),
+                ],
+                aliases: Vec::new(),
+            },
+        ]
+    }
+
+    #[test]
+    pub fn test_bind_action() {
+        let schemas = schemas();
+
+        let action = Action::parse("tweet[apples, oranges and pears]").unwrap();
+        let args = bind(&action, &schemas[0]).unwrap();
+        assert_eq!(args["topic"].value, Some(super::Value::StringValue("apples, oranges and pears".into())));
+
+        let action = Action::parse("round(1.23456, digits=2)").unwrap();
+        let args = bind(&action, &schemas[1]).unwrap();
+        assert_eq!(args["value"].value, Some(super::Value::NumberValue(1.23456)));
+        assert_eq!(args["digits"].value, Some(super::Value::NumberValue(2.0)));
+
+        let action = Action::parse("round(pi)").unwrap();
+        assert!(matches!(bind(&action, &schemas[1]), Err(SchemaError::InvalidType { .. })));
+
+        let action = Action::parse("round(digits=2)").unwrap();
+        assert!(matches!(bind(&action, &schemas[1]), Err(SchemaError::MissingArgument { .. })));
+
+        let action = Action::parse("round(1, 2, 3)").unwrap();
+        assert!(matches!(bind(&action, &schemas[1]), Err(SchemaError::TooManyArguments { .. })));
+    }
+
+    #[test]
+    pub fn test_validate_task() {
+        let schemas = schemas();
+
+        let task = TaskRequest {
+            instruction: "tweet".to_string(),
+            args: vec!["apples".to_string(), "write a tweet about apples".to_string()],
+            ..Default::default()
+        };
+        let task = validate(task, &schemas).unwrap();
+        assert_eq!(task.string_arg("topic"), Some("apples"));
+
+        let task = TaskRequest {
+            instruction: "twet".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(validate(task, &schemas), Err(SchemaError::UnknownCommand("twet".to_string())));
+    }
+}
@@ -0,0 +1,624 @@
+use std::{collections::HashMap, fmt, future::Future};
+
+use futures::{stream::FuturesUnordered, StreamExt};
+
+use crate::{
+    common::AgentMatch,
+    error::DispatchError,
+    meeseeks_proto::{arg_value::Value, Status, TaskRequest, TaskResponse},
+};
+
+lazy_static::lazy_static! {
+static ref RESULT_REF: regex::Regex = regex::Regex::new(r"\{\{\s*(?P<input>\d+)\.result\s*\}\}").unwrap();
+}
+
+/// A single input line together with the task it was parsed into and the agent that should run it.
+/// `task` or `agent` is `None` if parsing or matching the input failed, `error` then says why, or if
+/// no tool applies to the input, `settled` then holds its outcome.
+#[derive(Debug, Clone)]
+pub struct TaskNode {
+    pub input: String,
+    pub task: Option<TaskRequest>,
+    pub agent: Option<String>,
+    pub error: Option<String>,
+    /// The pools the matcher considered for the input, best first.
+    pub routing: Vec<AgentMatch>,
+    /// The routing rule that chose the agent instead of the matcher.
+    pub rule: Option<String>,
+    /// The outcome of an input that was settled without sending a task.
+    pub settled: Option<NodeStatus>,
+}
+
+impl TaskNode {
+    pub fn new(input: String, task: TaskRequest, agent: String) -> Self {
+        TaskNode {
+            input,
+            task: Some(task),
+            agent: Some(agent),
+            error: None,
+            routing: Vec::new(),
+            rule: None,
+            settled: None,
+        }
+    }
+
+    pub fn failed(input: String, error: String) -> Self {
+        TaskNode {
+            input,
+            task: None,
+            agent: None,
+            error: Some(error),
+            routing: Vec::new(),
+            rule: None,
+            settled: None,
+        }
+    }
+
+    /// An input none of the agents can handle. `reason` tells the user what to do about it.
+    pub fn no_tool(input: String, reason: String) -> Self {
+        TaskNode {
+            input,
+            task: None,
+            agent: None,
+            error: None,
+            routing: Vec::new(),
+            rule: None,
+            settled: Some(NodeStatus::NoTool(reason)),
+        }
+    }
+
+    /// An input no agent can handle that was answered without a tool.
+    pub fn answered(input: String, answer: String) -> Self {
+        TaskNode {
+            input,
+            task: None,
+            agent: None,
+            error: None,
+            routing: Vec::new(),
+            rule: None,
+            settled: Some(NodeStatus::Answered(answer)),
+        }
+    }
+
+    pub fn with_routing(mut self, routing: Vec<AgentMatch>, rule: Option<String>) -> Self {
+        self.routing = routing;
+        self.rule = rule;
+        self
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum NodeStatus {
+    Success(String),
+    /// The agent ran the task and reported a failure, or rejected it.
+    Failure(String),
+    /// The agent did not respond in time.
+    TimedOut(String),
+    /// No replica of the agent could be reached or all of their circuits are open.
+    Unavailable(String),
+    /// The node was never sent to an agent because it could not be parsed or one of its
+    /// dependencies did not succeed.
+    Skipped(String),
+    /// None of the agents can handle the input. Not a failure, the input asks for something no
+    /// tool offers.
+    NoTool(String),
+    /// No agent can handle the input and it was answered without a tool instead.
+    Answered(String),
+}
+
+impl NodeStatus {
+    /// Whether the node has a result that dependent nodes can use.
+    pub fn has_result(&self) -> bool {
+        matches!(self, NodeStatus::Success(_) | NodeStatus::Answered(_))
+    }
+}
+
+impl From<DispatchError> for NodeStatus {
+    fn from(error: DispatchError) -> Self {
+        match error.last() {
+            DispatchError::TimedOut { .. } => NodeStatus::TimedOut(error.to_string()),
+            DispatchError::NotFound(_) | DispatchError::Unavailable(_) | DispatchError::CircuitOpen(_) => {
+                NodeStatus::Unavailable(error.to_string())
+            }
+            e if e.is_transport() => NodeStatus::Unavailable(error.to_string()),
+            _ => NodeStatus::Failure(error.to_string()),
+        }
+    }
+}
+
+impl fmt::Display for NodeStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NodeStatus::Success(res) => write!(f, "success: {}", res),
+            NodeStatus::Failure(res) => write!(f, "failure: {}", res),
+            NodeStatus::TimedOut(res) => write!(f, "timed out: {}", res),
+            NodeStatus::Unavailable(res) => write!(f, "unavailable: {}", res),
+            NodeStatus::Skipped(reason) => write!(f, "skipped: {}", reason),
+            NodeStatus::NoTool(reason) => write!(f, "no tool applies: {}", reason),
+            NodeStatus::Answered(answer) => write!(f, "answered without a tool: {}", answer),
+        }
+    }
+}
+
+/// A try at running a node that was rejected by the agent and then corrected.
+#[derive(Debug, Clone)]
+pub struct Attempt {
+    pub task: TaskRequest,
+    pub agent: String,
+    pub status: NodeStatus,
+}
+
+#[derive(Debug, Clone)]
+pub struct NodeResult {
+    pub input: String,
+    /// The last task that was sent, after any corrections.
+    pub task: Option<TaskRequest>,
+    pub agent: Option<String>,
+    pub status: NodeStatus,
+    /// Earlier tries that were rejected, in the order they were sent.
+    pub attempts: Vec<Attempt>,
+}
+
+/// A batch of tasks where a task can use the result of an earlier input by referencing it as
+/// `{{N.result}}`, N being the 1-based position of that input. An input may have been parsed into
+/// several tasks: a reference waits for all of them and is replaced with the result of the last.
+/// Tasks that do not depend on each other are executed concurrently.
+#[derive(Debug)]
+pub struct TaskGraph {
+    nodes: Vec<TaskNode>,
+    deps: Vec<Vec<usize>>,
+    /// Indices of the nodes of every input, in input order.
+    inputs: Vec<Vec<usize>>,
+}
+
+impl TaskGraph {
+    /// A graph with one node per input.
+    pub fn new(nodes: Vec<TaskNode>) -> Self {
+        Self::from_inputs(nodes.into_iter().map(|node| vec![node]).collect())
+    }
+
+    /// A graph of the nodes each input was parsed into, in input order.
+    pub fn from_inputs(inputs: Vec<Vec<TaskNode>>) -> Self {
+        let mut nodes = Vec::new();
+        let mut input_nodes = Vec::with_capacity(inputs.len());
+        let mut input_of = Vec::new();
+        for (input, group) in inputs.into_iter().enumerate() {
+            input_nodes.push((nodes.len()..nodes.len() + group.len()).collect::<Vec<_>>());
+            input_of.resize(input_of.len() + group.len(), input);
+            nodes.extend(group);
+        }
+
+        let mut deps = Vec::with_capacity(nodes.len());
+        for (node, &input) in nodes.iter_mut().zip(&input_of) {
+            let mut node_deps = Vec::new();
+            let refs = node
+                .task
+                .iter()
+                .flat_map(|task| {
+                    task.args.iter().chain(task.named_args.values().filter_map(|arg| match &arg.value {
+                        Some(Value::StringValue(value)) => Some(value),
+                        _ => None,
+                    }))
+                })
+                .chain(std::iter::once(&node.input))
+                .flat_map(|text| RESULT_REF.captures_iter(text))
+                .filter_map(|caps| caps.name("input")?.as_str().parse::<usize>().ok());
+
+            for dep in refs {
+                let dep_nodes = match dep.checked_sub(1) {
+                    Some(dep) if dep < input => &input_nodes[dep],
+                    _ => {
+                        node.error.get_or_insert(format!(
+                            "invalid reference to input {}. tasks can only use results of earlier inputs",
+                            dep
+                        ));
+                        continue;
+                    }
+                };
+                if dep_nodes.is_empty() {
+                    node.error
+                        .get_or_insert(format!("input {} has no task whose result could be used", dep));
+                    continue;
+                }
+                for &dep_node in dep_nodes {
+                    if !node_deps.contains(&dep_node) {
+                        node_deps.push(dep_node);
+                    }
+                }
+            }
+            deps.push(node_deps);
+        }
+
+        TaskGraph {
+            nodes,
+            deps,
+            inputs: input_nodes,
+        }
+    }
+
+    pub fn nodes(&self) -> &[TaskNode] {
+        &self.nodes
+    }
+
+    /// Indices of the nodes the node at `index` depends on.
+    pub fn dependencies(&self, index: usize) -> &[usize] {
+        &self.deps[index]
+    }
+
+    /// The 1-based number of the input the node at `index` belongs to, as used by `{{N.result}}`.
+    fn input_number(&self, index: usize) -> usize {
+        self.inputs.iter().position(|nodes| nodes.contains(&index)).unwrap_or(index) + 1
+    }
+
+    /// Executes the graph using `dispatch` to send a task to the named agent. Returns one result per
+    /// node, in input order.
+    pub async fn execute<F, Fut>(self, dispatch: F) -> Vec<NodeResult>
+    where
+        F: Fn(String, TaskRequest) -> Fut,
+        Fut: Future<Output = Result<TaskResponse, DispatchError>>,
+    {
+        self.execute_with_corrections(dispatch, |_, _, _| async { None }, 0).await
+    }
+
+    /// Like `execute`, but a task the agent rejects because of its instruction or arguments is
+    /// passed to `correct` together with the node's input and the error. The corrected task and
+    /// the agent to send it to are dispatched instead, up to `max_corrections` times per node.
+    pub async fn execute_with_corrections<F, Fut, C, CFut>(
+        self,
+        dispatch: F,
+        correct: C,
+        max_corrections: usize,
+    ) -> Vec<NodeResult>
+    where
+        F: Fn(String, TaskRequest) -> Fut,
+        Fut: Future<Output = Result<TaskResponse, DispatchError>>,
+        C: Fn(String, TaskRequest, NodeStatus) -> CFut,
+        CFut: Future<Output = Option<(TaskRequest, String)>>,
+    {
+        let mut status: Vec<Option<NodeStatus>> = vec![None; self.nodes.len()];
+        let mut started = vec![false; self.nodes.len()];
+        let mut sent: HashMap<usize, (TaskRequest, String, Vec<Attempt>)> = HashMap::new();
+
+        for (i, node) in self.nodes.iter().enumerate() {
+            if let Some(settled) = &node.settled {
+                status[i] = Some(settled.clone());
+            } else if let Some(err) = &node.error {
+                status[i] = Some(NodeStatus::Skipped(err.clone()));
+            } else if node.task.is_none() || node.agent.is_none() {
+                status[i] = Some(NodeStatus::Skipped("failed to parse input into a task".to_string()));
+            }
+        }
+
+        let run = |i: usize, task: TaskRequest| {
+            let node = &self.nodes[i];
+            let (dispatch, correct) = (&dispatch, &correct);
+            let input = self.input_number(i);
+            async move {
+                let mut task = task;
+                let mut agent = node.agent.clone().unwrap();
+                let mut attempts = Vec::new();
+                loop {
+                    let (node_status, correctable) = match dispatch(agent.clone(), task.clone()).await {
+                        Ok(res) => match res.status() {
+                            Status::Success => (NodeStatus::Success(res.response), false),
+                            Status::Failure => (NodeStatus::Failure(res.failure_message()), res.is_correctable()),
+                        },
+                        Err(e) => {
+                            let correctable = e.is_correctable();
+                            (e.into(), correctable)
+                        }
+                    };
+
+                    if correctable && attempts.len() < max_corrections {
+                        let corrected = correct(node.input.clone(), task.clone(), node_status.clone()).await;
+                        if let Some((corrected, corrected_agent)) = corrected {
+                            tracing::info!("task of input {} was rejected ({}). retrying as {:?}", input, node_status, corrected);
+                            attempts.push(Attempt {
+                                task: std::mem::replace(&mut task, corrected),
+                                agent: std::mem::replace(&mut agent, corrected_agent),
+                                status: node_status,
+                            });
+                            continue;
+                        }
+                    }
+
+                    return (i, task, agent, attempts, node_status);
+                }
+            }
+        };
+
+        // every node starts as soon as its own dependencies are settled, so a slow task only holds
+        // up the tasks that use its result
+        let mut running = FuturesUnordered::new();
+        loop {
+            // dependencies point to earlier nodes, so one pass in order also settles the dependents
+            // of nodes skipped in the same pass
+            for i in 0..self.nodes.len() {
+                if status[i].is_some() || started[i] {
+                    continue;
+                }
+
+                let deps = &self.deps[i];
+                if let Some(&failed) = deps
+                    .iter()
+                    .find(|&&dep| matches!(&status[dep], Some(dep_status) if !dep_status.has_result()))
+                {
+                    status[i] = Some(NodeStatus::Skipped(format!(
+                        "input {} did not succeed",
+                        self.input_number(failed)
+                    )));
+                    continue;
+                }
+
+                if deps.iter().all(|&dep| status[dep].is_some()) {
+                    let mut task = self.nodes[i].task.clone().unwrap();
+                    for arg in task.args.iter_mut() {
+                        *arg = substitute_results(arg, &status, &self.inputs);
+                    }
+                    for arg in task.named_args.values_mut() {
+                        if let Some(Value::StringValue(value)) = &mut arg.value {
+                            *value = substitute_results(value, &status, &self.inputs);
+                        }
+                    }
+                    tracing::debug!("executing task of input {}", self.input_number(i));
+                    started[i] = true;
+                    running.push(run(i, task));
+                }
+            }
+
+            match running.next().await {
+                Some((i, task, agent, attempts, node_status)) => {
+                    status[i] = Some(node_status);
+                    sent.insert(i, (task, agent, attempts));
+                }
+                None => break,
+            }
+        }
+        drop(running);
+
+        // dependencies only point to earlier inputs, so this cannot happen unless the graph is
+        // broken. fail the remaining nodes instead of waiting for them forever
+        for (i, node_status) in status.iter_mut().enumerate().filter(|(_, status)| status.is_none()) {
+            *node_status = Some(NodeStatus::Skipped(format!(
+                "input {} depends on tasks that can never run",
+                self.input_number(i)
+            )));
+        }
+
+        self.nodes
+            .into_iter()
+            .zip(status)
+            .enumerate()
+            .map(|(i, (node, status))| match sent.remove(&i) {
+                Some((task, agent, attempts)) => NodeResult {
+                    input: node.input,
+                    task: Some(task),
+                    agent: Some(agent),
+                    status: status.unwrap(),
+                    attempts,
+                },
+                None => NodeResult {
+                    input: node.input,
+                    task: node.task,
+                    agent: node.agent,
+                    status: status.unwrap(),
+                    attempts: Vec::new(),
+                },
+            })
+            .collect()
+    }
+}
+
+/// Replaces every `{{N.result}}` in `text` with the result of the last node of input N. `inputs`
+/// holds the nodes of every input.
+fn substitute_results(text: &str, status: &[Option<NodeStatus>], inputs: &[Vec<usize>]) -> String {
+    RESULT_REF
+        .replace_all(text, |caps: &regex::Captures| {
+            let input = caps["input"].parse::<usize>().unwrap_or_default();
+            let last = input.checked_sub(1).and_then(|i| inputs.get(i)?.last());
+            match last.map(|&node| &status[node]) {
+                Some(Some(NodeStatus::Success(res) | NodeStatus::Answered(res))) => res.trim().to_string(),
+                _ => caps[0].to_string(),
+            }
+        })
+        .into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Mutex, time::Duration};
+
+    use crate::{
+        error::MeeseeksError,
+        meeseeks_proto::{TaskRequest, TaskResponse},
+    };
+
+    use super::{NodeStatus, TaskGraph, TaskNode};
+
+    fn node(input: &str, instruction: &str, arg: &str) -> TaskNode {
+        TaskNode::new(
+            input.to_string(),
+            TaskRequest {
+                instruction: instruction.to_string(),
+                args: vec![arg.to_string(), input.to_string()],
+                ..Default::default()
+            },
+            "agent".to_string(),
+        )
+    }
+
+    #[tokio::test]
+    pub async fn test_task_graph_substitutes_results() {
+        let graph = TaskGraph::new(vec![
+            node("what is 2 + 2?", "calculate", "2 + 2"),
+            node("write a tweet about {{1.result}}", "tweet", "{{1.result}}"),
+            node("what is 3 * 3?", "calculate", "3 * 3"),
+        ]);
+        assert_eq!(graph.dependencies(1), &[0]);
+        assert!(graph.dependencies(2).is_empty());
+
+        let order = Mutex::new(Vec::new());
+        let results = graph
+            .execute(|_, task| {
+                order.lock().unwrap().push(task.instruction.clone());
+                let response = match task.instruction.as_str() {
+                    "calculate" if task.args[0] == "2 + 2" => "4".to_string(),
+                    "calculate" => "9".to_string(),
+                    _ => format!("tweet about {}", task.args[0]),
+                };
+                async move { Ok(TaskResponse::success(response)) }
+            })
+            .await;
+
+        assert_eq!(order.into_inner().unwrap(), vec!["calculate", "calculate", "tweet"]);
+        assert_eq!(results.len(), 3);
+        assert_eq!(results[0].status, NodeStatus::Success("4".to_string()));
+        assert_eq!(results[1].status, NodeStatus::Success("tweet about 4".to_string()));
+        assert_eq!(results[2].status, NodeStatus::Success("9".to_string()));
+    }
+
+    #[tokio::test]
+    pub async fn test_task_graph_references_inputs() {
+        let graph = TaskGraph::from_inputs(vec![
+            vec![
+                node("what is 2 + 2 times 10?", "calculate", "2 + 2"),
+                node("what is 2 + 2 times 10?", "calculate", "4 * 10"),
+            ],
+            Vec::new(),
+            vec![node("write a tweet about {{1.result}}", "tweet", "{{1.result}}")],
+            vec![node("write a tweet about {{2.result}}", "tweet", "{{2.result}}")],
+        ]);
+        assert_eq!(graph.dependencies(2), &[0, 1]);
+
+        let results = graph
+            .execute(|_, task| {
+                let response = match task.instruction.as_str() {
+                    "calculate" if task.args[0] == "2 + 2" => "4".to_string(),
+                    "calculate" => "40".to_string(),
+                    _ => format!("tweet about {}", task.args[0]),
+                };
+                async move { Ok(TaskResponse::success(response)) }
+            })
+            .await;
+
+        assert_eq!(results.len(), 4);
+        assert_eq!(results[2].status, NodeStatus::Success("tweet about 40".to_string()));
+        assert_eq!(
+            results[3].status,
+            NodeStatus::Skipped("input 2 has no task whose result could be used".to_string())
+        );
+    }
+
+    #[tokio::test]
+    pub async fn test_task_graph_does_not_wait_for_unrelated_tasks() {
+        let graph = TaskGraph::new(vec![
+            node("summarize the history of rome", "summary", "history of rome"),
+            node("what is 2 + 2?", "calculate", "2 + 2"),
+            node("write a tweet about {{2.result}}", "tweet", "{{2.result}}"),
+        ]);
+
+        let events = Mutex::new(Vec::new());
+        let results = graph
+            .execute(|_, task| {
+                let events = &events;
+                async move {
+                    events.lock().unwrap().push(format!("start {}", task.instruction));
+                    if task.instruction == "summary" {
+                        tokio::time::sleep(Duration::from_millis(300)).await;
+                    }
+                    events.lock().unwrap().push(format!("end {}", task.instruction));
+                    Ok(TaskResponse::success("4"))
+                }
+            })
+            .await;
+
+        assert!(results.iter().all(|res| res.status.has_result()));
+        let events = events.into_inner().unwrap();
+        assert_eq!(events.last().unwrap(), "end summary", "{:?}", events);
+        assert!(events.contains(&"end tweet".to_string()), "{:?}", events);
+    }
+
+    #[tokio::test]
+    pub async fn test_task_graph_skips_dependents_of_failed_tasks() {
+        let graph = TaskGraph::new(vec![
+            node("what is 1 / x?", "calculate", "1 / x"),
+            node("write a tweet about {{1.result}}", "tweet", "{{1.result}}"),
+            node("write a tweet about {{3.result}}", "tweet", "{{3.result}}"),
+        ]);
+
+        let results = graph
+            .execute(|_, _| async {
+                Ok(TaskResponse::failure(MeeseeksError::InvalidArguments(
+                    "failed to evaluate expression".to_string(),
+                )))
+            })
+            .await;
+
+        assert!(matches!(results[0].status, NodeStatus::Failure(_)));
+        assert_eq!(results[1].status, NodeStatus::Skipped("input 1 did not succeed".to_string()));
+        assert!(matches!(results[2].status, NodeStatus::Skipped(_)));
+    }
+
+    #[tokio::test]
+    pub async fn test_task_graph_settled_nodes() {
+        let graph = TaskGraph::new(vec![
+            TaskNode::answered("who wrote faust?".to_string(), "Goethe".to_string()),
+            node("write a tweet about {{1.result}}", "tweet", "{{1.result}}"),
+            TaskNode::no_tool("book a flight".to_string(), "please rephrase the input".to_string()),
+            node("write a tweet about {{3.result}}", "tweet", "{{3.result}}"),
+        ]);
+
+        let sent = Mutex::new(Vec::new());
+        let results = graph
+            .execute(|_, task| {
+                sent.lock().unwrap().push(task.args[0].clone());
+                async { Ok(TaskResponse::success("tweeted".to_string())) }
+            })
+            .await;
+
+        assert_eq!(results[0].status, NodeStatus::Answered("Goethe".to_string()));
+        assert_eq!(results[1].status, NodeStatus::Success("tweeted".to_string()));
+        assert_eq!(results[2].status.to_string(), "no tool applies: please rephrase the input");
+        assert_eq!(results[3].status, NodeStatus::Skipped("input 3 did not succeed".to_string()));
+        assert_eq!(*sent.lock().unwrap(), vec!["Goethe".to_string()]);
+    }
+
+    #[tokio::test]
+    pub async fn test_task_graph_corrects_rejected_tasks() {
+        let graph = TaskGraph::new(vec![node("write a tweet about rust", "twet", "rust")]);
+
+        let results = graph
+            .execute_with_corrections(
+                |_, task| async move {
+                    match task.instruction.as_str() {
+                        "tweet" => Ok(TaskResponse::success(format!("tweet about {}", task.args[0]))),
+                        _ => Ok(TaskResponse::failure(MeeseeksError::InvalidInstruction {
+                            instruction: task.instruction,
+                            available: vec!["tweet".to_string()],
+                        })),
+                    }
+                },
+                |_, task, _| async move {
+                    let corrected = match task.instruction.as_str() {
+                        "twet" => "tweeet",
+                        _ => "tweet",
+                    };
+                    Some((
+                        TaskRequest {
+                            instruction: corrected.to_string(),
+                            ..task
+                        },
+                        "agent".to_string(),
+                    ))
+                },
+                2,
+            )
+            .await;
+
+        assert_eq!(results[0].status, NodeStatus::Success("tweet about rust".to_string()));
+        assert_eq!(results[0].task.as_ref().unwrap().instruction, "tweet");
+        let tried: Vec<&str> = results[0].attempts.iter().map(|a| a.task.instruction.as_str()).collect();
+        assert_eq!(tried, vec!["twet", "tweeet"]);
+        assert!(matches!(results[0].attempts[0].status, NodeStatus::Failure(_)));
+    }
+}
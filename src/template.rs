@@ -0,0 +1,401 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime},
+};
+
+use crate::error::TemplateError;
+
+/// A template the parsers or tools render, and the variables they render it with.
+struct Spec {
+    name: &'static str,
+    /// Variables that must have a placeholder in the template.
+    required: &'static [&'static str],
+    /// Variables the template may leave out.
+    optional: &'static [&'static str],
+    default: &'static str,
+}
+
+const SPECS: &[Spec] = &[
+    Spec {
+        name: "parse",
+        required: &["tools", "input"],
+        optional: &["examples"],
+        default: include_str!("../prompts/parse.txt"),
+    },
+    Spec {
+        name: "react",
+        required: &["tools", "input"],
+        optional: &["examples"],
+        default: include_str!("../prompts/react.txt"),
+    },
+    Spec {
+        name: "correct",
+        required: &["action", "error"],
+        optional: &[],
+        default: include_str!("../prompts/correct.txt"),
+    },
+    Spec {
+        name: "answer",
+        required: &["input"],
+        optional: &[],
+        default: include_str!("../prompts/answer.txt"),
+    },
+    Spec {
+        name: "task-parse",
+        required: &["tools", "tasks"],
+        optional: &[],
+        default: include_str!("../prompts/task-parse.txt"),
+    },
+    Spec {
+        name: "tweetu-dialog",
+        required: &["topic"],
+        optional: &[],
+        default: include_str!("../prompts/tweetu-dialog.txt"),
+    },
+    Spec {
+        name: "examples/calculator",
+        required: &[],
+        optional: &[],
+        default: include_str!("../prompts/examples/calculator.txt"),
+    },
+    Spec {
+        name: "examples/tweetu",
+        required: &[],
+        optional: &[],
+        default: include_str!("../prompts/examples/tweetu.txt"),
+    },
+    Spec {
+        name: "examples/wiki",
+        required: &[],
+        optional: &[],
+        default: include_str!("../prompts/examples/wiki.txt"),
+    },
+];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Segment {
+    Text(String),
+    Variable(String),
+}
+
+/// Text with `{{name}}` placeholders that are filled in by name.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Template {
+    segments: Vec<Segment>,
+}
+
+impl Template {
+    /// Parses `text`. `name` is only used in errors.
+    pub fn parse(name: &str, text: &str) -> Result<Self, TemplateError> {
+        let mut segments = Vec::new();
+        let mut rest = text;
+        while let Some(start) = rest.find("{{") {
+            let end = rest[start..].find("}}").ok_or_else(|| TemplateError::Unclosed {
+                template: name.to_string(),
+                offset: text.len() - rest.len() + start,
+            })?;
+            if start > 0 {
+                segments.push(Segment::Text(rest[..start].to_string()));
+            }
+            segments.push(Segment::Variable(rest[start + 2..start + end].trim().to_string()));
+            rest = &rest[start + end + 2..];
+        }
+        if !rest.is_empty() {
+            segments.push(Segment::Text(rest.to_string()));
+        }
+
+        Ok(Template { segments })
+    }
+
+    /// Names of the variables the template has placeholders for, in order of appearance.
+    pub fn variables(&self) -> impl Iterator<Item = &str> {
+        self.segments.iter().filter_map(|segment| match segment {
+            Segment::Variable(name) => Some(name.as_str()),
+            Segment::Text(_) => None,
+        })
+    }
+
+    /// Fills in the placeholders with `vars`. Variables without a value are left empty.
+    pub fn render(&self, vars: &[(&str, &str)]) -> String {
+        let mut text = String::new();
+        for segment in &self.segments {
+            match segment {
+                Segment::Text(t) => text.push_str(t),
+                Segment::Variable(name) => {
+                    if let Some((_, value)) = vars.iter().find(|(var, _)| var == name) {
+                        text.push_str(value);
+                    }
+                }
+            }
+        }
+        text
+    }
+}
+
+impl Spec {
+    fn compile(&self, text: &str) -> Result<Template, TemplateError> {
+        let template = Template::parse(self.name, text)?;
+
+        if let Some(variable) = template
+            .variables()
+            .find(|var| !self.required.contains(var) && !self.optional.contains(var))
+        {
+            return Err(TemplateError::UnknownVariable {
+                template: self.name.to_string(),
+                variable: variable.to_string(),
+                expected: self.required.iter().chain(self.optional).map(|var| var.to_string()).collect(),
+            });
+        }
+        if let Some(variable) = self.required.iter().find(|var| !template.variables().any(|v| v == **var)) {
+            return Err(TemplateError::MissingVariable {
+                template: self.name.to_string(),
+                variable: variable.to_string(),
+            });
+        }
+
+        Ok(template)
+    }
+}
+
+struct Loaded {
+    template: Arc<Template>,
+    /// Modification time of the file the template was read from, or `None` for the builtin one.
+    modified: Option<SystemTime>,
+}
+
+/// The prompt templates of the parsers and tools.
+///
+/// Every template has a builtin default. With a directory configured, `<dir>/<name>.txt` replaces
+/// the default of the template `name`, e.g. `<dir>/parse.txt` or `<dir>/examples/wiki.txt`. The
+/// `prompts` directory of this repository has the same layout. Templates are checked when they
+/// are loaded: a template may only use the variables it is rendered with, and must use those
+/// that are required, like the `input` of the parse prompt.
+pub struct Templates {
+    dir: Option<PathBuf>,
+    loaded: RwLock<HashMap<&'static str, Loaded>>,
+}
+
+impl Default for Templates {
+    fn default() -> Self {
+        Self::builtin()
+    }
+}
+
+impl Templates {
+    /// The builtin templates.
+    pub fn builtin() -> Self {
+        let loaded = SPECS
+            .iter()
+            .map(|spec| {
+                let template = spec.compile(spec.default).expect("builtin templates are valid");
+                (spec.name, Loaded { template: Arc::new(template), modified: None })
+            })
+            .collect();
+
+        Templates {
+            dir: None,
+            loaded: RwLock::new(loaded),
+        }
+    }
+
+    /// The builtin templates, replaced by those found in `dir`. Fails if any of them is invalid.
+    pub fn load(dir: impl Into<PathBuf>) -> Result<Self, TemplateError> {
+        let dir = dir.into();
+        let mut loaded = HashMap::new();
+        for spec in SPECS {
+            let path = template_path(&dir, spec.name);
+            let template = match modified(&path) {
+                Some(modified) => Loaded {
+                    template: Arc::new(spec.compile(&read(&path)?)?),
+                    modified: Some(modified),
+                },
+                None => Loaded {
+                    template: Arc::new(spec.compile(spec.default)?),
+                    modified: None,
+                },
+            };
+            loaded.insert(spec.name, template);
+        }
+
+        Ok(Templates {
+            dir: Some(dir),
+            loaded: RwLock::new(loaded),
+        })
+    }
+
+    /// The template called `name`.
+    ///
+    /// # Panics
+    ///
+    /// If there is no template called `name`.
+    pub fn get(&self, name: &str) -> Arc<Template> {
+        let loaded = self.loaded.read().unwrap();
+        match loaded.get(name) {
+            Some(loaded) => loaded.template.clone(),
+            None => panic!("unknown template {}", name),
+        }
+    }
+
+    pub fn render(&self, name: &str, vars: &[(&str, &str)]) -> String {
+        self.get(name).render(vars)
+    }
+
+    /// Reads the templates whose files changed since they were loaded, and goes back to the builtin
+    /// template for files that were removed. Invalid files are reported and the template loaded
+    /// before is kept. Returns the names of the templates that changed.
+    pub fn reload(&self) -> Vec<&'static str> {
+        let dir = match &self.dir {
+            Some(dir) => dir,
+            None => return Vec::new(),
+        };
+
+        let mut reloaded = Vec::new();
+        for spec in SPECS {
+            let path = template_path(dir, spec.name);
+            let modified = modified(&path);
+            if self.loaded.read().unwrap()[spec.name].modified == modified {
+                continue;
+            }
+
+            let text = match modified {
+                Some(_) => match read(&path) {
+                    Ok(text) => text,
+                    Err(e) => {
+                        tracing::warn!("{}", e);
+                        continue;
+                    }
+                },
+                None => spec.default.to_string(),
+            };
+            let template = match spec.compile(&text) {
+                Ok(template) => template,
+                Err(e) => {
+                    tracing::warn!("keeping the previous template: {}", e);
+                    // remember the time so that the same invalid file is only reported once
+                    if let Some(loaded) = self.loaded.write().unwrap().get_mut(spec.name) {
+                        loaded.modified = modified;
+                    }
+                    continue;
+                }
+            };
+
+            tracing::info!("reloaded template {}", spec.name);
+            self.loaded.write().unwrap().insert(
+                spec.name,
+                Loaded {
+                    template: Arc::new(template),
+                    modified,
+                },
+            );
+            reloaded.push(spec.name);
+        }
+        reloaded
+    }
+
+    /// Spawns a task that checks the template files for changes every `interval` and reloads them.
+    /// Does nothing if no directory is configured.
+    pub fn watch(self: &Arc<Self>, interval: Duration) -> Option<tokio::task::JoinHandle<()>> {
+        self.dir.as_ref()?;
+        let templates = self.clone();
+
+        Some(tokio::spawn(async move {
+            let mut interval = tokio::time::interval(interval);
+            loop {
+                interval.tick().await;
+                templates.reload();
+            }
+        }))
+    }
+}
+
+fn template_path(dir: &Path, name: &str) -> PathBuf {
+    dir.join(format!("{}.txt", name))
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
+}
+
+fn read(path: &Path) -> Result<String, TemplateError> {
+    fs::read_to_string(path).map_err(|error| TemplateError::Io {
+        path: path.to_path_buf(),
+        error,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, process, thread, time::Duration};
+
+    use crate::error::TemplateError;
+
+    use super::{Template, Templates};
+
+    #[test]
+    pub fn test_template() {
+        let template = Template::parse("test", "Write a tweet about {{ topic }}.\n{{input}}").unwrap();
+        assert_eq!(template.variables().collect::<Vec<_>>(), vec!["topic", "input"]);
+        assert_eq!(
+            template.render(&[("topic", "rust"), ("input", "now")]),
+            "Write a tweet about rust.\nnow"
+        );
+        assert_eq!(template.render(&[]), "Write a tweet about .\n");
+
+        assert!(matches!(
+            Template::parse("test", "about {{topic"),
+            Err(TemplateError::Unclosed { offset: 6, .. })
+        ));
+
+        let templates = Templates::builtin();
+        assert!(templates.render("tweetu-dialog", &[("topic", "rust")]).contains("Write a tweet about rust."));
+        assert!(templates.render("examples/calculator", &[]).contains("calculate"));
+    }
+
+    #[test]
+    pub fn test_templates_reload() {
+        let dir = std::env::temp_dir().join(format!("meeseeks-templates-{}", process::id()));
+        fs::create_dir_all(dir.join("examples")).unwrap();
+        let parse = dir.join("parse.txt");
+
+        fs::write(&parse, "Tools:\n{{tools}}Input: {{input}}\n").unwrap();
+        fs::write(dir.join("examples/wiki.txt"), "Input: who is the president of France?\n").unwrap();
+        let templates = Templates::load(&dir).unwrap();
+        assert_eq!(templates.render("parse", &[("tools", "- tweet\n"), ("input", "hi")]), "Tools:\n- tweet\nInput: hi\n");
+        assert_eq!(templates.render("examples/wiki", &[]), "Input: who is the president of France?\n");
+        assert!(templates.render("react", &[]).contains("Observation"), "missing files fall back to the builtin");
+        assert!(templates.reload().is_empty());
+
+        // an invalid change is reported and the previous template kept
+        // some file systems only keep the mtime in seconds, so write until the change shows
+        let rewrite = |text: &str| {
+            let modified = || fs::metadata(&parse).unwrap().modified().unwrap();
+            let before = modified();
+            fs::write(&parse, text).unwrap();
+            while modified() == before {
+                thread::sleep(Duration::from_millis(50));
+                fs::write(&parse, text).unwrap();
+            }
+        };
+        rewrite("Tools:\n{{tools}}Input: {{question}}\n");
+        assert!(templates.reload().is_empty());
+        assert!(templates.render("parse", &[("input", "hi")]).ends_with("Input: hi\n"));
+
+        rewrite("{{tools}}Q: {{input}}\n");
+        assert_eq!(templates.reload(), vec!["parse"]);
+        assert_eq!(templates.render("parse", &[("tools", ""), ("input", "hi")]), "Q: hi\n");
+
+        fs::remove_file(&parse).unwrap();
+        assert_eq!(templates.reload(), vec!["parse"]);
+        assert_eq!(*templates.get("parse"), *Templates::builtin().get("parse"));
+
+        fs::write(&parse, "{{tools}}\n").unwrap();
+        assert!(matches!(Templates::load(&dir), Err(TemplateError::MissingVariable { .. })));
+        fs::write(&parse, "{{tools}}{{input}}{{answer}}\n").unwrap();
+        assert!(matches!(Templates::load(&dir), Err(TemplateError::UnknownVariable { .. })));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,11 +1,25 @@
+use std::sync::Arc;
+
 use crate::{
     common::TaskExecutor,
-    meeseeks_proto::{Status, TaskRequest, TaskResponse},
+    error::MeeseeksError,
+    meeseeks_proto::{CommandSchema, ParameterSchema, TaskRequest, TaskResponse},
+    template::Templates,
 };
 
 const COMMANDS: &[&'static str] = &["calculate[mathematical expression]"];
-const EXAMPLES: &'static str = include_str!("../../prompts/calculator.txt");
-pub struct Calculator;
+
+#[derive(Default)]
+pub struct Calculator {
+    templates: Arc<Templates>,
+}
+
+impl Calculator {
+    pub fn with_templates(mut self, templates: Arc<Templates>) -> Self {
+        self.templates = templates;
+        self
+    }
+}
 
 
 #[tonic::async_trait]
@@ -13,23 +27,26 @@ impl TaskExecutor for Calculator {
     async fn exec(&self, task: TaskRequest) -> TaskResponse {
         match task.instruction.as_str() {
             "calculate" => {
-                let expr = &task.args[0];
-                match meval::eval_str(&expr) {
-                    Ok(result) => TaskResponse {
-                        status: Status::Success.into(),
-                        response: format!("result: {}", result),
-                    },
-                    Err(e) => TaskResponse {
-                        status: Status::Failure.into(),
-                        response: format!("failed to evaluate expression: {}", e),
-                    },
+                let expr = match task.string_arg("expression").or(task.args.first().map(String::as_str)) {
+                    Some(expr) => expr,
+                    None => {
+                        return TaskResponse::failure(MeeseeksError::InvalidArguments(
+                            "missing expression".to_string(),
+                        ))
+                    }
+                };
+                match meval::eval_str(expr) {
+                    Ok(result) => TaskResponse::success(format!("result: {}", result)),
+                    Err(e) => TaskResponse::failure(MeeseeksError::InvalidArguments(format!(
+                        "failed to evaluate expression: {}",
+                        e
+                    ))),
                 }
             }
-            _ => TaskResponse {
-                status: Status::Failure.into(),
-                response: "invalid instruction. available instructions are: [\"calculate\"]"
-                    .to_string(),
-            },
+            _ => TaskResponse::failure(MeeseeksError::InvalidInstruction {
+                instruction: task.instruction,
+                available: vec!["calculate".to_string()],
+            }),
         }
     }
     
@@ -37,25 +54,52 @@ impl TaskExecutor for Calculator {
         COMMANDS
     }
 
-    fn examples<'a>(&self) -> &'a str {
-        EXAMPLES
+    fn examples(&self) -> String {
+        self.templates.render("examples/calculator", &[])
+    }
+
+    fn schemas(&self) -> Vec<CommandSchema> {
+        vec![CommandSchema {
+            name: "calculate".to_string(),
+            description: "evaluate a mathematical expression".to_string(),
+            parameters: vec![ParameterSchema::string("expression", "mathematical expression", true)],
+            aliases: vec!["calc".to_string(), "calculator".to_string(), "evaluate".to_string()],
+        }]
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::{tool::Calculator, common::TaskExecutor, meeseeks_proto::{TaskRequest, Status}};
+    use crate::{tool::Calculator, common::TaskExecutor, meeseeks_proto::{ErrorKind, TaskRequest, Status}};
 
     #[tokio::test]
     pub async fn test_calculator() {
-        let calc = Calculator;
+        let calc = Calculator::default();
         let res = calc.exec(TaskRequest {
             instruction: "calculate".to_string(),
             args: vec!["17 * 9".to_string(), "what is 17 * 9?".to_string()],
+            ..Default::default()
         }).await;
 
         println!("{:?}", res);
 
-        assert_eq!(res.status, Into::<i32>::into(Status::Success))
+        assert_eq!(res.status, Into::<i32>::into(Status::Success));
+
+        let res = calc.exec(TaskRequest {
+            instruction: "calculate".to_string(),
+            args: vec!["17 * ".to_string()],
+            ..Default::default()
+        }).await;
+        let error = res.error.unwrap();
+        assert_eq!(error.kind(), ErrorKind::InvalidArguments);
+        assert!(!error.retryable);
+
+        let res = calc.exec(TaskRequest {
+            instruction: "calc".to_string(),
+            ..Default::default()
+        }).await;
+        let error = res.error.unwrap();
+        assert_eq!(error.kind(), ErrorKind::InvalidInstruction);
+        assert_eq!(error.details["available"], "calculate");
     }
 }
@@ -8,7 +8,7 @@ pub use wiki::Wiki;
 
 use crate::{
     common::TaskExecutor,
-    meeseeks_proto::{TaskRequest, TaskResponse},
+    meeseeks_proto::{CommandSchema, TaskRequest, TaskResponse},
 };
 
 
@@ -36,11 +36,19 @@ impl TaskExecutor for Tool {
         }
     }
 
-    fn examples<'a>(&self) -> &'a str {
+    fn examples(&self) -> String {
         match self {
             Tool::Calculator(calc) => calc.examples(),
             Tool::Tweetu(tweetu) => tweetu.examples(),
             Tool::Wiki(wiki) => wiki.examples(),
         }
     }
+
+    fn schemas(&self) -> Vec<CommandSchema> {
+        match self {
+            Tool::Calculator(calc) => calc.schemas(),
+            Tool::Tweetu(tweetu) => tweetu.schemas(),
+            Tool::Wiki(wiki) => wiki.schemas(),
+        }
+    }
 }
@@ -1,28 +1,20 @@
-use dyn_fmt::AsStrFormatExt;
+use std::sync::Arc;
 
 use crate::{
     common::TaskExecutor,
-    meeseeks_proto::{TaskRequest, TaskResponse, Status},
+    error::MeeseeksError,
+    meeseeks_proto::{CommandSchema, ParameterSchema, TaskRequest, TaskResponse},
+    template::Templates,
 };
 
-const PROMPT_TEMPLATE: &'static str = r#"
-Transcript of a dialog, where the User interacts with an Assistant named Tweetu. Tweetu is helpful, kind, honest and a creative writer. Tweetu specialises in writing tweets for the user. Tweets are short creative pieces of text that have a limit of 140 characters and can contain hashtags and mention other users.
-
-User: Can you write a tweet about the benefits of drinking coffee?
-Tweetu: Starting your day with a cup of coffee not only provides an energy boost, but also offers a range of health benefits, such as improving focus, reducing the risk of diseases, and even enhancing athletic performance. #coffeebenefits #healthylifestyle
-User: Please write a tweet about GitHub.
-Tweetu: Harness the power of #GitHub!. Collaborative coding made easy. Version control for seamless progress. Open-source treasure trove. Integrated issue tracking & project management. Community-driven knowledge & support. Unleash your dev potential today! #GitGoing #DevLife
-User: Write a tweet about {}.  
-Tweetu: 
-"#;
 const OPENAI_COMPLETION_API_URL: &'static str = "https://api.openai.com/v1/completions";
 const OPENAI_MODEL_NAME: &'static str = "text-davinci-003";
 
 const COMMANDS: &[&'static str] = &["tweet(topic)"];
-const EXAMPLES: &'static str = include_str!("../../prompts/tweetu.txt");
 
 pub struct Tweetu {
     client: reqwest::Client,
+    templates: Arc<Templates>,
 }
 
 
@@ -36,11 +28,17 @@ impl Tweetu {
         let client = reqwest::ClientBuilder::new().default_headers(headers).build().unwrap();
         Self {
             client,
+            templates: Arc::default(),
         }
     }
+
+    pub fn with_templates(mut self, templates: Arc<Templates>) -> Self {
+        self.templates = templates;
+        self
+    }
     
-    async fn generate_tweet(&self, topic: &str) -> color_eyre::Result<String> {
-        let prompt = PROMPT_TEMPLATE.format(&[topic]);
+    async fn generate_tweet(&self, topic: &str) -> Result<String, MeeseeksError> {
+        let prompt = self.templates.render("tweetu-dialog", &[("topic", topic)]);
 
         let payload = serde_json::json!({
             "model": OPENAI_MODEL_NAME,
@@ -52,19 +50,21 @@ impl Tweetu {
             "stream": false,
         });
 
-        let res: serde_json::Value = self.client.post(OPENAI_COMPLETION_API_URL).json(&payload).send().await?.json().await?;
+        let res: serde_json::Value = self
+            .client
+            .post(OPENAI_COMPLETION_API_URL)
+            .json(&payload)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
 
         println!("{:?}", res);
-        let choices = res.get("choices").ok_or(TweetuError::OpenAIAPIError(res.clone()))?;
-        let _tweet = choices[0].get("text").ok_or(TweetuError::OpenAIAPIError(res.clone()))?;
-        let tweet = match res.get("choices") {
-            Some(choices) => {
-                choices[0].get("text").unwrap()
-            }
-            None => {
-                color_eyre::eyre::bail!("OpenAI API returned an invalid response")
-            }
-        };
+        let tweet = res
+            .get("choices")
+            .and_then(|choices| choices[0].get("text"))
+            .ok_or_else(|| MeeseeksError::UpstreamError(TweetuError::OpenAIAPIError(res.clone()).to_string()))?;
 
         Ok(tweet.to_string())
     }
@@ -74,24 +74,22 @@ impl Tweetu {
 impl TaskExecutor for Tweetu {
     async fn exec(&self, task: TaskRequest) -> TaskResponse {
         match task.instruction.as_str() {
-            // ugly hack because Llama cannot spell tweet sometimes
-            "twee" | "tweet" | "tweeit" | "tweeet" => {
-                let topic = &task.args[0];
-                match self.generate_tweet(topic).await {
-                    Ok(tweet) => TaskResponse {
-                        status: Status::Success.into(),
-                        response: tweet
-                    },
-                    Err(e) => TaskResponse{
-                        status: Status::Failure.into(),
-                        response: format!("failed to generate tweet: {}", e)
+            "tweet" => {
+                let topic = match task.string_arg("topic").or(task.args.first().map(String::as_str)) {
+                    Some(topic) => topic,
+                    None => {
+                        return TaskResponse::failure(MeeseeksError::InvalidArguments("missing topic".to_string()))
                     }
+                };
+                match self.generate_tweet(topic).await {
+                    Ok(tweet) => TaskResponse::success(tweet),
+                    Err(e) => TaskResponse::failure(e),
                 }
             }
-            _ => TaskResponse {
-                status: Status::Failure.into(),
-                response: "invalid instruction. available instructions are: [\"tweet\"]".to_string()
-            }
+            _ => TaskResponse::failure(MeeseeksError::InvalidInstruction {
+                instruction: task.instruction,
+                available: vec!["tweet".to_string()],
+            }),
         }
     }
 
@@ -99,10 +97,18 @@ impl TaskExecutor for Tweetu {
         COMMANDS
     }
 
-    fn examples<'a>(&self) -> &'a str {
-        EXAMPLES
+    fn examples(&self) -> String {
+        self.templates.render("examples/tweetu", &[])
     }
 
+    fn schemas(&self) -> Vec<CommandSchema> {
+        vec![CommandSchema {
+            name: "tweet".to_string(),
+            description: "write a tweet".to_string(),
+            parameters: vec![ParameterSchema::string("topic", "what the tweet should be about", true)],
+            aliases: vec!["twitter".to_string(), "post_tweet".to_string()],
+        }]
+    }
 }
 
 #[derive(thiserror::Error, Debug)]
@@ -124,6 +130,7 @@ mod tests {
         let res = tweetu.exec(TaskRequest {
             instruction: "tweet".to_string(),
             args: vec!["Elon Musk".to_string(), "write a tweet about Elon Musk".to_string()],
+            ..Default::default()
         }).await;
 
         println!("{}", res.response);
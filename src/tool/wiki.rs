@@ -1,23 +1,25 @@
-use std::{env};
+use std::{env, sync::Arc};
 
 
 use wikipedia::Wikipedia;
 
 use crate::{
     common::TaskExecutor,
-    meeseeks_proto::{Status, TaskRequest, TaskResponse},
+    error::MeeseeksError,
+    meeseeks_proto::{CommandSchema, ParameterSchema, TaskRequest, TaskResponse},
+    template::Templates,
 };
 
 const HF_QA_API_URL: &'static str = "https://api-inference.huggingface.co/models/deepset/roberta-base-squad2";
 const HF_SUMMARY_API_URL: &'static str = "https://api-inference.huggingface.co/models/facebook/bart-large-cnn";
 
 const COMMANDS: &[&'static str] = &["summary(topic)", "question(query)"];
-const EXAMPLES: &'static str = include_str!("../../prompts/wiki.txt");
 
 
 pub struct Wiki {
     wiki: Wikipedia<wikipedia::http::default::Client>,
     client: reqwest::Client,
+    templates: Arc<Templates>,
 }
 
 impl Wiki {
@@ -31,31 +33,52 @@ impl Wiki {
         Self {
             wiki,
             client,
+            templates: Arc::default(),
         }
     }
 
-    pub async fn summary(&self, content: &str) -> Result<String, Box<dyn std::error::Error>> {
+    pub fn with_templates(mut self, templates: Arc<Templates>) -> Self {
+        self.templates = templates;
+        self
+    }
+
+    pub async fn summary(&self, content: &str) -> Result<String, MeeseeksError> {
         let payload = serde_json::json!({
             "inputs": content,
             "parameters": { "do_sample": false }
         });
-        let res: serde_json::Value = self.client.post(HF_SUMMARY_API_URL).json(&payload).send().await?.json().await?;
+        let res: serde_json::Value = self.client.post(HF_SUMMARY_API_URL).json(&payload).send().await?.error_for_status()?.json().await?;
 
-        let summary = res[0].get("summary_text").ok_or(WikiError::HFApiError(res.clone()))?;
+        let summary = res[0]
+            .get("summary_text")
+            .ok_or_else(|| MeeseeksError::UpstreamError(WikiError::HFApiError(res.clone()).to_string()))?;
 
         Ok(summary.to_string())
     }
 
-    pub async fn qa(&self, question: &str, context: &str) -> Result<String, Box<dyn std::error::Error>> {
+    /// Searches wikipedia for pages matching `query`. Fails with the response to send back if
+    /// the search failed or found nothing.
+    fn search(&self, query: &str) -> Result<Vec<String>, TaskResponse> {
+        let error = match self.wiki.search(query) {
+            Ok(pages) if !pages.is_empty() => return Ok(pages),
+            Ok(_) => MeeseeksError::InvalidArguments(format!("no wikipedia pages found for {}", query)),
+            Err(e) => MeeseeksError::UpstreamError(format!("failed to search wikipedia: {}", e)),
+        };
+        Err(TaskResponse::failure(error))
+    }
+
+    pub async fn qa(&self, question: &str, context: &str) -> Result<String, MeeseeksError> {
         let payload = serde_json::json!({
             "inputs": {
                 "question": question,
                 "context": context,
             }
         });
-        let res: serde_json::Value = self.client.post(HF_QA_API_URL).json(&payload).send().await?.json().await?;
+        let res: serde_json::Value = self.client.post(HF_QA_API_URL).json(&payload).send().await?.error_for_status()?.json().await?;
         
-        let answer = res[0].get("answer").ok_or(WikiError::HFApiError(res.clone()))?;
+        let answer = res[0]
+            .get("answer")
+            .ok_or_else(|| MeeseeksError::UpstreamError(WikiError::HFApiError(res.clone()).to_string()))?;
         
         Ok(answer.to_string())
     }
@@ -65,89 +88,50 @@ impl Wiki {
 impl TaskExecutor for Wiki {
     async fn exec(&self, task: TaskRequest) -> TaskResponse {
         if task.args.len() < 2 {
-            return TaskResponse {
-                status: Status::Failure.into(),
-                response: "Invalid instruction provided. Missing args".to_string(),
-            };
+            return TaskResponse::failure(MeeseeksError::InvalidArguments("missing args".to_string()));
         }
         match task.instruction.as_str() {
             "summary" => {
-                let query = &task.args[0];
-                let pages = match self.wiki.search(query) {
-                    Ok(pages) if pages.len() > 0 => pages,
-                    _ => {
-                        return TaskResponse {
-                            status: Status::Failure.into(),
-                            response: format!("failed to search wikipedia")  
-                        };
-                    }
+                let query = task.string_arg("topic").unwrap_or(&task.args[0]);
+                let pages = match self.search(query) {
+                    Ok(pages) => pages,
+                    Err(res) => return res,
                 };
 
                 let page = self.wiki.page_from_title(pages[0].to_string());
                 match page.get_summary() {
                     Ok(content) => {
                         match self.summary(&content).await {
-                            Ok(summary) => {
-                                TaskResponse {
-                                    status: Status::Success.into(),
-                                    response: format!("{}", summary),
-                                }
-                            }
-                            Err(e) => TaskResponse {
-                                status: Status::Failure.into(),
-                                response: format!("failed to get summary: {}", e),
-                            },
+                            Ok(summary) => TaskResponse::success(summary),
+                            Err(e) => TaskResponse::failure(e),
                         }
                     }
-                    Err(e) => TaskResponse {
-                        status: Status::Failure.into(),
-                        response: format!("failed to fetch wiki page: {}", e),
-                    },
+                    Err(e) => TaskResponse::failure(MeeseeksError::UpstreamError(format!("failed to fetch wiki page: {}", e))),
                 }
             }
-            "question" | "search" => {
-                let query = &task.args[0];
+            "question" => {
+                let query = task.string_arg("query").unwrap_or(&task.args[0]);
                 let question = &task.args[1];
-                let pages = match self.wiki.search(query) {
-                    Ok(pages) if pages.len() > 0 => pages,
-                    _ => {
-                        return TaskResponse {
-                            status: Status::Failure.into(),
-                            response: format!("failed to search wikipedia")  
-                        };
-                    }
+                let pages = match self.search(query) {
+                    Ok(pages) => pages,
+                    Err(res) => return res,
                 };
 
                 let page = self.wiki.page_from_title(pages[0].to_string());
                 match page.get_summary() {
                     Ok(content) => {
-                        match self.qa(&question, &content).await {
-                            Ok(answer) => {
-                                TaskResponse {
-                                    status: Status::Success.into(),
-                                    response: format!("result: {}", answer),
-                                }
-                            },
-                            Err(e) => {
-                                TaskResponse {
-                                    status: Status::Failure.into(),
-                                    response: format!("failed to answer question: {}", e),
-                                }
-                            }
+                        match self.qa(question, &content).await {
+                            Ok(answer) => TaskResponse::success(format!("result: {}", answer)),
+                            Err(e) => TaskResponse::failure(e),
                         }
                     }
-                    Err(e) => TaskResponse {
-                        status: Status::Failure.into(),
-                        response: format!("failed to answer question: {}", e),
-                    },
+                    Err(e) => TaskResponse::failure(MeeseeksError::UpstreamError(format!("failed to fetch wiki page: {}", e))),
                 }
             }
-            _ => TaskResponse {
-                status: Status::Failure.into(),
-                response:
-                    "invalid instruction. available instructions are: [\"summary\", \"question\", \"search\"]"
-                        .to_string(),
-            },
+            _ => TaskResponse::failure(MeeseeksError::InvalidInstruction {
+                instruction: task.instruction,
+                available: vec!["summary".to_string(), "question".to_string()],
+            }),
         }
     }
 
@@ -155,8 +139,31 @@ impl TaskExecutor for Wiki {
         COMMANDS
     }
 
-    fn examples<'a>(&self) -> &'a str {
-        EXAMPLES
+    fn examples(&self) -> String {
+        self.templates.render("examples/wiki", &[])
+    }
+
+    fn schemas(&self) -> Vec<CommandSchema> {
+        vec![
+            CommandSchema {
+                name: "summary".to_string(),
+                description: "write a short paragraph about a topic".to_string(),
+                parameters: vec![ParameterSchema::string("topic", "topic to summarise", true)],
+                aliases: vec!["summarize".to_string(), "describe".to_string()],
+            },
+            CommandSchema {
+                name: "question".to_string(),
+                description: "answer a question using wikipedia".to_string(),
+                parameters: vec![ParameterSchema::string("query", "what to look up on wikipedia", true)],
+                aliases: vec![
+                    "ask".to_string(),
+                    "answer".to_string(),
+                    "search".to_string(),
+                    "lookup".to_string(),
+                    "wikipedia".to_string(),
+                ],
+            },
+        ]
     }
 }
 
@@ -1,6 +1,6 @@
 use reqwest::{Url, Client};
 
-use crate::common::{AgentMatcher, ConnectedAgent};
+use crate::common::{AgentMatch, AgentMatcher, ConnectedAgent};
 
 pub struct ToolDB {
     client: Client,
@@ -20,7 +20,7 @@ impl AgentMatcher for ToolDB {
     async fn add_agent(&self, agent: ConnectedAgent) -> Result<(), Box<dyn std::error::Error>> {
         let payload = serde_json::json!({
             "tool": {
-                "name": agent.name,
+                "name": agent.pool,
                 "commands": agent.commands,
                 "examples": agent.examples,
             }
@@ -32,15 +32,47 @@ impl AgentMatcher for ToolDB {
         Ok(())
     }
 
-    async fn match_agent(&self, task: &str) -> Result<String, Box<dyn std::error::Error>> {
-        let addr = self.addr.join("/tool/match").unwrap();
-        let res: serde_json::Value = self.client.get(addr).query(&[("task", task)]).send().await?.json().await?;
+    async fn remove_agent(&self, name: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let payload = serde_json::json!({
+            "name": name,
+        });
 
-        match res.get("name") {
-            Some(name) => Ok(name.as_str().unwrap().to_string()),
-            None => {
-                Ok("".to_string())
-            }
+        let addr = self.addr.join("/tool/remove").unwrap();
+        let _res: serde_json::Value = self.client.post(addr).json(&payload).send().await?.json().await?;
+
+        Ok(())
+    }
+
+    async fn match_agents(&self, task: &str, limit: usize) -> Result<Vec<AgentMatch>, Box<dyn std::error::Error>> {
+        let addr = self.addr.join("/tool/match").unwrap();
+        let res = self
+            .client
+            .get(addr)
+            .query(&[("task", task), ("n", &limit.to_string())])
+            .send()
+            .await?;
+        // older versions of ToolDB answer 404 when no tool matches
+        if res.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(Vec::new());
         }
+        let res: serde_json::Value = res.error_for_status()?.json().await?;
+
+        let matches = res
+            .get("matches")
+            .and_then(serde_json::Value::as_array)
+            .map(|matches| {
+                matches
+                    .iter()
+                    .filter_map(|m| {
+                        Some(AgentMatch {
+                            name: m.get("name")?.as_str()?.to_string(),
+                            score: m.get("score")?.as_f64()? as f32,
+                        })
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        Ok(matches)
     }
 }
@@ -1,10 +1,15 @@
+import os
+
 import chromadb
+from chromadb.config import Settings
 from flask import Flask, jsonify
 from flask import Flask, request, abort, jsonify
 from string import Template
 from uuid import uuid1
 
 COLLECTION_NAME = "connected-agents"
+# directory the tools are persisted to, so that they survive restarts. kept in memory if unset
+PERSIST_DIRECTORY = os.environ.get("TOOLDB_PERSIST_DIRECTORY")
 DOCUMENT_TEMPLATE = Template("""
 ---NAME---
 $name
@@ -17,13 +22,21 @@ $examples
 
 class ToolDB:
     def __init__(self):
-        self.client = chromadb.Client()
-        self.collection = self.client.create_collection(COLLECTION_NAME)
+        if PERSIST_DIRECTORY:
+            self.client = chromadb.Client(Settings(chroma_db_impl="duckdb+parquet", persist_directory=PERSIST_DIRECTORY))
+        else:
+            self.client = chromadb.Client()
+        self.collection = self.client.get_or_create_collection(COLLECTION_NAME)
 
         # self.collection.create_index()
-        self.collection.add(documents=[""], ids=["none"])
+        if self.collection.count() == 0:
+            self.collection.add(documents=[""], ids=["none"])
+            self._persist()
 
     def add_tool(self, tool):
+        # replace the documents of a tool that registers again instead of adding duplicates
+        self.remove_tool(tool['name'])
+
         documents = self._make_documents(tool)
         metadatas = [{'name': tool['name']} for _ in documents]
         ids = [str(uuid1()) for _ in documents]
@@ -32,16 +45,35 @@ class ToolDB:
             metadatas=metadatas,
             ids=ids
         )
+        self._persist()
+
+    def remove_tool(self, name):
+        self.collection.delete(where={'name': name})
+        self._persist()
+
+    def _persist(self):
+        if PERSIST_DIRECTORY:
+            self.client.persist()
+
+    def get_matching_tools(self, query, n):
+        # every tool has several documents, so ask for more and keep the best one of each tool
+        n_results = min(self.collection.count(), n * 10)
+        if n_results == 0:
+            return []
+        results = self.collection.query(query_texts=[query], n_results=n_results, include=['metadatas', 'distances'])
+
+        matches = []
+        for metadata, distance in zip(results['metadatas'][0], results['distances'][0]):
+            name = metadata.get('name') if metadata else None
+            if name is None or any(m['name'] == name for m in matches):
+                continue
+            # chroma returns squared L2 distances of normalized embeddings: 1 - d / 2 is the cosine similarity
+            matches.append({'name': name, 'score': max(0.0, 1.0 - distance / 2)})
+            if len(matches) == n:
+                break
+
+        return matches
 
-    def get_matching_tool(self, query):
-        query_texts = [query,]
-        n_results = 1
-        
-        results = self.collection.query(query_texts=query_texts, n_results=n_results, include=['metadatas'])
-        print(results)
-        
-        return results['metadatas'][0][0]['name']
-        
     def _make_documents(self, tool):
         docs = []
         docs += tool['commands']
@@ -63,14 +95,23 @@ def add():
         print(e)
         return jsonify({"message": "invalid request"}), 400
 
+@app.route("/tool/remove", methods=["POST"])
+def remove():
+    try:
+        name = request.json['name']
+        tooldb.remove_tool(name)
+        return jsonify({"name": name}), 200
+    except Exception as e:
+        print(e)
+        return jsonify({"message": "invalid request"}), 400
+
 @app.route("/tool/match", methods=["GET"])
 def match():
     query = request.args.get("task")
-    tool = tooldb.get_matching_tool(query)
-    print(tool)
-    if tool == "none":
-        return jsonify({"message": "not found"}), 404
-    return jsonify({"name": tool}), 200
+    n = request.args.get("n", default=1, type=int)
+    matches = tooldb.get_matching_tools(query, max(n, 1))
+    print(matches)
+    return jsonify({"matches": matches}), 200
 
 if __name__ == '__main__':
     app.debug == True